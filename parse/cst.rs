@@ -0,0 +1,556 @@
+//! Concrete syntax trees, which are the source as it was written instead of what it means.
+//!
+//! `parse_lossless` keeps every byte of the source, whitespace and comments included, so tools
+//! can change part of a file and leave the rest exactly how it was.  `read` boils that down to
+//! just the comments and blank lines, which is what the formatter cares about.
+
+use std::fmt;
+use std::mem;
+
+use pretty::{column, header_len, newline};
+use span::Span;
+use {lex_with_comments, parse_all, LexError, ParseError, Token};
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Node {
+
+    /// Anything that isn't made of other things, with its text just like it was in the source.
+    Atom(Token, String),
+
+    /// Something in brackets, like `(...)`, `#(...)`, `#u8(...)`, or `{...}`.
+    Group(Bracket, Vec<Node>),
+
+    /// A reader prefix like `'` or `,@` and what it applies to.
+    Prefixed(&'static str, Box<Node>),
+
+    /// The `.` in a dotted list.
+    Dot,
+
+    /// A `;` comment.  It's trailing if it came after something else on the same line.
+    Comment { text: String, trailing: bool },
+
+    /// At least one empty line between two things.
+    Blank
+
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Bracket {
+    List,
+    Vector,
+    Bytes,
+    Map
+}
+
+impl Bracket {
+
+    pub fn open(&self) -> &'static str {
+        match self {
+            &Bracket::List => "(",
+            &Bracket::Vector => "#(",
+            &Bracket::Bytes => "#u8(",
+            &Bracket::Map => "{"
+        }
+    }
+
+    pub fn close(&self) -> &'static str {
+        match self {
+            &Bracket::Map => "}",
+            _ => ")"
+        }
+    }
+
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum ReadError {
+    Lex(LexError),
+    Parse(ParseError)
+}
+
+impl From<LexError> for ReadError {
+    fn from(e: LexError) -> ReadError {
+        ReadError::Lex(e)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> ReadError {
+        ReadError::Parse(e)
+    }
+}
+
+/// Whitespace or a comment, which the parser otherwise skips over.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Trivia {
+    Space(String),
+    Comment(String)
+}
+
+impl Trivia {
+    pub fn text(&self) -> &str {
+        match self {
+            &Trivia::Space(ref s) | &Trivia::Comment(ref s) => s
+        }
+    }
+}
+
+/// A token with its text exactly as written, and all the trivia between it and the one before.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Leaf {
+    pub trivia: Vec<Trivia>,
+    pub token: Token,
+    pub text: String,
+    pub span: Span
+}
+
+/// The lossless tree, where every byte of the source belongs to some leaf.  Writing it back out
+/// with `Display` gives back exactly what was read.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Syntax {
+
+    Atom(Leaf),
+
+    /// The opening bracket, what's inside, and the closing bracket.
+    Group(Bracket, Leaf, Vec<Syntax>, Leaf),
+
+    /// A reader prefix like `'` and what it applies to.
+    Prefixed(Leaf, Box<Syntax>),
+
+    Dot(Leaf)
+
+}
+
+impl Syntax {
+
+    /// The leaf this starts with, which has the trivia in front of it.
+    pub fn first(&self) -> &Leaf {
+        match self {
+            &Syntax::Atom(ref l) | &Syntax::Group(_, ref l, _, _) | &Syntax::Prefixed(ref l, _) | &Syntax::Dot(ref l) => l
+        }
+    }
+
+    pub fn last(&self) -> &Leaf {
+        match self {
+            Syntax::Group(_, _, _, l) => l,
+            Syntax::Prefixed(_, s) => s.last(),
+            s => s.first()
+        }
+    }
+
+    /// Where it is in the source, not counting the trivia in front.
+    pub fn span(&self) -> Span {
+        self.first().span.to(&self.last().span)
+    }
+
+}
+
+/// A whole file, with whatever trivia comes after the last form.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SyntaxFile {
+    pub forms: Vec<Syntax>,
+    pub trivia: Vec<Trivia>
+}
+
+impl fmt::Display for Leaf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for t in &self.trivia {
+            f.write_str(t.text())?;
+        }
+        f.write_str(&self.text)
+    }
+}
+
+impl fmt::Display for Syntax {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Syntax::Group(_, open, items, close) => {
+                write!(f, "{}", open)?;
+                for s in items {
+                    write!(f, "{}", s)?;
+                }
+                write!(f, "{}", close)
+            },
+            Syntax::Prefixed(p, s) => write!(f, "{}{}", p, s),
+            Syntax::Atom(l) | Syntax::Dot(l) => write!(f, "{}", l)
+        }
+    }
+}
+
+impl fmt::Display for SyntaxFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for s in &self.forms {
+            write!(f, "{}", s)?;
+        }
+        for t in &self.trivia {
+            f.write_str(t.text())?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a whole file into the lossless tree.  It accepts exactly what `parse_all` does, since
+/// it checks with it first.
+pub fn parse_lossless(src: &str) -> Result<SyntaxFile, ReadError> {
+
+    let toks = lex_with_comments(src)?;
+    parse_all(&mut toks.iter().filter(|&(t, _)| !is_comment(t)).map(|(t, _)| t.clone()).peekable())?;
+
+    // The lexer only skips whitespace, so that's what's in the gaps between the tokens.
+    let mut leaves = Vec::new();
+    let mut trivia = Vec::new();
+    let mut pos = 0;
+    for (t, span) in toks {
+        if span.start > pos {
+            trivia.push(Trivia::Space(src[pos..span.start].into()));
+        }
+        pos = span.end;
+        let text = src[span.start..span.end].to_string();
+        match t {
+            Token::Comment(_) => trivia.push(Trivia::Comment(text)),
+            t => leaves.push(Leaf { trivia: mem::take(&mut trivia), token: t, text, span })
+        }
+    }
+    if pos < src.len() {
+        trivia.push(Trivia::Space(src[pos..].into()));
+    }
+
+    let mut leaves = leaves.into_iter();
+    let mut forms = Vec::new();
+    while let Some(l) = leaves.next() {
+        forms.push(build(l, &mut leaves));
+    }
+    Ok(SyntaxFile { forms, trivia })
+
+}
+
+fn is_comment(t: &Token) -> bool {
+    matches!(t, &Token::Comment(_))
+}
+
+/// Builds the tree from the leaves.  The parser has already checked them so the brackets all
+/// match up and nothing runs out early.
+fn build<I: Iterator<Item = Leaf>>(leaf: Leaf, leaves: &mut I) -> Syntax {
+    let bracket = match leaf.token {
+        Token::OpenParen => Bracket::List,
+        Token::OpenVector => Bracket::Vector,
+        Token::OpenBytes => Bracket::Bytes,
+        Token::OpenBrace => Bracket::Map,
+        Token::Quote | Token::Quasiquote | Token::Unquote | Token::UnquoteSplicing => {
+            let sub = leaves.next().unwrap();
+            return Syntax::Prefixed(leaf, Box::new(build(sub, leaves)));
+        },
+        Token::Dot => return Syntax::Dot(leaf),
+        _ => return Syntax::Atom(leaf)
+    };
+    let mut items = Vec::new();
+    loop {
+        let next = leaves.next().unwrap();
+        match next.token {
+            Token::CloseParen | Token::CloseBrace => return Syntax::Group(bracket, leaf, items, next),
+            _ => items.push(build(next, leaves))
+        }
+    }
+}
+
+/// Reads a whole file into the nodes the formatter works with.
+pub fn read(src: &str) -> Result<Vec<Node>, ReadError> {
+    let file = parse_lossless(src)?;
+    let mut nodes = Vec::new();
+    let mut first = true;
+    for s in &file.forms {
+        add_node(s, !first, &mut nodes);
+        first = false;
+    }
+    add_trivia(&file.trivia, !first, &mut nodes);
+    Ok(nodes)
+}
+
+/// Turns the trivia into comments and blank lines.  A comment is trailing if there wasn't a
+/// newline since the last token.
+fn add_trivia(trivia: &[Trivia], after_token: bool, nodes: &mut Vec<Node>) {
+    let mut newlines = 0;
+    let mut after_token = after_token;
+    for t in trivia {
+        match t {
+            Trivia::Space(s) => newlines += s.matches('\n').count(),
+            Trivia::Comment(text) => {
+                if after_token && newlines > 1 {
+                    nodes.push(Node::Blank);
+                }
+                nodes.push(Node::Comment { text: text.trim_end().into(), trailing: after_token && newlines == 0 });
+                newlines = 0;
+                after_token = true;
+            }
+        }
+    }
+    if after_token && newlines > 1 {
+        nodes.push(Node::Blank);
+    }
+}
+
+/*
+ * Comments between a prefix and what it applies to, like `' ; c\n x`, come out before the prefix
+ * since there's nowhere else to keep them.
+ */
+fn add_node(s: &Syntax, after_token: bool, nodes: &mut Vec<Node>) {
+    add_trivia(&s.first().trivia, after_token, nodes);
+    let n = match s {
+        Syntax::Atom(l) => Node::Atom(l.token.clone(), l.text.clone()),
+        Syntax::Dot(_) => Node::Dot,
+        Syntax::Group(b, _, items, close) => {
+            let mut inner = Vec::new();
+            for s in items {
+                add_node(s, true, &mut inner);
+            }
+            add_trivia(&close.trivia, true, &mut inner);
+            Node::Group(*b, inner)
+        },
+        Syntax::Prefixed(p, sub) => {
+            let mut inner = Vec::new();
+            add_node(sub, true, &mut inner);
+            let sub = inner.pop().unwrap();
+            nodes.extend(inner);
+            Node::Prefixed(prefix_str(&p.token), Box::new(sub))
+        }
+    };
+    nodes.push(n);
+}
+
+fn prefix_str(t: &Token) -> &'static str {
+    match t {
+        &Token::Quasiquote => "`",
+        &Token::Unquote => ",",
+        &Token::UnquoteSplicing => ",@",
+        _ => "'"
+    }
+}
+
+/// Writes the nodes back out with the same indentation `pretty::pretty` uses, keeping comments
+/// where they were and squashing runs of blank lines down to one.
+pub fn format(nodes: &[Node], width: usize) -> String {
+    let mut out = String::new();
+    for (i, n) in nodes.iter().enumerate() {
+        match n {
+            &Node::Blank if i == 0 || i + 1 == nodes.len() => {},
+            &Node::Blank => out.push('\n'),
+            &Node::Comment { ref text, trailing } => {
+                if trailing && i > 0 {
+                    out.push(' ');
+                } else if i > 0 {
+                    out.push('\n');
+                }
+                out.push_str(text);
+            },
+            n => {
+                if i > 0 {
+                    out.push('\n');
+                }
+                layout(n, width, &mut out);
+            }
+        }
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+/// The node on one line, unless there's a comment or blank line in it that means it can't be.
+fn flat(n: &Node) -> Option<String> {
+    match n {
+        Node::Atom(_, text) => Some(text.clone()),
+        Node::Group(b, items) => {
+            let parts = items.iter().map(flat).collect::<Option<Vec<_>>>()?;
+            Some(format!("{}{}{}", b.open(), parts.join(" "), b.close()))
+        },
+        Node::Prefixed(p, sub) => Some(format!("{}{}", p, flat(sub)?)),
+        Node::Dot => Some(".".into()),
+        Node::Comment { .. } | Node::Blank => None
+    }
+}
+
+fn is_code(n: &Node) -> bool {
+    !matches!(n, &Node::Comment { .. } | &Node::Blank)
+}
+
+fn symbol(n: Option<&Node>) -> Option<&str> {
+    match n {
+        Some(&Node::Atom(Token::Name(ref s), _)) => Some(s),
+        _ => None
+    }
+}
+
+fn layout(n: &Node, width: usize, out: &mut String) {
+
+    let col = column(out);
+    if let Some(f) = flat(n) {
+        if col + f.chars().count() <= width {
+            out.push_str(&f);
+            return;
+        }
+    }
+
+    match n {
+        &Node::Group(b, ref items) => layout_group(b, items, col, width, out),
+        &Node::Prefixed(p, ref sub) => {
+            out.push_str(p);
+            layout(sub, width, out);
+        },
+
+        // Atoms that don't fit just stick out, and the rest never get here.
+        n => out.push_str(&flat(n).unwrap_or_default())
+    }
+
+}
+
+/*
+ * The same rules as the pretty printer: forms like `define` keep their header on the first line
+ * and indent the body by two, calls line their arguments up under the first one, maps get a key
+ * and value per line, and anything else gets one item per line.  A comment anywhere before the
+ * end of the header means the header can't all go on one line, so those items get their own.
+ */
+fn layout_group(b: Bracket, items: &[Node], col: usize, width: usize, out: &mut String) {
+
+    out.push_str(b.open());
+
+    let leading = items.iter().take_while(|n| is_code(n)).count();
+    let total = items.iter().filter(|n| is_code(n)).count();
+    let head = if b == Bracket::List { symbol(items.first()) } else { None };
+    let header = head.and_then(|h| header_len(h, symbol(items.get(1)).is_some())).filter(|&n| leading > n && total > n + 1);
+    let (inline, indent) = match (header, head) {
+        (Some(n), _) => (n + 1, col + 2),
+        (None, Some(h)) if leading > 1 => (2, col + b.open().len() + h.chars().count() + 1),
+        _ => (1, col + b.open().len())
+    };
+
+    let mut code = 0usize;
+    let mut after_comment = false;
+    let mut after_dot = false;
+    for (i, n) in items.iter().enumerate() {
+        match n {
+            &Node::Blank if i == 0 || i + 1 == items.len() => {},
+            &Node::Blank => out.push('\n'),
+            &Node::Comment { ref text, trailing } => {
+                if trailing {
+                    out.push(' ');
+                } else {
+                    newline(indent, out);
+                }
+                out.push_str(text);
+                after_comment = true;
+            },
+            n => {
+                let on_line = match b {
+                    Bracket::Map => !code.is_multiple_of(2),
+                    _ => code < inline || after_dot
+                };
+                if after_comment || (i > 0 && !on_line) {
+                    newline(indent, out);
+                } else if i > 0 {
+                    out.push(' ');
+                }
+                layout(n, width, out);
+                after_dot = *n == Node::Dot;
+                after_comment = false;
+                code += 1;
+            }
+        }
+    }
+
+    // Nothing can come after a comment on its line, even the bracket.
+    if after_comment {
+        newline(indent, out);
+    }
+    out.push_str(b.close());
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{format, parse_lossless, read, Node, ReadError, Syntax, Trivia};
+    use span::Span;
+    use {ParseError, Token};
+
+    fn fmt(src: &str, width: usize) -> String {
+        format(&read(&String::from(src)).unwrap(), width)
+    }
+
+    #[test]
+    fn test_read() {
+        let nodes = read(&String::from("; top\n(a ; end\n\n\n 'b)")).unwrap();
+        assert_eq!(nodes[0], Node::Comment { text: "; top".into(), trailing: false });
+        match nodes[1] {
+            Node::Group(_, ref items) => {
+                assert_eq!(items[0], Node::Atom(Token::Name("a".into()), "a".into()));
+                assert_eq!(items[1], Node::Comment { text: "; end".into(), trailing: true });
+                assert_eq!(items[2], Node::Blank);
+                assert_eq!(items[3], Node::Prefixed("'", Box::new(Node::Atom(Token::Name("b".into()), "b".into()))));
+            },
+            _ => panic!()
+        }
+        assert_eq!(read(&String::from("(a ; )")), Err(ReadError::Parse(ParseError::UnexpectedTermination)));
+        assert_eq!(read(&String::from("{(a) 1}")), Err(ReadError::Parse(ParseError::UnexpectedToken(Token::OpenParen))));
+    }
+
+    #[test]
+    fn test_lossless() {
+        let srcs = [
+            "",
+            "  \n",
+            "; only a comment",
+            "(define (f x)   ; trailing  \r\n\t'( a . b ) #u8( 1 2 )\n\n { k \"v é\" }  ) \n;; end\n",
+            "`(a ,@ b , c)#(#\\space #\\x41)#t"
+        ];
+        for src in &srcs {
+            let file = parse_lossless(&String::from(*src)).unwrap();
+            assert_eq!(file.to_string(), *src);
+        }
+
+        let file = parse_lossless(&String::from("  ; hi\n(a\n 'b) ")).unwrap();
+        assert_eq!(file.trivia, vec![Trivia::Space(" ".into())]);
+        let f = &file.forms[0];
+        assert_eq!(f.first().trivia, vec![Trivia::Space("  ".into()), Trivia::Comment("; hi".into()), Trivia::Space("\n".into())]);
+        assert_eq!(f.span(), Span { start: 7, end: 14, line: 2, col: 1 });
+        match f {
+            Syntax::Group(_, _, items, close) => {
+                assert_eq!(items[1].to_string(), "\n 'b");
+                assert_eq!(items[1].span(), Span { start: 11, end: 13, line: 3, col: 2 });
+                assert_eq!(close.text, ")");
+            },
+            _ => panic!()
+        }
+        assert_eq!(parse_lossless(&String::from("(a")), Err(ReadError::Parse(ParseError::UnexpectedTermination)));
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(fmt("(define foo (lambda (a b)\n(+ a (* 2 b))))\n\n\n\n(foo 5 6)\n; done", 80), "\
+(define foo (lambda (a b) (+ a (* 2 b))))
+
+(foo 5 6)
+; done
+");
+        assert_eq!(fmt(";; header\n(define (f x) ; the f\n  ; says hi\n(display \"hi\")\n\n  x) ; after", 80), "\
+;; header
+(define (f x) ; the f
+  ; says hi
+  (display \"hi\")
+
+  x) ; after
+");
+        assert_eq!(fmt("(if a ; yes\n b)", 80), "(if a ; yes\n    b)\n");
+        assert_eq!(fmt("(f a b ; last\n)", 80), "(f a\n   b ; last\n   )\n");
+        assert_eq!(fmt("(define (g y) (if (> y 1) (h 'y #(1 2)) {k 1 j 2}))", 24), "\
+(define (g y)
+  (if (> y 1)
+      (h 'y #(1 2))
+      {k 1 j 2}))
+");
+        assert_eq!(fmt("{alpha 1 beta (f 2)}", 12), "{alpha 1\n beta (f 2)}\n");
+        assert_eq!(fmt("#\\x41 \"a\\nb\"", 80), "#\\x41\n\"a\\nb\"\n");
+        assert_eq!(fmt("", 80), "");
+    }
+
+}
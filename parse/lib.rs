@@ -1,6 +1,20 @@
+#![allow(
+    clippy::match_ref_pats,
+    clippy::redundant_field_names
+)]
+
+use std::cell::Cell;
+use std::fmt;
 use std::iter::*;
+use std::rc::Rc;
 
+pub mod cst;
+pub mod pretty;
+pub mod recover;
 pub mod sexp;
+pub mod span;
+
+use span::{Pos, Span};
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum Token {
@@ -8,83 +22,307 @@ pub enum Token {
     Str(String),
     Number(i64),
     Bool(bool),
+    Char(char),
+    Keyword(String),
     Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
+    Dot,
     OpenParen,
-    CloseParen
+
+    /// The `#(` that starts a vector literal.
+    OpenVector,
+
+    /// The `#u8(` that starts a byte array literal.
+    OpenBytes,
+    CloseParen,
+    OpenBrace,
+    CloseBrace,
+
+    /// A `;` comment, from the semicolon to the end of the line.  Only `lex_with_comments` keeps
+    /// these, and the parser doesn't take them.
+    Comment(String)
+}
+
+/// Writes the token like it would look in the source.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Token::*;
+        match self {
+            Name(s) | Comment(s) => write!(f, "{}", s),
+            Str(s) => write!(f, "{}", sexp::Sexp::Str(s.clone())),
+            Number(n) => write!(f, "{}", n),
+            Bool(b) => write!(f, "{}", if *b { "#t" } else { "#f" }),
+            Char(c) => write!(f, "{}", sexp::Sexp::Char(*c)),
+            Keyword(s) => write!(f, "#:{}", s),
+            Quote => write!(f, "'"),
+            Quasiquote => write!(f, "`"),
+            Unquote => write!(f, ","),
+            UnquoteSplicing => write!(f, ",@"),
+            Dot => write!(f, "."),
+            OpenParen => write!(f, "("),
+            OpenVector => write!(f, "#("),
+            OpenBytes => write!(f, "#u8("),
+            CloseParen => write!(f, ")"),
+            OpenBrace => write!(f, "{{"),
+            &CloseBrace => write!(f, "}}")
+        }
+    }
 }
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum LexError {
     UnknownChar(char),
+
+    /// Something like `#\\nope`, which isn't a character we know the name of.
+    UnknownCharName(String),
+
+    /// An integer literal that doesn't fit in 64 bits.
+    NumberOutOfRange,
+
+    /// A backslash in a string followed by something that isn't `n`, `r`, `\` or `"`.
+    BadEscape(char),
+
+    /// A string with a raw line break in it, which is almost always a missing `"`.
+    NewlineInString,
+
+    /// A string that's still open at the end of the input.
+    UnclosedString,
     UnexpectedTermination
 }
 
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexError::UnknownChar(c) => write!(f, "unexpected character {:?}", c),
+            LexError::UnknownCharName(n) => write!(f, "unknown character name #\\{}", n),
+            LexError::NumberOutOfRange => write!(f, "number is too big"),
+            LexError::BadEscape(c) => write!(f, "unknown escape \\{} in string", c),
+            LexError::NewlineInString => write!(f, "string runs onto the next line"),
+            LexError::UnclosedString => write!(f, "string is never closed"),
+            LexError::UnexpectedTermination => write!(f, "unexpected end of input")
+        }
+    }
+}
+
 #[inline]
 fn is_name_char(c: char, start: bool) -> bool {
     // This function isn't that pretty.
     if start {
-        match c {
-            'a'...'z' | 'A'...'Z' | '*' | '+' | '/' | '=' | '_' | '$' | '%' => true,
-            _ => false
-        }
+        matches!(c, 'a'..='z' | 'A'..='Z' | '*' | '+' | '/' | '=' | '_' | '$' | '%' | '!' | '?' | '<' | '>')
     } else {
-        match c {
-            'a'...'z' | 'A'...'Z' | '*' | '+' | '/' | '=' | '_' | '$' | '%' | '0'...'9' => true,
-            _ => false
+        matches!(c, 'a'..='z' | 'A'..='Z' | '*' | '+' | '/' | '=' | '_' | '$' | '%' | '!' | '?' | '<' | '>' | '-' | '0'..='9')
+    }
+}
+
+/// Wraps a char iterator, remembering where the last char it gave out was.
+///
+/// Since `Peekable` reads ahead, after a `peek()` this is the position of the peeked char.  Once
+/// the input runs out it's the position at the very end.
+struct Tracked<I> {
+    inner: I,
+    next: Pos,
+    last: Rc<Cell<Pos>>
+}
+
+impl<I: Iterator<Item = char>> Iterator for Tracked<I> {
+    type Item = char;
+    fn next(&mut self) -> Option<char> {
+        let c = self.inner.next();
+        self.last.set(self.next);
+        if let Some(c) = c {
+            self.next = self.next.advance(c);
         }
+        c
     }
 }
 
 /// Parses a vector of tokens.
 ///
 /// A lot of this is stolen from https://adriann.github.io/rust_parser.html.
-pub fn lex(input: &String) -> Result<Vec<Token>, LexError> {
+pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
+    Ok(lex_spanned(input)?.into_iter().map(|(t, _)| t).collect())
+}
+
+/// Like `lex`, but also says where each token came from.
+pub fn lex_spanned(input: &str) -> Result<Vec<(Token, Span)>, LexError> {
+    lex_tokens(input, false)
+}
+
+/// Like `lex_spanned`, but keeps comments as tokens too, for tools that care about them.
+pub fn lex_with_comments(input: &str) -> Result<Vec<(Token, Span)>, LexError> {
+    lex_tokens(input, true)
+}
+
+fn lex_tokens(input: &str, comments: bool) -> Result<Vec<(Token, Span)>, LexError> {
+    let (toks, mut errors) = lex_all(input, comments, false);
+    match errors.pop() {
+        Some((e, _)) => Err(e),
+        None => Ok(toks)
+    }
+}
+
+/// Tokens that did lex, and the errors from the parts that didn't, each with where it was.
+pub type Recovered = (Vec<(Token, Span)>, Vec<(LexError, Span)>);
+
+/// Like `lex_spanned`, but keeps going after errors and gives back all of them along with where
+/// they were.  Whatever was bad is skipped, which for a broken string is the rest of it.
+pub fn lex_recovering(input: &str) -> Recovered {
+    lex_all(input, false, true)
+}
+
+fn lex_all(input: &str, comments: bool, recover: bool) -> Recovered {
 
     let mut vec = Vec::new();
-    let mut iter = input.chars().peekable();
+    let mut spans = Vec::new();
+    let mut errors = Vec::new();
+    let last = Rc::new(Cell::new(Pos::start()));
+    let mut iter = Tracked { inner: input.chars(), next: Pos::start(), last: last.clone() }.peekable();
 
     while let Some(&c) = iter.peek() {
-        match c {
-            '-' | '0'...'9' => {
-                vec.push(read_number(&mut iter)?);
-            },
-            // We catch the `-` case above.
-            v if is_name_char(v, true) => {
-                vec.push(read_name(&mut iter)?);
-            },
-            '"' => {
-                vec.push(read_string(&mut iter)?);
-            }
-            '#' => {
-                iter.next();
-                match iter.peek().cloned() {
-                    Some('t') => vec.push(Token::Bool(true)),
-                    Some('f') => vec.push(Token::Bool(false)),
-                    Some(c) => return Err(LexError::UnknownChar(c)),
-                    _ => return Err(LexError::UnexpectedTermination)
+        let start = last.get();
+
+        if let Err(e) = lex_one(c, &mut iter, comments, &mut vec) {
+
+            // Make sure we always get past at least one char, so this can't go around forever.
+            if c == '"' {
+                while let Some(&c) = iter.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    iter.next();
+                    if c == '"' {
+                        break;
+                    }
                 }
-            },
-            '\'' => {
-                iter.next();
-                vec.push(Token::Quote);
-            },
-            '(' => {
+            } else if iter.peek().is_some() && last.get().offset == start.offset {
                 iter.next();
-                vec.push(Token::OpenParen);
-            },
-            ')' => {
-                iter.next();
-                vec.push(Token::CloseParen);
             }
-            ' ' | '\n' | '\r' => { iter.next(); },
-            c @ _ => return Err(LexError::UnknownChar(c))
+
+            iter.peek();
+            let end = last.get();
+            errors.push((e, Span { start: start.offset, end: end.offset, line: start.line, col: start.col }));
+            if recover {
+                continue;
+            } else {
+                break;
+            }
+
+        }
+
+        // If we got a new token then look at the next char to find out where this one ended.
+        if vec.len() > spans.len() {
+            iter.peek();
+            let end = last.get();
+            spans.push(Span { start: start.offset, end: end.offset, line: start.line, col: start.col });
         }
     }
 
-    Ok(vec)
+    (vec.into_iter().zip(spans).collect(), errors)
 
 }
 
+/// Reads one token starting at `c`, or skips over whitespace or a comment.
+fn lex_one<T: Iterator<Item = char>>(c: char, iter: &mut Peekable<T>, comments: bool, vec: &mut Vec<Token>) -> Result<(), LexError> {
+    match c {
+        '-' | '0'..='9' => {
+            vec.push(read_number(iter)?);
+        },
+        // We catch the `-` case above.
+        v if is_name_char(v, true) => {
+            vec.push(read_name(iter)?);
+        },
+        '"' => {
+            vec.push(read_string(iter)?);
+        }
+        '#' => {
+            iter.next();
+            match iter.peek().cloned() {
+                Some('t') => {
+                    iter.next();
+                    vec.push(Token::Bool(true));
+                },
+                Some('f') => {
+                    iter.next();
+                    vec.push(Token::Bool(false));
+                },
+                Some(':') => {
+                    iter.next();
+                    vec.push(read_keyword(iter)?);
+                },
+                Some('\\') => {
+                    iter.next();
+                    vec.push(read_char(iter)?);
+                },
+                Some('(') => {
+                    iter.next();
+                    vec.push(Token::OpenVector);
+                },
+                Some('u') => {
+                    iter.next();
+                    for expected in &['8', '('] {
+                        match iter.next() {
+                            Some(c) if c == *expected => {},
+                            Some(c) => return Err(LexError::UnknownChar(c)),
+                            None => return Err(LexError::UnexpectedTermination)
+                        }
+                    }
+                    vec.push(Token::OpenBytes);
+                },
+                Some(c) => return Err(LexError::UnknownChar(c)),
+                _ => return Err(LexError::UnexpectedTermination)
+            }
+        },
+        '\'' => {
+            iter.next();
+            vec.push(Token::Quote);
+        },
+        '`' => {
+            iter.next();
+            vec.push(Token::Quasiquote);
+        },
+        ',' => {
+            iter.next();
+            if let Some(&'@') = iter.peek() {
+                iter.next();
+                vec.push(Token::UnquoteSplicing);
+            } else {
+                vec.push(Token::Unquote);
+            }
+        },
+        '.' => {
+            iter.next();
+            vec.push(Token::Dot);
+        },
+        '(' => {
+            iter.next();
+            vec.push(Token::OpenParen);
+        },
+        ')' => {
+            iter.next();
+            vec.push(Token::CloseParen);
+        },
+        '{' => {
+            iter.next();
+            vec.push(Token::OpenBrace);
+        },
+        '}' => {
+            iter.next();
+            vec.push(Token::CloseBrace);
+        }
+        ';' => {
+            let c = read_comment(iter);
+            if comments {
+                vec.push(c);
+            }
+        },
+        ' ' | '\t' | '\n' | '\r' => { iter.next(); },
+        c => return Err(LexError::UnknownChar(c))
+    }
+    Ok(())
+}
+
 fn read_number<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<Token, LexError> {
 
     let sign = if *iter.peek().unwrap() == '-' {
@@ -96,21 +334,37 @@ fn read_number<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<Token
 
     if let Some(Ok(_)) = iter.peek().map(|c| c.to_string().parse::<i64>()) {
 
-        // This is so hacky but so slick.
-        let mut num = 0;
+        // This is so hacky but so slick.  The sign goes on each digit so the most negative
+        // number doesn't overflow on its way in.
+        let mut num = Some(0i64);
         while let Some(Ok(digit)) = iter.peek().map(|c| c.to_string().parse::<i64>()) {
-            num = num * 10 + digit;
+            num = num.and_then(|n| n.checked_mul(10)).and_then(|n| n.checked_add(digit * sign));
             iter.next();
         }
 
-        Ok(Token::Number(num * sign))
+        num.map(Token::Number).ok_or(LexError::NumberOutOfRange)
 
     } else {
-        Ok(Token::Name(String::from("-")))
+
+        // Not a number after all, so it's a name like `-` or `->list`.
+        let mut name = String::from("-");
+        while let Some(&c) = iter.peek() {
+            if is_name_char(c, false) {
+                name.push(c);
+                iter.next();
+            } else {
+                break;
+            }
+        }
+
+        Ok(Token::Name(name))
+
     }
 
 }
 
+/// Reads a name, up to the first char that can't be part of one.  That char is left alone, so a
+/// bad one gets reported by itself instead of taking the whole name with it.
 fn read_name<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<Token, LexError> {
 
     let mut name = String::new();
@@ -125,7 +379,7 @@ fn read_name<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<Token,
             name.push(c);
             iter.next();
         } else {
-            return Err(LexError::UnknownChar(c));
+            break;
         }
     }
 
@@ -133,6 +387,79 @@ fn read_name<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<Token,
 
 }
 
+/// Reads what's after a `#\\`, which is either the character itself, a name like `space`, or a
+/// hex code like `x41`.
+fn read_char<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<Token, LexError> {
+
+    let first = match iter.next() {
+        Some(c) => c,
+        None => return Err(LexError::UnexpectedTermination)
+    };
+
+    // Only letters and digits can run on into a name, so `#\\(` is just a paren.
+    let mut name = first.to_string();
+    if first.is_alphanumeric() {
+        while let Some(&c) = iter.peek() {
+            if c.is_alphanumeric() || c == '-' {
+                name.push(c);
+                iter.next();
+            } else {
+                break;
+            }
+        }
+    }
+
+    if name.chars().count() == 1 {
+        return Ok(Token::Char(first));
+    }
+
+    if let Some(&(_, c)) = sexp::CHAR_NAMES.iter().find(|&&(n, _)| n == name) {
+        return Ok(Token::Char(c));
+    }
+
+    match name.strip_prefix('x').and_then(|h| u32::from_str_radix(h, 16).ok()).and_then(::std::char::from_u32) {
+        Some(c) => Ok(Token::Char(c)),
+        None => Err(LexError::UnknownCharName(name))
+    }
+
+}
+
+/// Reads a comment up to the end of the line, leaving the newline and any trailing spaces out.
+fn read_comment<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Token {
+    let mut text = String::new();
+    while let Some(&c) = iter.peek() {
+        if c == '\n' {
+            break;
+        }
+        text.push(c);
+        iter.next();
+    }
+    Token::Comment(text.trim_end().into())
+}
+
+fn read_keyword<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<Token, LexError> {
+
+    // The `#:` has already been eaten, so this is just the name part.
+    let mut name = String::new();
+    while let Some(&c) = iter.peek() {
+        if is_name_char(c, false) {
+            name.push(c);
+            iter.next();
+        } else if name.is_empty() {
+            return Err(LexError::UnknownChar(c));
+        } else {
+            break;
+        }
+    }
+
+    if name.is_empty() {
+        Err(LexError::UnexpectedTermination)
+    } else {
+        Ok(Token::Keyword(name))
+    }
+
+}
+
 fn read_string<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<Token, LexError> {
 
     let mut s = String::new();
@@ -142,7 +469,10 @@ fn read_string<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<Token
 
     while let Some(&c) = iter.peek() {
         match c {
-            '"' => break,
+            '"' => {
+                iter.next();
+                return Ok(Token::Str(s));
+            },
             '\\' => {
                 iter.next();
                 s.push(match iter.peek() {
@@ -150,17 +480,17 @@ fn read_string<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<Token
                     Some(& 'r') => '\r',
                     Some(& '\\') => '\\',
                     Some(& '"') => '"',
-                    Some(&c) => return Err(LexError::UnknownChar(c)),
-                    None => return Err(LexError::UnexpectedTermination)
+                    Some(&c) => return Err(LexError::BadEscape(c)),
+                    None => return Err(LexError::UnclosedString)
                 })
             },
-            '\n' | '\r' => return Err(LexError::UnknownChar(c)),
+            '\n' | '\r' => return Err(LexError::NewlineInString),
             v => s.push(v)
         }
         iter.next();
     }
 
-    Ok(Token::Str(s))
+    Err(LexError::UnclosedString)
 
 }
 
@@ -170,36 +500,187 @@ pub enum ParseError {
     UnexpectedTermination
 }
 
-pub fn parse<T: Iterator<Item = Token>>(iter: &mut Peekable<T>) -> Result<sexp::Sexp, ParseError> {
-    match iter.peek().cloned() {
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedToken(t) => write!(f, "unexpected `{}`", t),
+            ParseError::UnexpectedTermination => write!(f, "unexpected end of input")
+        }
+    }
+}
+
+/// Things the parser can parse, which is tokens that might know where they came from.
+pub trait ParseToken {
+    fn token(&self) -> &Token;
+    fn span(&self) -> Option<Span>;
+}
+
+impl ParseToken for Token {
+    fn token(&self) -> &Token {
+        self
+    }
+    fn span(&self) -> Option<Span> {
+        None
+    }
+}
+
+impl ParseToken for (Token, Span) {
+    fn token(&self) -> &Token {
+        &self.0
+    }
+    fn span(&self) -> Option<Span> {
+        Some(self.1)
+    }
+}
+
+#[inline]
+fn peek_token<I: ParseToken, T: Iterator<Item = I>>(iter: &mut Peekable<T>) -> Option<Token> {
+    iter.peek().map(|t| t.token().clone())
+}
+
+#[inline]
+fn peek_span<I: ParseToken, T: Iterator<Item = I>>(iter: &mut Peekable<T>) -> Option<Span> {
+    iter.peek().and_then(|t| t.span())
+}
+
+/// The span from `start` to the end of the token we're looking at, if we know both.
+#[inline]
+fn loc_to<I: ParseToken, T: Iterator<Item = I>>(start: Option<Span>, iter: &mut Peekable<T>) -> sexp::SrcLoc {
+    sexp::SrcLoc(match (start, peek_span(iter)) {
+        (Some(s), Some(e)) => Some(s.to(&e)),
+        _ => None
+    })
+}
+
+/// Parses one S-expression, leaving the iterator on its last token.
+pub fn parse<I: ParseToken, T: Iterator<Item = I>>(iter: &mut Peekable<T>) -> Result<sexp::Sexp, ParseError> {
+    match peek_token(iter) {
         Some(Token::OpenParen) => {
+            let start = peek_span(iter);
             let mut subs = Vec::new();
             iter.next();
-            while let Some(tok) = iter.peek().cloned() {
-                match tok {
-                    Token::CloseParen => break,
-                    _ => {
+            loop {
+                match peek_token(iter) {
+                    Some(Token::CloseParen) => return Ok(sexp::Sexp::List(subs, loc_to(start, iter))),
+                    Some(Token::Dot) => {
+
+                        // There has to be something before the dot, and exactly one thing after.
+                        if subs.is_empty() {
+                            return Err(ParseError::UnexpectedToken(Token::Dot));
+                        }
+
+                        iter.next();
+                        let tail = parse(iter)?;
+                        iter.next();
+                        return match peek_token(iter) {
+                            Some(Token::CloseParen) => Ok(sexp::Sexp::dotted(subs, tail)),
+                            Some(t) => Err(ParseError::UnexpectedToken(t)),
+                            None => Err(ParseError::UnexpectedTermination)
+                        };
+
+                    },
+                    Some(_) => {
                         subs.push(parse(iter)?);
                         iter.next();
-                    }
+                    },
+                    None => return Err(ParseError::UnexpectedTermination)
                 }
             }
-            Ok(sexp::Sexp::List(subs))
         },
-        Some(Token::Quote) => {
+        Some(Token::OpenVector) => {
+            let mut items = Vec::new();
             iter.next();
-            let sub = parse(iter)?;
-            Ok(sexp::Sexp::List(vec![sexp::Sexp::Symbol(String::from("quote")), sub]))
+            loop {
+                match peek_token(iter) {
+                    Some(Token::CloseParen) => return Ok(sexp::Sexp::Vector(items)),
+                    Some(Token::Dot) => return Err(ParseError::UnexpectedToken(Token::Dot)),
+                    Some(_) => {
+                        items.push(parse(iter)?);
+                        iter.next();
+                    },
+                    None => return Err(ParseError::UnexpectedTermination)
+                }
+            }
+        },
+        Some(Token::OpenBytes) => {
+            let mut bytes = Vec::new();
+            iter.next();
+            loop {
+                match peek_token(iter) {
+                    Some(Token::CloseParen) => return Ok(sexp::Sexp::ByteArray(bytes.into_boxed_slice())),
+                    Some(Token::Number(n)) if (0..=255).contains(&n) => {
+                        bytes.push(n as u8);
+                        iter.next();
+                    },
+                    Some(t) => return Err(ParseError::UnexpectedToken(t)),
+                    None => return Err(ParseError::UnexpectedTermination)
+                }
+            }
         },
+        Some(Token::OpenBrace) => {
+            let mut pairs = Vec::new();
+            iter.next();
+            loop {
+
+                // Keys have to be atoms that can be hashed, like `{a 1 "b" 2}`.
+                let key = match peek_token(iter) {
+                    Some(Token::CloseBrace) => return Ok(sexp::Sexp::Map(pairs)),
+                    Some(t) => match parse(iter)? {
+                        k @ sexp::Sexp::Integer(_) | k @ sexp::Sexp::Str(_) | k @ sexp::Sexp::Boolean(_) | k @ sexp::Sexp::Char(_) |
+                        k @ sexp::Sexp::Symbol(_) | k @ sexp::Sexp::Keyword(_) => k,
+                        _ => return Err(ParseError::UnexpectedToken(t))
+                    },
+                    None => return Err(ParseError::UnexpectedTermination)
+                };
+                iter.next();
+
+                match peek_token(iter) {
+                    Some(Token::CloseBrace) => return Err(ParseError::UnexpectedToken(Token::CloseBrace)),
+                    Some(_) => {
+                        pairs.push((key, parse(iter)?));
+                        iter.next();
+                    },
+                    None => return Err(ParseError::UnexpectedTermination)
+                }
+
+            }
+        },
+        Some(Token::Quote) => parse_prefixed("quote", iter),
+        Some(Token::Quasiquote) => parse_prefixed("quasiquote", iter),
+        Some(Token::Unquote) => parse_prefixed("unquote", iter),
+        Some(Token::UnquoteSplicing) => parse_prefixed("unquote-splicing", iter),
         Some(Token::Number(n)) => Ok(sexp::Sexp::Integer(n)),
         Some(Token::Name(ref s)) => Ok(sexp::Sexp::Symbol(s.clone())),
         Some(Token::Str(ref s)) => Ok(sexp::Sexp::Str(s.clone())),
         Some(Token::Bool(b)) => Ok(sexp::Sexp::Boolean(b)),
+        Some(Token::Char(c)) => Ok(sexp::Sexp::Char(c)),
+        Some(Token::Keyword(ref s)) => Ok(sexp::Sexp::Keyword(s.clone())),
         Some(Token::CloseParen) => Err(ParseError::UnexpectedToken(Token::CloseParen)),
-        None => return Err(ParseError::UnexpectedTermination)
+        Some(Token::CloseBrace) => Err(ParseError::UnexpectedToken(Token::CloseBrace)),
+        Some(Token::Dot) => Err(ParseError::UnexpectedToken(Token::Dot)),
+        Some(t @ Token::Comment(_)) => Err(ParseError::UnexpectedToken(t)),
+        None => Err(ParseError::UnexpectedTermination)
     }
 }
 
+/// Parses every S-expression until the tokens run out, like for a whole file.
+pub fn parse_all<I: ParseToken, T: Iterator<Item = I>>(iter: &mut Peekable<T>) -> Result<Vec<sexp::Sexp>, ParseError> {
+    let mut all = Vec::new();
+    while iter.peek().is_some() {
+        all.push(parse(iter)?);
+        iter.next();
+    }
+    Ok(all)
+}
+
+/// Desugars a reader prefix like `'x` into the `(quote x)` form.
+fn parse_prefixed<I: ParseToken, T: Iterator<Item = I>>(name: &str, iter: &mut Peekable<T>) -> Result<sexp::Sexp, ParseError> {
+    let start = peek_span(iter);
+    iter.next();
+    let sub = parse(iter)?;
+    Ok(sexp::Sexp::List(vec![sexp::Sexp::symb_str(name), sub], loc_to(start, iter)))
+}
+
 #[cfg(test)]
 pub mod tests {
 
@@ -211,6 +692,8 @@ pub mod tests {
         assert_eq!(n1, Ok(Token::Number(12345)));
         let n2 = super::read_number(&mut String::from("-1337").chars().peekable());
         assert_eq!(n2, Ok(Token::Number(-1337)));
+        let n3 = super::read_number(&mut String::from("99999999999999999999").chars().peekable());
+        assert_eq!(n3, Err(super::LexError::NumberOutOfRange));
     }
 
     #[test]
@@ -219,6 +702,20 @@ pub mod tests {
         assert_eq!(n1, Ok(Token::Name(String::from("hello"))));
         let n2 = super::read_name(&mut String::from("a").chars().peekable());
         assert_eq!(n2, Ok(Token::Name(String::from("a"))));
+        let n3 = super::read_name(&mut String::from("str-len").chars().peekable());
+        assert_eq!(n3, Ok(Token::Name(String::from("str-len"))));
+        let n4 = super::read_number(&mut String::from("->list").chars().peekable());
+        assert_eq!(n4, Ok(Token::Name(String::from("->list"))));
+        let n5 = super::read_name(&mut String::from("xs)").chars().peekable());
+        assert_eq!(n5, Ok(Token::Name(String::from("xs"))));
+
+        // A bad char after a name is left for the lexer, so recovery can skip just that.
+        let mut iter = "foo|bar".chars().peekable();
+        assert_eq!(super::read_name(&mut iter), Ok(Token::Name(String::from("foo"))));
+        assert_eq!(iter.next(), Some('|'));
+        let (toks, errors) = super::lex_recovering(&String::from("foo|bar"));
+        assert_eq!(toks.into_iter().map(|(t, _)| t).collect::<Vec<_>>(), vec![Token::Name(String::from("foo")), Token::Name(String::from("bar"))]);
+        assert_eq!(errors, vec![(super::LexError::UnknownChar('|'), super::Span { start: 3, end: 4, line: 1, col: 4 })]);
     }
 
     #[test]
@@ -229,6 +726,101 @@ pub mod tests {
         assert_eq!(s2, Ok(Token::Str(String::from("foo"))));
         let s3 = super::read_string(&mut String::from("\" t \\r e \\n s \\\" t \\\\ s \"").chars().peekable());
         assert_eq!(s3, Ok(Token::Str(String::from(" t \r e \n s \" t \\ s "))));
+        let s4 = super::read_string(&mut String::from("\"foo").chars().peekable());
+        assert_eq!(s4, Err(super::LexError::UnclosedString));
+        let s5 = super::read_string(&mut String::from("\"a\\q\"").chars().peekable());
+        assert_eq!(s5, Err(super::LexError::BadEscape('q')));
+        let s6 = super::read_string(&mut String::from("\"a\nb\"").chars().peekable());
+        assert_eq!(s6, Err(super::LexError::NewlineInString));
+        let toks = super::lex(&String::from("(a \"b\")"));
+        assert_eq!(toks, Ok(vec![Token::OpenParen, Token::Name(String::from("a")), Token::Str(String::from("b")), Token::CloseParen]));
+    }
+
+    #[test]
+    fn test_lex_hash_forms() {
+        use super::Token::*;
+        assert_eq!(
+            super::lex(&String::from("(#t #f #:width)")),
+            Ok(vec![OpenParen, Bool(true), Bool(false), Keyword(String::from("width")), CloseParen]));
+        assert_eq!(super::lex(&String::from("#: x")), Err(super::LexError::UnknownChar(' ')));
+        assert_eq!(super::lex(&String::from("#:")), Err(super::LexError::UnexpectedTermination));
+        assert_eq!(super::lex(&String::from("#(1)")), Ok(vec![OpenVector, Number(1), CloseParen]));
+        assert_eq!(super::lex(&String::from("#u8(1)")), Ok(vec![OpenBytes, Number(1), CloseParen]));
+        assert_eq!(super::lex(&String::from("#u7(")), Err(super::LexError::UnknownChar('7')));
+    }
+
+    #[test]
+    fn test_lex_comments() {
+        use super::Token::*;
+        let src = String::from("(a ; one\r\n;two;\n b)");
+        assert_eq!(super::lex(&src), Ok(vec![OpenParen, Name(String::from("a")), Name(String::from("b")), CloseParen]));
+        let toks: Vec<Token> = super::lex_with_comments(&src).unwrap().into_iter().map(|(t, _)| t).collect();
+        assert_eq!(toks[2], Comment(String::from("; one")));
+        assert_eq!(toks[3], Comment(String::from(";two;")));
+        assert_eq!(super::lex(&String::from("\"; not a comment\"")), Ok(vec![Str(String::from("; not a comment"))]));
+    }
+
+    #[test]
+    fn test_parse_map() {
+        use sexp::Sexp;
+        let toks = super::lex(&String::from("{a 1 \"b\" (c)}")).unwrap();
+        let m = super::parse(&mut toks.into_iter().peekable()).unwrap();
+        assert_eq!(m, Sexp::Map(vec![
+            (Sexp::symb_str("a"), Sexp::Integer(1)),
+            (Sexp::str_str("b"), Sexp::list(vec![Sexp::symb_str("c")]))]));
+        assert_eq!(format!("{}", m), "{a 1 \"b\" (c)}");
+        let toks = super::lex(&String::from("{a}")).unwrap();
+        assert_eq!(super::parse(&mut toks.into_iter().peekable()), Err(super::ParseError::UnexpectedToken(super::Token::CloseBrace)));
+        let toks = super::lex(&String::from("{(a) 1}")).unwrap();
+        assert_eq!(super::parse(&mut toks.into_iter().peekable()), Err(super::ParseError::UnexpectedToken(super::Token::OpenParen)));
+    }
+
+    #[test]
+    fn test_parse_vector() {
+        use sexp::Sexp;
+        let toks = super::lex(&String::from("#(1 (a) #())")).unwrap();
+        let v = super::parse(&mut toks.into_iter().peekable()).unwrap();
+        assert_eq!(v, Sexp::Vector(vec![Sexp::Integer(1), Sexp::list(vec![Sexp::symb_str("a")]), Sexp::Vector(vec![])]));
+        assert_eq!(format!("{}", v), "#(1 (a) #())");
+        let toks = super::lex(&String::from("#(1 . 2)")).unwrap();
+        assert_eq!(super::parse(&mut toks.into_iter().peekable()), Err(super::ParseError::UnexpectedToken(super::Token::Dot)));
+    }
+
+    #[test]
+    fn test_lex_chars() {
+        use super::Token::*;
+        let lex = |s: &str| super::lex(&String::from(s));
+        assert_eq!(lex("#\\a #\\Z #\\( #\\\\"), Ok(vec![Char('a'), Char('Z'), Char('('), Char('\\')]));
+        assert_eq!(lex("#\\space #\\newline #\\x41 #\\x3bb"), Ok(vec![Char(' '), Char('\n'), Char('A'), Char('\u{3bb}')]));
+        assert_eq!(lex("(#\\x)"), Ok(vec![OpenParen, Char('x'), CloseParen]));
+        assert_eq!(lex("#\\\u{3bb}"), Ok(vec![Char('\u{3bb}')]));
+        assert_eq!(lex("#\\nope"), Err(super::LexError::UnknownCharName(String::from("nope"))));
+        assert_eq!(lex("#\\xzz"), Err(super::LexError::UnknownCharName(String::from("xzz"))));
+        assert_eq!(lex("#\\"), Err(super::LexError::UnexpectedTermination));
+    }
+
+    #[test]
+    fn test_char_display() {
+        use sexp::Sexp;
+        for s in &["#\\a", "#\\space", "#\\newline", "#\\(", "#\\x1"] {
+            let toks = super::lex(&String::from(*s)).unwrap();
+            let c = super::parse(&mut toks.into_iter().peekable()).unwrap();
+            assert_eq!(format!("{}", c), *s);
+        }
+        assert_eq!(format!("{}", Sexp::Char('A')), "#\\A");
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        use sexp::Sexp;
+        let toks = super::lex(&String::from("#u8(0 16 255)")).unwrap();
+        let b = super::parse(&mut toks.into_iter().peekable()).unwrap();
+        assert_eq!(b, Sexp::ByteArray(vec![0, 16, 255].into_boxed_slice()));
+        assert_eq!(format!("{}", b), "#u8(0 16 255)");
+        let toks = super::lex(&String::from("#u8(256)")).unwrap();
+        assert_eq!(super::parse(&mut toks.into_iter().peekable()), Err(super::ParseError::UnexpectedToken(super::Token::Number(256))));
+        let toks = super::lex(&String::from("#u8(a)")).unwrap();
+        assert!(super::parse(&mut toks.into_iter().peekable()).is_err());
     }
 
     #[test]
@@ -236,10 +828,10 @@ pub mod tests {
 
         use super::Token::*;
         use sexp::Sexp;
-        let l = vec![OpenParen, Name(String::from("foo")), Str(String::from("bar")), CloseParen];
+        let l = [OpenParen, Name(String::from("foo")), Str(String::from("bar")), CloseParen];
         assert_eq!(
             super::parse(&mut l.iter().cloned().peekable()),
-            Ok(Sexp::List(vec![Sexp::symb_str("foo"), Sexp::str_str("bar")])));
+            Ok(Sexp::list(vec![Sexp::symb_str("foo"), Sexp::str_str("bar")])));
 
     }
 
@@ -248,10 +840,99 @@ pub mod tests {
 
         use super::Token::*;
         use sexp::Sexp;
-        let l = vec![Quote, OpenParen, Name(String::from("foo")), Str(String::from("bar")), CloseParen];
+        let l = [Quote, OpenParen, Name(String::from("foo")), Str(String::from("bar")), CloseParen];
         assert_eq!(
             super::parse(&mut l.iter().cloned().peekable()),
-            Ok(Sexp::List(vec![Sexp::symb_str("quote"), Sexp::List(vec![Sexp::symb_str("foo"), Sexp::str_str("bar")])])));
+            Ok(Sexp::list(vec![Sexp::symb_str("quote"), Sexp::list(vec![Sexp::symb_str("foo"), Sexp::str_str("bar")])])));
+
+    }
+
+    #[test]
+    fn test_parse_dotted() {
+
+        use sexp::Sexp;
+        let parse_str = |s: &str| super::parse(&mut super::lex(&String::from(s)).unwrap().into_iter().peekable());
+        assert_eq!(
+            parse_str("(a . b)"),
+            Ok(Sexp::DottedList(vec![Sexp::symb_str("a")], Box::new(Sexp::symb_str("b")))));
+        assert_eq!(
+            parse_str("(a b . (c . d))"),
+            Ok(Sexp::DottedList(vec![Sexp::symb_str("a"), Sexp::symb_str("b"), Sexp::symb_str("c")], Box::new(Sexp::symb_str("d")))));
+        assert_eq!(
+            parse_str("(a . (b))"),
+            Ok(Sexp::list(vec![Sexp::symb_str("a"), Sexp::symb_str("b")])));
+        assert_eq!(parse_str("(. a)"), Err(super::ParseError::UnexpectedToken(Token::Dot)));
+        assert_eq!(parse_str("(a . b c)"), Err(super::ParseError::UnexpectedToken(Token::Name(String::from("c")))));
+        assert_eq!(parse_str("(a b"), Err(super::ParseError::UnexpectedTermination));
+        assert_eq!(format!("{}", parse_str("(a (b . c) . 1)").unwrap()), "(a (b . c) . 1)");
+
+    }
+
+    #[test]
+    fn test_lex_spans() {
+        use span::Span;
+        let toks = super::lex_spanned(&String::from("(foo\n  \"é\" 12)")).unwrap();
+        let spans: Vec<Span> = toks.into_iter().map(|(_, s)| s).collect();
+        assert_eq!(spans, vec![
+            Span { start: 0, end: 1, line: 1, col: 1 },
+            Span { start: 1, end: 4, line: 1, col: 2 },
+            Span { start: 7, end: 11, line: 2, col: 3 },
+            Span { start: 12, end: 14, line: 2, col: 7 },
+            Span { start: 14, end: 15, line: 2, col: 9 }]);
+    }
+
+    #[test]
+    fn test_parse_spans() {
+        use sexp::Sexp;
+        use span::Span;
+        let toks = super::lex_spanned(&String::from("(a\n (b c)) 'd")).unwrap();
+        let all = super::parse_all(&mut toks.into_iter().peekable()).unwrap();
+        assert_eq!(all.len(), 2);
+        match all[0] {
+            Sexp::List(ref l, ref loc) => {
+                assert_eq!(loc.0, Some(Span { start: 0, end: 10, line: 1, col: 1 }));
+                match l[1] {
+                    Sexp::List(_, ref loc) => assert_eq!(loc.0, Some(Span { start: 4, end: 9, line: 2, col: 2 })),
+                    _ => panic!()
+                }
+            },
+            _ => panic!()
+        }
+        match all[1] {
+            Sexp::List(_, ref loc) => assert_eq!(loc.0, Some(Span { start: 11, end: 13, line: 2, col: 9 })),
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_lex_quasiquote() {
+        use super::Token::*;
+        assert_eq!(
+            super::lex(&String::from("`(a ,b ,@c)")),
+            Ok(vec![
+                Quasiquote,
+                OpenParen,
+                Name(String::from("a")),
+                Unquote,
+                Name(String::from("b")),
+                UnquoteSplicing,
+                Name(String::from("c")),
+                CloseParen]));
+    }
+
+    #[test]
+    fn test_parse_quasiquote() {
+
+        use sexp::Sexp;
+        let toks = super::lex(&String::from("`(a ,b ,@c)")).unwrap();
+        assert_eq!(
+            super::parse(&mut toks.into_iter().peekable()),
+            Ok(Sexp::list(vec![
+                Sexp::symb_str("quasiquote"),
+                Sexp::list(vec![
+                    Sexp::symb_str("a"),
+                    Sexp::list(vec![Sexp::symb_str("unquote"), Sexp::symb_str("b")]),
+                    Sexp::list(vec![Sexp::symb_str("unquote-splicing"), Sexp::symb_str("c")])])])));
 
     }
 
@@ -0,0 +1,388 @@
+//! Writes S-expressions back out as text, either on one line with `write` or laid out to fit a
+//! width with `pretty`.
+//!
+//! Whatever comes out reads back in as an equal `Sexp`.  The one exception is `Null`, which is
+//! written as `()` and so comes back as the empty list, which means the same thing.
+
+use std::fmt::Write;
+
+use sexp::{Sexp, CHAR_NAMES};
+use {lex, Token};
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum WriteError {
+
+    /// A symbol or keyword that wouldn't read back as one, like `a b` or `12`.
+    BadName(String),
+
+    /// A map key that isn't an atom.
+    BadKey(Sexp),
+
+    /// A dotted list with nothing before the dot, or with a list after it.
+    BadDottedList
+
+}
+
+/// Writes the whole thing on one line, spelling out quote forms like `(quote x)`.
+pub fn write(s: &Sexp) -> Result<String, WriteError> {
+    check(s)?;
+    let mut out = String::new();
+    write_flat(s, false, &mut out);
+    Ok(out)
+}
+
+/// Lays the expression out so lines stay within `width` chars where they can.  Things that are
+/// too long by themselves, like a long string, still stick out past it.
+pub fn pretty(s: &Sexp, width: usize) -> Result<String, WriteError> {
+    check(s)?;
+    let mut out = String::new();
+    layout(s, width, &mut out);
+    Ok(out)
+}
+
+/// Quotes a string with only the escapes `read_string` knows how to read back.
+pub fn write_str(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+/// Makes sure everything in there can be written so it reads back the same.
+fn check(s: &Sexp) -> Result<(), WriteError> {
+    match s {
+
+        // The easiest way to know that a name reads back is to read it back.
+        Sexp::Symbol(n) if lex(n) != Ok(vec![Token::Name(n.clone())]) => Err(WriteError::BadName(n.clone())),
+        Sexp::Keyword(n) => {
+            let text = format!("#:{}", n);
+            if lex(&text) == Ok(vec![Token::Keyword(n.clone())]) {
+                Ok(())
+            } else {
+                Err(WriteError::BadName(text))
+            }
+        },
+
+        Sexp::List(l, _) | Sexp::Vector(l) => l.iter().try_for_each(check),
+        Sexp::DottedList(l, t) => match t.as_ref() {
+            _ if l.is_empty() => Err(WriteError::BadDottedList),
+            &Sexp::Null | &Sexp::List(_, _) | &Sexp::DottedList(_, _) => Err(WriteError::BadDottedList),
+            t => {
+                l.iter().try_for_each(check)?;
+                check(t)
+            }
+        },
+        Sexp::Map(pairs) => pairs.iter().try_for_each(|(k, v)| {
+            match k {
+                &Sexp::Integer(_) | &Sexp::Str(_) | &Sexp::Boolean(_) | &Sexp::Char(_) | &Sexp::Symbol(_) | &Sexp::Keyword(_) => check(k)?,
+                k => return Err(WriteError::BadKey(k.clone()))
+            }
+            check(v)
+        }),
+        _ => Ok(())
+
+    }
+}
+
+/// The `'` for `(quote x)` and friends, along with the `x`.
+fn shorthand(l: &[Sexp]) -> Option<(&'static str, &Sexp)> {
+    let prefix = match l {
+        &[Sexp::Symbol(ref n), _] => match n.as_str() {
+            "quote" => "'",
+            "quasiquote" => "`",
+            "unquote" => ",",
+            "unquote-splicing" => ",@",
+            _ => return None
+        },
+        _ => return None
+    };
+    Some((prefix, &l[1]))
+}
+
+/// Writes it all on one line.  This is also what `Display` does, minus the checks.
+pub(crate) fn write_flat(s: &Sexp, short: bool, out: &mut String) {
+    match s {
+        Sexp::Null => out.push_str("()"),
+        Sexp::Integer(i) => write!(out, "{}", i).unwrap(),
+        Sexp::ByteArray(a) => {
+            out.push_str("#u8(");
+            for (i, b) in a.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                write!(out, "{}", b).unwrap();
+            }
+            out.push(')');
+        },
+        Sexp::Char(c) => match CHAR_NAMES.iter().find(|&&(_, nc)| nc == *c) {
+            Some(&(n, _)) => write!(out, "#\\{}", n).unwrap(),
+            None if c.is_control() => write!(out, "#\\x{:x}", *c as u32).unwrap(),
+            None => write!(out, "#\\{}", c).unwrap()
+        },
+        Sexp::Str(s) => write_str(s, out),
+        Sexp::Boolean(b) => out.push_str(if *b { "#t" } else { "#f" }),
+        Sexp::Symbol(s) => out.push_str(s),
+        Sexp::Keyword(s) => write!(out, "#:{}", s).unwrap(),
+        Sexp::List(l, _) => match shorthand(l) {
+            Some((prefix, sub)) if short => {
+                out.push_str(prefix);
+                write_flat(sub, short, out);
+            },
+            _ => {
+                out.push('(');
+                write_items(l, short, out);
+                out.push(')');
+            }
+        },
+        Sexp::DottedList(l, t) => {
+            out.push('(');
+            write_items(l, short, out);
+            out.push_str(" . ");
+            write_flat(t, short, out);
+            out.push(')');
+        },
+        Sexp::Vector(l) => {
+            out.push_str("#(");
+            write_items(l, short, out);
+            out.push(')');
+        },
+        Sexp::Map(l) => {
+            out.push('{');
+            for (i, (k, v)) in l.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                write_flat(k, short, out);
+                out.push(' ');
+                write_flat(v, short, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_items(items: &[Sexp], short: bool, out: &mut String) {
+    for (i, s) in items.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        write_flat(s, short, out);
+    }
+}
+
+/// How far along the last line we are, in chars.
+pub(crate) fn column(out: &str) -> usize {
+    out[out.rfind('\n').map_or(0, |i| i + 1)..].chars().count()
+}
+
+pub(crate) fn newline(indent: usize, out: &mut String) {
+    out.push('\n');
+    out.extend(::std::iter::repeat_n(' ', indent));
+}
+
+/// How many arguments go on the first line with the name of a form like `define`, before the
+/// body starts on its own lines.  `named` is whether the first argument is a symbol, since a
+/// named let has the name first and then the bindings.
+pub(crate) fn header_len(head: &str, named: bool) -> Option<usize> {
+    match head {
+        "define" | "lambda" | "vlambda" => Some(1),
+        "let" if named => Some(2),
+        "let" | "let*" | "letrec" => Some(1),
+        _ => None
+    }
+}
+
+/// Same as `header_len`, but only if there's a body after the header.
+fn form_header(l: &[Sexp]) -> Option<usize> {
+    match l {
+        &[Sexp::Symbol(ref head), ref rest @ ..] => {
+            let n = header_len(head, matches!(rest.first(), Some(&Sexp::Symbol(_))))?;
+            if rest.len() > n {
+                Some(n)
+            } else {
+                None
+            }
+        },
+        _ => None
+    }
+}
+
+/*
+ * If it fits on the rest of the line then it goes there.  Otherwise forms like `define` keep
+ * their header on the first line and indent the body by two, calls line their arguments up under
+ * the first one (which covers `if`), and anything else gets one item per line just inside the
+ * bracket.
+ */
+fn layout(s: &Sexp, width: usize, out: &mut String) {
+
+    let col = column(out);
+    let mut flat = String::new();
+    write_flat(s, true, &mut flat);
+    if col + flat.chars().count() <= width {
+        out.push_str(&flat);
+        return;
+    }
+
+    match s {
+        Sexp::List(l, _) => {
+            if let Some((prefix, sub)) = shorthand(l) {
+                out.push_str(prefix);
+                layout(sub, width, out);
+                return;
+            }
+            out.push('(');
+            match (form_header(l), l.first()) {
+                (Some(n), _) => {
+                    layout_inline(&l[..n + 1], width, out);
+                    for s in &l[n + 1..] {
+                        newline(col + 2, out);
+                        layout(s, width, out);
+                    }
+                },
+                (None, Some(&Sexp::Symbol(_))) if l.len() > 1 => {
+                    layout_inline(&l[..2], width, out);
+                    let indent = col + 1 + flat_len(&l[0]) + 1;
+                    layout_lines(&l[2..], indent, width, out);
+                },
+                _ => layout_items(l, col + 1, width, out)
+            }
+            out.push(')');
+        },
+        Sexp::DottedList(l, t) => {
+            out.push('(');
+            layout_items(l, col + 1, width, out);
+            newline(col + 1, out);
+            out.push_str(". ");
+            layout(t, width, out);
+            out.push(')');
+        },
+        Sexp::Vector(l) => {
+            out.push_str("#(");
+            layout_items(l, col + 2, width, out);
+            out.push(')');
+        },
+        Sexp::Map(pairs) => {
+            out.push('{');
+            for (i, (k, v)) in pairs.iter().enumerate() {
+                if i > 0 {
+                    newline(col + 1, out);
+                }
+                write_flat(k, true, out);
+                out.push(' ');
+                layout(v, width, out);
+            }
+            out.push('}');
+        },
+
+        // Atoms can't be broken up, so they just stick out.
+        _ => out.push_str(&flat)
+    }
+
+}
+
+fn flat_len(s: &Sexp) -> usize {
+    let mut flat = String::new();
+    write_flat(s, true, &mut flat);
+    flat.chars().count()
+}
+
+/// Everything on the current line, separated by spaces.
+fn layout_inline(items: &[Sexp], width: usize, out: &mut String) {
+    for (i, s) in items.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        layout(s, width, out);
+    }
+}
+
+/// Each one on its own new line.
+fn layout_lines(items: &[Sexp], indent: usize, width: usize, out: &mut String) {
+    for s in items {
+        newline(indent, out);
+        layout(s, width, out);
+    }
+}
+
+/// The first one where we are, and the rest on their own lines under it.
+fn layout_items(items: &[Sexp], indent: usize, width: usize, out: &mut String) {
+    if let Some((first, rest)) = items.split_first() {
+        layout(first, width, out);
+        layout_lines(rest, indent, width, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use sexp::Sexp;
+    use super::{pretty, write, WriteError};
+
+    fn read(s: &str) -> Sexp {
+        ::parse(&mut ::lex(&String::from(s)).unwrap().into_iter().peekable()).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cases = [
+            "(a (b . c) #(1 -2 #u8(3)) {k \"v\" #\\a #t})",
+            "(\"tab\there\" \"q\\\"\\\\\\n\\r\" #\\space #\\x1 #\\( #\\\u{3bb} #:key)",
+            "('a `(b ,c ,@d) (quote))",
+            "-9223372036854775808"
+        ];
+        for c in &cases {
+            let s = read(c);
+            assert_eq!(read(&write(&s).unwrap()), s);
+            for w in &[0, 10, 80] {
+                assert_eq!(read(&pretty(&s, *w).unwrap()), s);
+            }
+        }
+        assert_eq!(write(&read("'(a \"b\")")).unwrap(), "(quote (a \"b\"))");
+        assert_eq!(read(&write(&Sexp::Str("\u{0}\t\u{1b}".into())).unwrap()), Sexp::Str("\u{0}\t\u{1b}".into()));
+    }
+
+    #[test]
+    fn test_unwritable() {
+        assert_eq!(write(&Sexp::symb_str("a b")), Err(WriteError::BadName("a b".into())));
+        assert_eq!(write(&Sexp::symb_str("12")), Err(WriteError::BadName("12".into())));
+        assert_eq!(write(&Sexp::Keyword("".into())), Err(WriteError::BadName("#:".into())));
+        assert_eq!(write(&Sexp::DottedList(vec![], Box::new(Sexp::Integer(1)))), Err(WriteError::BadDottedList));
+        assert_eq!(write(&Sexp::DottedList(vec![Sexp::Integer(1)], Box::new(Sexp::list(vec![])))), Err(WriteError::BadDottedList));
+        let key = Sexp::list(vec![]);
+        assert_eq!(write(&Sexp::Map(vec![(key.clone(), Sexp::Null)])), Err(WriteError::BadKey(key)));
+    }
+
+    #[test]
+    fn test_pretty() {
+        let src = "(define (f x) (if (> x 100) (display \"big number\") (g 'x (h x x x))))";
+        assert_eq!(pretty(&read(src), 80).unwrap(), src.replace("(quote x)", "'x"));
+        assert_eq!(pretty(&read(src), 40).unwrap(), "\
+(define (f x)
+  (if (> x 100)
+      (display \"big number\")
+      (g 'x (h x x x))))");
+        assert_eq!(pretty(&read(src), 20).unwrap(), "\
+(define (f x)
+  (if (> x 100)
+      (display \"big number\")
+      (g 'x
+         (h x x x))))");
+        assert_eq!(pretty(&read("(let loop ((i 0) (acc ())) (loop (+ i 1) acc))"), 30).unwrap(), "\
+(let loop ((i 0) (acc ()))
+  (loop (+ i 1) acc))");
+        assert_eq!(pretty(&read("(lambda (a) #(1 2 3) {a 1 b 2})"), 10).unwrap(), "\
+(lambda (a)
+  #(1 2 3)
+  {a 1
+   b 2})");
+        assert_eq!(pretty(&read("((f a) (g b) . c)"), 10).unwrap(), "((f a)\n (g b)\n . c)");
+    }
+
+}
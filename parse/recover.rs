@@ -0,0 +1,339 @@
+//! A parser that keeps going after syntax errors, so editors and `mglisp check` can show every
+//! problem in a file at once instead of just the first one.
+//!
+//! Whatever it could make sense of still comes out as a tree.  For a file without errors that's
+//! exactly what `parse_all` gives back.
+
+use std::fmt;
+use std::iter::Peekable;
+
+use sexp::{Sexp, SrcLoc};
+use span::Span;
+use {lex_recovering, Token};
+
+/// A syntax error and where it was.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, col {}: {}", self.span.line, self.span.col, self.message)
+    }
+}
+
+/// Parses as much as it can, giving back the forms along with everything that was wrong, in the
+/// order they appear in the source.
+pub fn parse_recovering(input: &str) -> (Vec<Sexp>, Vec<Diagnostic>) {
+
+    let (toks, lex_errors) = lex_recovering(input);
+    let mut diags: Vec<Diagnostic> = lex_errors.into_iter()
+        .map(|(e, span)| Diagnostic { span, message: e.to_string() })
+        .collect();
+
+    // Only guess where forms start if there really is a bracket missing somewhere.
+    let opens = toks.iter().filter(|&(t, _)| is_open(t)).count();
+    let closes = toks.iter().filter(|&(t, _)| is_close(t)).count();
+
+    let mut p = Parser { toks: toks.into_iter().peekable(), diags: Vec::new(), last: Span::default(), unbalanced: opens > closes };
+    let mut forms = Vec::new();
+    while p.toks.peek().is_some() {
+        if let Some(f) = p.form() {
+            forms.push(f);
+        }
+    }
+
+    diags.extend(p.diags);
+    diags.sort_by_key(|d| d.span.start);
+    (forms, diags)
+
+}
+
+fn is_open(t: &Token) -> bool {
+    matches!(t, &Token::OpenParen | &Token::OpenVector | &Token::OpenBytes | &Token::OpenBrace)
+}
+
+fn is_close(t: &Token) -> bool {
+    matches!(t, &Token::CloseParen | &Token::CloseBrace)
+}
+
+/// How a group ended, if it did.
+enum End {
+    Closed,
+
+    /// Ran out of tokens, or got to something that looks like the start of the next form.
+    Unclosed,
+
+    /// Got to the dot in a list, which the caller deals with.
+    Dot
+}
+
+struct Parser<I: Iterator<Item = (Token, Span)>> {
+    toks: Peekable<I>,
+    diags: Vec<Diagnostic>,
+
+    /// The last token we took, for where lists end.
+    last: Span,
+
+    /// Whether there are more opening brackets than closing ones.  If there are, an opening paren
+    /// at the start of a line is taken to be a new top-level form, so one missing paren doesn't
+    /// swallow the rest of the file.
+    unbalanced: bool
+}
+
+impl<I: Iterator<Item = (Token, Span)>> Parser<I> {
+
+    fn error(&mut self, span: Span, message: String) {
+        self.diags.push(Diagnostic { span, message });
+    }
+
+    fn bump(&mut self) -> Option<(Token, Span)> {
+        let next = self.toks.next();
+        if let Some((_, span)) = next {
+            self.last = span;
+        }
+        next
+    }
+
+    /// Parses one form and gives it back, or reports why it couldn't and skips past it.
+    fn form(&mut self) -> Option<Sexp> {
+        let (t, span) = self.bump()?;
+        Some(match t {
+            Token::OpenParen => self.list(span),
+            Token::OpenVector => {
+                let (items, _) = self.items(span, Token::CloseParen);
+                Sexp::Vector(items.into_iter().map(|(s, _)| s).collect())
+            },
+            Token::OpenBytes => {
+                let (items, _) = self.items(span, Token::CloseParen);
+                let mut bytes = Vec::new();
+                for (s, span) in items {
+                    match s {
+                        Sexp::Integer(n) if (0..=255).contains(&n) => bytes.push(n as u8),
+                        s => self.error(span, format!("byte arrays can only have integers from 0 to 255, not {}", s))
+                    }
+                }
+                Sexp::ByteArray(bytes.into_boxed_slice())
+            },
+            Token::OpenBrace => self.map(span),
+            Token::Quote => return self.prefixed("quote", t, span),
+            Token::Quasiquote => return self.prefixed("quasiquote", t, span),
+            Token::Unquote => return self.prefixed("unquote", t, span),
+            Token::UnquoteSplicing => return self.prefixed("unquote-splicing", t, span),
+            Token::Number(n) => Sexp::Integer(n),
+            Token::Name(s) => Sexp::Symbol(s),
+            Token::Str(s) => Sexp::Str(s),
+            Token::Bool(b) => Sexp::Boolean(b),
+            Token::Char(c) => Sexp::Char(c),
+            Token::Keyword(s) => Sexp::Keyword(s),
+            t => {
+                self.error(span, format!("unexpected `{}`", t));
+                return None;
+            }
+        })
+    }
+
+    /*
+     * Reads forms until the closing bracket.  The wrong kind of closing bracket gets reported
+     * but still closes the group, since that's usually what was meant.  It stops without taking
+     * anything at a dot in a list, or at what looks like the next top-level form if there's a
+     * bracket missing.
+     */
+    fn group(&mut self, open: Span, close: &Token, dots: bool, items: &mut Vec<(Sexp, Span)>) -> End {
+        loop {
+            let (t, span) = match self.toks.peek() {
+                Some(&(ref t, span)) => (t.clone(), span),
+                None => return End::Unclosed
+            };
+            if t == *close {
+                self.bump();
+                return End::Closed;
+            }
+            match t {
+                Token::Dot if dots => return End::Dot,
+                Token::OpenParen if self.unbalanced && span.col == 1 && span.line > open.line => return End::Unclosed,
+                Token::CloseParen | Token::CloseBrace => {
+                    self.bump();
+                    self.error(span, format!("expected `{}` to match line {}, col {}, got `{}`", close, open.line, open.col, t));
+                    return End::Closed;
+                },
+                _ => {
+                    if let Some(s) = self.form() {
+                        items.push((s, span.to(&self.last)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn items(&mut self, open: Span, close: Token) -> (Vec<(Sexp, Span)>, bool) {
+        let mut items = Vec::new();
+        let closed = match self.group(open, &close, false, &mut items) {
+            End::Closed => true,
+            _ => {
+                self.unclosed(open);
+                false
+            }
+        };
+        (items, closed)
+    }
+
+    fn unclosed(&mut self, open: Span) {
+        self.error(open, "this is never closed".into());
+    }
+
+    fn list(&mut self, open: Span) -> Sexp {
+
+        let mut items = Vec::new();
+        let end = self.group(open, &Token::CloseParen, true, &mut items);
+        let items: Vec<Sexp> = items.into_iter().map(|(s, _)| s).collect();
+        match end {
+            End::Closed => return Sexp::List(items, SrcLoc(Some(open.to(&self.last)))),
+            End::Unclosed => {
+                self.unclosed(open);
+                return Sexp::List(items, SrcLoc(Some(open.to(&self.last))));
+            },
+            End::Dot => {}
+        }
+
+        // There has to be something before the dot, and exactly one thing after it.
+        let (_, dot) = self.bump().unwrap();
+        let mut rest = Vec::new();
+        let end = self.group(open, &Token::CloseParen, true, &mut rest);
+        if let End::Unclosed = end {
+            self.unclosed(open);
+        }
+        if let End::Dot = end {
+            let (_, span) = self.bump().unwrap();
+            self.error(span, "only one `.` can go in a list".into());
+            let mut more = Vec::new();
+            if let End::Unclosed = self.group(open, &Token::CloseParen, false, &mut more) {
+                self.unclosed(open);
+            }
+        }
+
+        let mut rest = rest.into_iter();
+        let tail = match rest.next() {
+            Some((tail, _)) if !items.is_empty() => tail,
+            Some(_) => {
+                self.error(dot, "there has to be something before the `.`".into());
+                return Sexp::List(items, SrcLoc(Some(open.to(&self.last))));
+            },
+            None => {
+                self.error(dot, "there has to be something after the `.`".into());
+                return Sexp::List(items, SrcLoc(Some(open.to(&self.last))));
+            }
+        };
+        if let Some((_, span)) = rest.next() {
+            self.error(span, "only one thing can come after the `.`".into());
+        }
+        Sexp::dotted(items, tail)
+
+    }
+
+    fn map(&mut self, open: Span) -> Sexp {
+
+        let (items, closed) = self.items(open, Token::CloseBrace);
+        let mut pairs = Vec::new();
+        let mut items = items.into_iter();
+        while let Some((k, span)) = items.next() {
+            let v = match items.next() {
+                Some((v, _)) => v,
+                None if closed => {
+                    self.error(span, format!("map key {} has no value", k));
+                    break;
+                },
+                None => break
+            };
+            match k {
+                Sexp::Integer(_) | Sexp::Str(_) | Sexp::Boolean(_) | Sexp::Char(_) | Sexp::Symbol(_) | Sexp::Keyword(_) => pairs.push((k, v)),
+                k => self.error(span, format!("map keys have to be atoms, not {}", k))
+            }
+        }
+        Sexp::Map(pairs)
+
+    }
+
+    fn prefixed(&mut self, name: &str, prefix: Token, start: Span) -> Option<Sexp> {
+        let missing = match self.toks.peek() {
+            Some((t, _)) => is_close(t) || *t == Token::Dot,
+            None => true
+        };
+        if missing {
+            self.error(start, format!("nothing comes after the `{}`", prefix));
+            return None;
+        }
+        let sub = self.form()?;
+        Some(Sexp::List(vec![Sexp::symb_str(name), sub], SrcLoc(Some(start.to(&self.last)))))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use sexp::Sexp;
+    use span::Span;
+    use super::parse_recovering;
+
+    fn problems(src: &str) -> Vec<(usize, usize, String)> {
+        let (_, diags) = parse_recovering(&String::from(src));
+        diags.into_iter().map(|d| (d.span.line, d.span.col, d.message)).collect()
+    }
+
+    #[test]
+    fn test_same_as_parse() {
+        let src = String::from("(define (f x) ; hi\n  `(a ,x . #(1 #u8(2) {k \"v\"}))) 'b #\\a");
+        let toks = ::lex(&src).unwrap();
+        let (forms, diags) = parse_recovering(&src);
+        assert_eq!(forms, ::parse_all(&mut toks.into_iter().peekable()).unwrap());
+        assert!(diags.is_empty());
+    }
+
+    #[test]
+    fn test_many_errors() {
+        let src = "(a | b)\n(c \"bad\\q\" d)\n) (e . )\n{f}";
+        assert_eq!(problems(src), vec![
+            (1, 4, "unexpected character '|'".into()),
+            (2, 4, "unknown escape \\q in string".into()),
+            (3, 1, "unexpected `)`".into()),
+            (3, 6, "there has to be something after the `.`".into()),
+            (4, 2, "map key f has no value".into())]);
+        let (forms, _) = parse_recovering(&String::from(src));
+        assert_eq!(forms[0], Sexp::list(vec![Sexp::symb_str("a"), Sexp::symb_str("b")]));
+        assert_eq!(forms[1], Sexp::list(vec![Sexp::symb_str("c"), Sexp::symb_str("d")]));
+        assert_eq!(forms.len(), 4);
+    }
+
+    #[test]
+    fn test_broken_strings() {
+        assert_eq!(problems("(a \"b\nc)"), vec![(1, 4, "string runs onto the next line".into())]);
+        assert_eq!(problems("(a) \"b"), vec![(1, 5, "string is never closed".into())]);
+    }
+
+    #[test]
+    fn test_missing_parens() {
+        let src = "(define (f x)\n  (g x)\n\n(define (h y)\n  (k y]\n(l 99999999999999999999 #(1 . 2) #u8(300) '";
+        assert_eq!(problems(src), vec![
+            (1, 1, "this is never closed".into()),
+            (4, 1, "this is never closed".into()),
+            (5, 3, "this is never closed".into()),
+            (5, 7, "unexpected character ']'".into()),
+            (6, 1, "this is never closed".into()),
+            (6, 4, "number is too big".into()),
+            (6, 29, "unexpected `.`".into()),
+            (6, 38, "byte arrays can only have integers from 0 to 255, not 300".into()),
+            (6, 43, "nothing comes after the `'`".into())]);
+        let (forms, diags) = parse_recovering(&String::from(src));
+        assert_eq!(forms.len(), 3);
+        assert_eq!(diags[0].span, Span { start: 0, end: 1, line: 1, col: 1 });
+        assert_eq!(format!("{}", diags[3]), "line 5, col 7: unexpected character ']'");
+
+        // Balanced files don't get any guessing, even with forms at the start of a line.
+        assert!(problems("'(\n(1 2)\n(3 4))").is_empty());
+        assert_eq!(problems("(a }"), vec![(1, 4, "expected `)` to match line 1, col 1, got `}`".into())]);
+    }
+
+}
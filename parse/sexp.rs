@@ -1,6 +1,38 @@
 
 #![allow(unused)]
 
+use std::fmt;
+
+use pretty;
+use span::Span;
+
+/// Where a list came from in the source, if it came from source at all.
+///
+/// This is just metadata, so it's ignored when comparing expressions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SrcLoc(pub Option<Span>);
+
+impl PartialEq for SrcLoc {
+    fn eq(&self, _other: &SrcLoc) -> bool {
+        true
+    }
+}
+
+impl Eq for SrcLoc {}
+
+/// Characters that are written by name, like `#\\space`.
+pub const CHAR_NAMES: &[(&str, char)] = &[
+    ("space", ' '),
+    ("newline", '\n'),
+    ("tab", '\t'),
+    ("return", '\r'),
+    ("null", '\0'),
+    ("alarm", '\u{7}'),
+    ("backspace", '\u{8}'),
+    ("escape", '\u{1b}'),
+    ("delete", '\u{7f}')
+];
+
 /// Some data value.
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum Sexp {
@@ -11,8 +43,11 @@ pub enum Sexp {
     /// 64-bit integer.
     Integer(i64),
 
-    /// Byte array.
-    ByteArray(Box<[i8]>),
+    /// Byte array, like `#u8(1 2 255)`.
+    ByteArray(Box<[u8]>),
+
+    /// A single Unicode character, like `#\\a`.
+    Char(char),
 
     /// UTF-8 string.
     Str(String),
@@ -23,8 +58,20 @@ pub enum Sexp {
     /// A symbol that's not a string.
     Symbol(String),
 
+    /// A keyword like `#:width`, which evaluates to itself.
+    Keyword(String),
+
     /// List of S-expresions.
-    List(Vec<Sexp>),
+    List(Vec<Sexp>, SrcLoc),
+
+    /// Improper list, like `(a b . c)`.  The tail is never a list itself.
+    DottedList(Vec<Sexp>, Box<Sexp>),
+
+    /// Vector literal, like `#(1 2 3)`.
+    Vector(Vec<Sexp>),
+
+    /// Hash map literal, like `{a 1 b 2}`.  Keys are always atoms.
+    Map(Vec<(Sexp, Sexp)>),
 
 }
 
@@ -38,4 +85,33 @@ impl Sexp {
         Sexp::Str(String::from(s))
     }
 
+    /// A list that didn't come from any source.
+    pub fn list(items: Vec<Sexp>) -> Sexp {
+        Sexp::List(items, SrcLoc::default())
+    }
+
+    /// Makes a dotted list, folding the tail in if it's actually a list so `(a . (b))` is `(a b)`.
+    pub fn dotted(mut head: Vec<Sexp>, tail: Sexp) -> Sexp {
+        match tail {
+            Sexp::List(rest, _) => {
+                head.extend(rest);
+                Sexp::list(head)
+            },
+            Sexp::DottedList(rest, t) => {
+                head.extend(rest);
+                Sexp::DottedList(head, t)
+            },
+            t => Sexp::DottedList(head, Box::new(t))
+        }
+    }
+
+}
+
+/// The same as `pretty::write`, except it writes things that won't read back in too.
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        pretty::write_flat(self, false, &mut out);
+        f.write_str(&out)
+    }
 }
@@ -0,0 +1,66 @@
+/// A region of source text.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Span {
+
+    /// Byte offset of the first character.
+    pub start: usize,
+
+    /// Byte offset just past the last character.
+    pub end: usize,
+
+    /// Line of the first character, starting at 1.
+    pub line: usize,
+
+    /// Column of the first character, starting at 1 and counted in chars.
+    pub col: usize
+
+}
+
+impl Span {
+
+    /// The span covering both of these, assuming `self` comes first.
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            end: other.end,
+            .. *self
+        }
+    }
+
+}
+
+/// A position in the source, for keeping track of where the lexer is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Pos {
+    pub offset: usize,
+    pub line: usize,
+    pub col: usize
+}
+
+impl Pos {
+
+    pub fn start() -> Pos {
+        Pos {
+            offset: 0,
+            line: 1,
+            col: 1
+        }
+    }
+
+    /// Where we end up after reading the char.
+    pub fn advance(&self, c: char) -> Pos {
+        if c == '\n' {
+            Pos {
+                offset: self.offset + c.len_utf8(),
+                line: self.line + 1,
+                col: 1
+            }
+        } else {
+            Pos {
+                offset: self.offset + c.len_utf8(),
+                col: self.col + 1,
+                .. *self
+            }
+        }
+    }
+
+}
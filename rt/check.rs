@@ -0,0 +1,37 @@
+//! `mglisp check`, which reports every syntax error in scripts without running them.
+
+use std::fs;
+
+use parser::recover;
+
+/// Checks every file named in `args`, printing each problem as `path:line:col: message`.  Gives
+/// back the exit status.
+pub fn run(args: &[String]) -> i32 {
+
+    if args.is_empty() {
+        eprintln!("usage: mglisp check <file>...");
+        return 2;
+    }
+
+    let mut status = 0;
+    for path in args {
+        let src = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                status = 2;
+                continue;
+            }
+        };
+        let (_, diags) = recover::parse_recovering(&src);
+        for d in &diags {
+            println!("{}:{}:{}: {}", path, d.span.line, d.span.col, d.message);
+        }
+        if !diags.is_empty() {
+            status = status.max(1);
+        }
+    }
+
+    status
+
+}
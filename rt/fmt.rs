@@ -0,0 +1,82 @@
+//! `mglisp fmt`, which rewrites scripts in the standard style.
+
+use std::fs;
+
+use parser;
+use parser::cst;
+
+/// Lines longer than this get broken up where they can be.
+const WIDTH: usize = 80;
+
+/// Formats every file named in `args`, or with `--check` only lists the ones that would change.
+/// Gives back the exit status.
+pub fn run(args: &[String]) -> i32 {
+
+    let check = args.iter().any(|a| a == "--check");
+    let paths: Vec<&String> = args.iter().filter(|a| *a != "--check").collect();
+    if paths.is_empty() {
+        eprintln!("usage: mglisp fmt [--check] <file>...");
+        return 2;
+    }
+
+    let mut status = 0;
+    for path in paths {
+
+        let src = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                status = 2;
+                continue;
+            }
+        };
+
+        let formatted = match format_source(&src) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                status = status.max(1);
+                continue;
+            }
+        };
+
+        if formatted == src {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            status = status.max(1);
+        } else if let Err(e) = fs::write(path, formatted) {
+            eprintln!("{}: {}", path, e);
+            status = 2;
+        }
+
+    }
+
+    status
+
+}
+
+/// Formats the whole file, making sure the result still reads as the same code.
+fn format_source(src: &str) -> Result<String, String> {
+
+    let nodes = match cst::read(src) {
+        Ok(n) => n,
+        Err(cst::ReadError::Lex(e)) => return Err(format!("lex error: {}", e)),
+        Err(cst::ReadError::Parse(e)) => return Err(format!("parse error: {}", e))
+    };
+    let formatted = cst::format(&nodes, WIDTH);
+
+    // This should never happen, but if it does it's better to leave the file alone.
+    if read_forms(src) != read_forms(&formatted) {
+        return Err("formatting would change what the code means, so it's been left alone".into());
+    }
+
+    Ok(formatted)
+
+}
+
+fn read_forms(src: &str) -> Option<Vec<parser::sexp::Sexp>> {
+    let toks = parser::lex(src).ok()?;
+    parser::parse_all(&mut toks.into_iter().peekable()).ok()
+}
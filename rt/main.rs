@@ -1,3 +1,75 @@
+extern crate mglisp_parser as parser;
+extern crate mglisp_vm as vm;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use vm::LispProgram;
+use vm::eval::LispValue;
+
+mod check;
+mod fmt;
+
 fn main() {
-    println!("Hello, world!");
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "fmt" {
+        process::exit(fmt::run(&args[2..]));
+    }
+    if args.len() > 1 && args[1] == "check" {
+        process::exit(check::run(&args[2..]));
+    }
+    if args.len() != 2 {
+        eprintln!("usage: {} <file>", args[0]);
+        eprintln!("       {} fmt [--check] <file>...", args[0]);
+        eprintln!("       {} check <file>...", args[0]);
+        process::exit(2);
+    }
+
+    let path = &args[1];
+    let src = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(2);
+        }
+    };
+
+    process::exit(run(path, &src));
+
+}
+
+/// Runs every form in the source, printing the result of the last one.
+fn run(path: &str, src: &str) -> i32 {
+
+    // Every syntax error gets reported, not just the first, and then nothing runs.
+    let (forms, diags) = parser::recover::parse_recovering(src);
+    if !diags.is_empty() {
+        for d in &diags {
+            eprintln!("{}:{}:{}: {}", path, d.span.line, d.span.col, d.message);
+        }
+        return 1;
+    }
+
+    let mut prog = LispProgram::standard();
+    let mut last = None;
+    for f in forms.iter() {
+        match prog.exec(f) {
+            Ok(v) => last = Some(v),
+            Err(e) => {
+                eprintln!("{}", e.traceback_in(path, src));
+                return 1;
+            }
+        }
+    }
+
+    if let Some(v) = last {
+        if *v != LispValue::Null {
+            println!("{:#}", v);
+        }
+    }
+
+    0
+
 }
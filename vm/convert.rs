@@ -0,0 +1,155 @@
+use std::cell::RefCell;
+use std::rc::*;
+
+use eval::{HashKey, LispValue, EvalError};
+use parser::sexp::Sexp;
+
+/// Converts an S-expression into the data it literally represents, without evaluating anything.
+///
+/// This is what `quote` does, lists become chains of cons cells ending in null.
+pub fn sexp_to_value(s: &Sexp) -> Rc<LispValue> {
+    Rc::new(match s {
+        Sexp::Null => LispValue::Null,
+        Sexp::Integer(i) => LispValue::Integer(*i),
+        Sexp::ByteArray(a) => LispValue::ByteArray(RefCell::new(a.clone())),
+        Sexp::Char(c) => LispValue::Char(*c),
+        Sexp::Str(s) => LispValue::Str(s.clone()),
+        Sexp::Boolean(b) => LispValue::Boolean(*b),
+        Sexp::Symbol(s) => LispValue::Symbol(s.clone()),
+        Sexp::Keyword(s) => LispValue::Keyword(s.clone()),
+        Sexp::List(l, _) => {
+            // Build it up backwards since that's the order cons cells want.
+            return l.iter().rev().fold(Rc::new(LispValue::Null), |c, sx| Rc::new(LispValue::Cons(sexp_to_value(sx), c)));
+        },
+        Sexp::DottedList(l, t) => {
+            return l.iter().rev().fold(sexp_to_value(t), |c, sx| Rc::new(LispValue::Cons(sexp_to_value(sx), c)));
+        },
+        Sexp::Vector(l) => LispValue::Vector(RefCell::new(l.iter().map(sexp_to_value).collect())),
+
+        // The parser only lets atoms be keys, so they always work.
+        Sexp::Map(l) => LispValue::HashMap(RefCell::new(l.iter()
+            .filter_map(|(k, v)| HashKey::from_value(&sexp_to_value(k)).map(|k| (k, sexp_to_value(v))))
+            .collect()))
+    })
+}
+
+/// Converts a value back into an S-expression, so that it can be evaluated as code.
+///
+/// Fails on things that don't have a syntax, like functions.
+pub fn value_to_sexp(v: &LispValue) -> Result<Sexp, EvalError> {
+    Ok(match v {
+        LispValue::Null => Sexp::Null,
+        LispValue::Integer(i) => Sexp::Integer(*i),
+        LispValue::ByteArray(a) => Sexp::ByteArray(a.borrow().clone()),
+        LispValue::Char(c) => Sexp::Char(*c),
+        LispValue::Str(s) => Sexp::Str(s.clone()),
+        LispValue::Boolean(b) => Sexp::Boolean(*b),
+        LispValue::Symbol(s) => Sexp::Symbol(s.clone()),
+        LispValue::Keyword(s) => Sexp::Keyword(s.clone()),
+        LispValue::Cons(_, _) => {
+            let mut items = Vec::new();
+            let mut cur = v;
+            loop {
+                match cur {
+                    LispValue::Cons(f, r) => {
+                        items.push(value_to_sexp(f.as_ref())?);
+                        cur = r.as_ref();
+                    },
+                    LispValue::Null => return Ok(Sexp::list(items)),
+                    t => return Ok(Sexp::DottedList(items, Box::new(value_to_sexp(t)?)))
+                }
+            }
+        },
+        LispValue::Vector(v) => Sexp::Vector(v.borrow().iter().map(|e| value_to_sexp(e)).collect::<Result<_, _>>()?),
+        LispValue::HashMap(m) => {
+            let mut pairs = Vec::new();
+            for (k, v) in m.borrow().iter() {
+                pairs.push((value_to_sexp(&k.to_value())?, value_to_sexp(v)?));
+            }
+            Sexp::Map(pairs)
+        },
+        LispValue::PersistentVector(_) => return Err(EvalError::msg("can't convert pvector to S-expression")),
+        LispValue::PersistentMap(_) => return Err(EvalError::msg("can't convert pmap to S-expression")),
+        LispValue::Record(_) => return Err(EvalError::msg("can't convert record to S-expression")),
+        LispValue::Func(_) => return Err(EvalError::msg("can't convert function to S-expression")),
+        LispValue::Condition(_) => return Err(EvalError::msg("can't convert condition to S-expression")),
+        LispValue::Coroutine(_) => return Err(EvalError::msg("can't convert coroutine to S-expression"))
+    })
+}
+
+/// The items of a proper list, or `None` if it isn't one.
+pub fn list_to_vec(v: &Rc<LispValue>) -> Option<Vec<Rc<LispValue>>> {
+    let mut items = Vec::new();
+    let mut cur = v.clone();
+    loop {
+        let next = match cur.as_ref() {
+            LispValue::Cons(f, r) => {
+                items.push(f.clone());
+                r.clone()
+            },
+            LispValue::Null => return Some(items),
+            _ => return None
+        };
+        cur = next;
+    }
+}
+
+/// Makes a proper list out of the items.
+pub fn vec_to_list(items: Vec<Rc<LispValue>>) -> Rc<LispValue> {
+    items.into_iter().rev().fold(Rc::new(LispValue::Null), |c, v| Rc::new(LispValue::Cons(v, c)))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use parser::sexp::Sexp;
+    use eval::LispValue::{self, *};
+    use super::{sexp_to_value, value_to_sexp};
+
+    fn cons(a: LispValue, b: LispValue) -> LispValue {
+        Cons(Rc::new(a), Rc::new(b))
+    }
+
+    #[test]
+    fn test_list_keeps_every_element() {
+        let s = Sexp::list(vec![Sexp::Integer(1), Sexp::Integer(2), Sexp::Integer(3)]);
+        assert_eq!(
+            sexp_to_value(&s),
+            Rc::new(cons(Integer(1), cons(Integer(2), cons(Integer(3), Null)))));
+    }
+
+    #[test]
+    fn test_nested_and_empty_lists() {
+        let s = Sexp::list(vec![Sexp::symb_str("a"), Sexp::list(vec![]), Sexp::list(vec![Sexp::str_str("b")])]);
+        assert_eq!(
+            sexp_to_value(&s),
+            Rc::new(cons(Symbol("a".into()), cons(Null, cons(cons(Str("b".into()), Null), Null)))));
+    }
+
+    #[test]
+    fn test_atoms() {
+        assert_eq!(sexp_to_value(&Sexp::Integer(-4)), Rc::new(Integer(-4)));
+        assert_eq!(sexp_to_value(&Sexp::Boolean(true)), Rc::new(Boolean(true)));
+        assert_eq!(sexp_to_value(&Sexp::symb_str("x")), Rc::new(Symbol("x".into())));
+        assert_eq!(sexp_to_value(&Sexp::Null), Rc::new(Null));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let s = Sexp::list(vec![
+            Sexp::symb_str("+"),
+            Sexp::Integer(1),
+            Sexp::list(vec![Sexp::symb_str("f"), Sexp::str_str("x"), Sexp::Boolean(false)])]);
+        assert_eq!(value_to_sexp(sexp_to_value(&s).as_ref()).unwrap(), s);
+    }
+
+    #[test]
+    fn test_improper_list() {
+        let s = Sexp::DottedList(vec![Sexp::Integer(1), Sexp::Integer(2)], Box::new(Sexp::Integer(3)));
+        let v = cons(Integer(1), cons(Integer(2), Integer(3)));
+        assert_eq!(sexp_to_value(&s), Rc::new(v.clone()));
+        assert_eq!(value_to_sexp(&v).unwrap(), s);
+    }
+
+}
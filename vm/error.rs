@@ -0,0 +1,275 @@
+use std::error;
+use std::fmt;
+use std::rc::*;
+
+use eval::LispValue;
+use machine::Continuation;
+use parser::span::Span;
+
+/// How many arguments a function wanted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+    Between(usize, usize)
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Arity::Exactly(n) => write!(f, "{}", n),
+            &Arity::AtLeast(n) => write!(f, "at least {}", n),
+            &Arity::Between(a, b) => write!(f, "{} to {}", a, b)
+        }
+    }
+}
+
+/// What actually went wrong, so embedders can tell errors apart without parsing messages.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+
+    /// A symbol was evaluated that isn't bound to anything.
+    UnboundName(String),
+
+    /// A function was called with the wrong number of arguments.
+    ArityMismatch { expected: Arity, got: usize },
+
+    /// An argument had the wrong type.  The index starts at 1 and type names are what `typeof`
+    /// reports.
+    TypeError { expected: String, got: String, arg_index: usize },
+
+    /// Something that isn't a function was called, with the type of the thing.
+    NotCallable(String),
+
+    /// A special form or S-expression is malformed, like a `lambda` without a body.
+    InvalidForm(String),
+
+    /// An argument had the right type but a bad value, like an unknown keyword argument.
+    InvalidArgument(String),
+
+    /// Integer division (or remainder) by zero.
+    DivideByZero,
+
+    /// Integer arithmetic that doesn't fit in 64 bits.
+    Overflow,
+
+    /// An index past the end of something (or negative), with the length it had.
+    OutOfRange { index: i64, len: usize },
+
+    /// Raised on purpose by a script, with whatever value it raised.
+    UserRaised(Rc<LispValue>),
+
+    /// Anything else.
+    Other(String)
+
+}
+
+impl ErrorKind {
+
+    /// The name scripts see for this kind of error, from `condition-kind`.
+    pub fn symbol(&self) -> &'static str {
+        use self::ErrorKind::*;
+        match self {
+            &UnboundName(_) => "unbound-name",
+            &ArityMismatch { .. } => "arity-mismatch",
+            &TypeError { .. } => "type-error",
+            &NotCallable(_) => "not-callable",
+            &InvalidForm(_) => "invalid-form",
+            &InvalidArgument(_) => "invalid-argument",
+            &DivideByZero => "divide-by-zero",
+            &Overflow => "overflow",
+            &OutOfRange { .. } => "out-of-range",
+            &UserRaised(_) => "user-raised",
+            &Other(_) => "error"
+        }
+    }
+
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ErrorKind::*;
+        match self {
+            UnboundName(n) => write!(f, "unbound name {}", n),
+            ArityMismatch { expected, got } => write!(f, "expected {} arguments, got {}", expected, got),
+            TypeError { expected, got, arg_index } => write!(f, "argument {} should be {}, got {}", arg_index, expected, got),
+            NotCallable(t) => write!(f, "tried to call a non-function ({})", t),
+            InvalidForm(m) => write!(f, "invalid form, {}", m),
+            InvalidArgument(m) => write!(f, "{}", m),
+            DivideByZero => write!(f, "division by zero"),
+            Overflow => write!(f, "integer overflow"),
+            OutOfRange { index, len } => write!(f, "index {} is out of range for length {}", index, len),
+            UserRaised(v) => write!(f, "raised {:#}", v),
+            Other(m) => write!(f, "{}", m)
+        }
+    }
+}
+
+/// A function call that an error passed through on its way out.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Frame {
+
+    /// The function's name, or `<lambda>` if it never got one.
+    pub function: String,
+
+    pub intrinsic: bool,
+
+    /// Where the call was in the source, if we know.
+    pub call_site: Option<Span>,
+
+    /// The arguments it was called with.  Special forms get theirs unevaluated, so for those these
+    /// are the argument expressions as data.
+    pub args: Vec<Rc<LispValue>>
+
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref s) = self.call_site {
+            write!(f, "line {}, col {}, ", s.line, s.col)?;
+        }
+        write!(f, "in {}{}", if self.intrinsic { "intrinsic " } else { "" }, self.function)?;
+        write!(f, " (")?;
+        for (i, a) in self.args.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:#}", a)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// An error from evaluating something, along with the context it happened in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EvalError {
+
+    kind: ErrorKind,
+
+    /// Extra notes about what was going on, innermost first.
+    context: Vec<String>,
+
+    /// The calls the error unwound through, innermost first.
+    trace: Vec<Frame>,
+
+    /// Set when this isn't really an error, just a continuation jumping out through some Rust code
+    /// to get back to the machine it came from.
+    escape: Option<(Rc<Continuation>, Rc<LispValue>)>
+
+}
+
+impl EvalError {
+
+    pub fn new(kind: ErrorKind) -> EvalError {
+        EvalError {
+            kind: kind,
+            context: Vec::new(),
+            trace: Vec::new(),
+            escape: None
+        }
+    }
+
+    pub(crate) fn escape(k: Rc<Continuation>, v: Rc<LispValue>) -> EvalError {
+        EvalError {
+            escape: Some((k, v)),
+            .. EvalError::msg("continuation called outside of the code that captured it")
+        }
+    }
+
+    /// The continuation this is jumping to and the value it's passing, if it's an escape.
+    pub(crate) fn escaping(&self) -> Option<&(Rc<Continuation>, Rc<LispValue>)> {
+        self.escape.as_ref()
+    }
+
+    /// Shorthand for an `ErrorKind::Other` error.
+    pub fn msg<S: Into<String>>(msg: S) -> EvalError {
+        EvalError::new(ErrorKind::Other(msg.into()))
+    }
+
+    /// Adds some context about where the error is passing through.
+    pub fn with_context<S: Into<String>>(mut self, ctx: S) -> EvalError {
+        self.context.push(ctx.into());
+        self
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// The context, innermost first.
+    pub fn context(&self) -> &[String] {
+        self.context.as_slice()
+    }
+
+    /// Records a call that the error is unwinding out of.
+    pub fn with_frame(mut self, frame: Frame) -> EvalError {
+        self.trace.push(frame);
+        self
+    }
+
+    /// The stack trace, innermost first.
+    pub fn trace(&self) -> &[Frame] {
+        self.trace.as_slice()
+    }
+
+    /// Formats the stack trace and the error, with the most recent call last.
+    pub fn traceback(&self) -> String {
+        self.format_traceback(None)
+    }
+
+    /// Like `traceback`, but like Python it names the file and shows the source line for every
+    /// call we know the site of.
+    pub fn traceback_in(&self, path: &str, src: &str) -> String {
+        self.format_traceback(Some((path, src)))
+    }
+
+    fn format_traceback(&self, source: Option<(&str, &str)>) -> String {
+        let mut s = String::from("Traceback (most recent call last):\n");
+        for fr in self.trace.iter().rev() {
+            match (source, fr.call_site) {
+                (Some((path, src)), Some(site)) => {
+                    let line = src.lines().nth(site.line - 1).unwrap_or("");
+                    s.push_str(format!("  File \"{}\", {}\n    {}\n", path, fr, line.trim()).as_str());
+                },
+                _ => s.push_str(format!("  {}\n", fr).as_str())
+            }
+        }
+        s.push_str(format!("error: {}", self).as_str());
+        s
+    }
+
+}
+
+impl From<ErrorKind> for EvalError {
+    fn from(k: ErrorKind) -> EvalError {
+        EvalError::new(k)
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.context.iter().rev() {
+            write!(f, "{}: ", c)?;
+        }
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl error::Error for EvalError {}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let e = EvalError::new(ErrorKind::TypeError { expected: "integer".into(), got: "bool".into(), arg_index: 3 })
+            .with_context("in intrinsic +")
+            .with_context("in intrinsic if");
+        assert_eq!(format!("{}", e), "in intrinsic if: in intrinsic +: argument 3 should be integer, got bool");
+        let e = EvalError::new(ErrorKind::ArityMismatch { expected: Arity::AtLeast(2), got: 1 });
+        assert_eq!(format!("{}", e), "expected at least 2 arguments, got 1");
+    }
+
+}
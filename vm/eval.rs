@@ -1,28 +1,270 @@
 
 #![allow(dead_code)]
 
+use std::cell::RefCell;
+use std::fmt;
 use std::rc::*;
 use std::collections::*;
 
 use intrinsics;
+use machine::{Continuation, Coroutine, Machine};
+use persist::{PMap, PVec};
 use parser::sexp::Sexp;
 
+pub use error::{Arity, ErrorKind, EvalError, Frame};
+
 type BindingMap = HashMap<String, Rc<LispValue>>;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// Local bindings get copied along with the environment, but the top-level ones are shared, so
+/// anything defined there is seen from every environment that came from the same program.
+#[derive(Clone, Default)]
 pub struct Env {
-    bindings: BindingMap,
+    locals: Option<BindingMap>,
+    globals: Rc<RefCell<BindingMap>>
 }
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum LispFunction {
-    Lambda(Rc<Sexp>, Env, Vec<String>), // the `Env` here is the local context of the function
-    VariadicLambda(Rc<Sexp>, Env),
-    Intrinsic(intrinsics::MgIntrinsic)
+    Lambda(Closure),
+    Intrinsic(intrinsics::MgIntrinsic),
+
+    /// From `call/cc`, calling it jumps back to where it was captured.
+    Continuation(Rc<Continuation>)
 }
 
-/// Some data value.
+impl LispFunction {
+
+    /// What to call the function in stack traces.
+    pub fn name(&self) -> String {
+        match self {
+            LispFunction::Lambda(Closure { name: Some(n), .. }) => n.clone(),
+            LispFunction::Lambda(_) => String::from("<lambda>"),
+            LispFunction::Intrinsic(idat) => idat.name.clone(),
+            LispFunction::Continuation(_) => String::from("<continuation>")
+        }
+    }
+
+}
+
+/// A function defined within the engine.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Closure {
+
+    /// The name it was first `define`d with, if it was.
+    pub name: Option<String>,
+
+    pub params: Params,
+
+    pub body: Rc<Sexp>,
+
+    /// The local context of the function, from where it was created.
+    pub env: Env
+
+}
+
+/// The parameter list of a lambda, like `(a #:optional (b 2) #:key c . rest)`.
 #[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Params {
+
+    /// Names that each take exactly one argument.
+    pub required: Vec<String>,
+
+    /// Positional names that can be left off, with the expression for their default value.
+    pub optional: Vec<(String, Option<Rc<Sexp>>)>,
+
+    /// Names that are passed like `#:name value` after the positional arguments.
+    pub keys: Vec<(String, Option<Rc<Sexp>>)>,
+
+    /// Name that gets a list of whatever arguments are left over, if any.
+    pub rest: Option<String>
+
+}
+
+impl Params {
+
+    /// Params that are only required names, like `(a b c)`.
+    pub fn required(names: Vec<String>) -> Params {
+        Params {
+            required: names,
+            optional: Vec::new(),
+            keys: Vec::new(),
+            rest: None
+        }
+    }
+
+    /// Binds the arguments of a call to their names on top of the closure's environment, or
+    /// complains if they don't fit.
+    ///
+    /// Defaults are evaluated at call time, and can see the parameters before them.
+    pub fn bind(&self, args: Vec<Rc<LispValue>>, clos: &Env) -> Result<Env, EvalError> {
+
+        let positional = self.required.len() + self.optional.len();
+        if args.len() < self.required.len() || (self.rest.is_none() && self.keys.is_empty() && args.len() > positional) {
+            return Err(EvalError::new(ErrorKind::ArityMismatch {
+                expected: if self.rest.is_some() || !self.keys.is_empty() {
+                    Arity::AtLeast(self.required.len())
+                } else if self.optional.is_empty() {
+                    Arity::Exactly(self.required.len())
+                } else {
+                    Arity::Between(self.required.len(), positional)
+                },
+                got: args.len()
+            }));
+        }
+
+        let mut env = clos.child();
+        let mut iter = args.into_iter().peekable();
+        for n in self.required.iter() {
+            env.add_binding(n.clone(), iter.next().unwrap());
+        }
+
+        // Optionals get filled in positionally until we run out or hit the first keyword.
+        for (n, default) in self.optional.iter() {
+            let v = match iter.peek().cloned() {
+                Some(ref v) if self.keys.is_empty() || !v.is_keyword() => {
+                    iter.next();
+                    v.clone()
+                },
+                _ => default_value(default, &mut env)?
+            };
+            env.add_binding(n.clone(), v);
+        }
+
+        let leftover: Vec<_> = iter.collect();
+
+        if !self.keys.is_empty() {
+
+            // Scan the leftovers for keyword pairs.  The last one wins if there's duplicates.
+            let mut given = HashMap::new();
+            let mut i = 0;
+            while i < leftover.len() {
+                match leftover[i].as_ref() {
+                    LispValue::Keyword(k) => {
+                        if !self.keys.iter().any(|(n, _)| n == k) && self.rest.is_none() {
+                            return Err(ErrorKind::InvalidArgument(format!("unknown keyword argument #:{}", k)).into());
+                        }
+                        match leftover.get(i + 1) {
+                            Some(v) => given.insert(k.clone(), v.clone()),
+                            None => return Err(ErrorKind::InvalidArgument(format!("keyword argument #:{} is missing a value", k)).into())
+                        };
+                        i += 2;
+                    },
+                    _ if self.rest.is_some() => i += 1,
+                    _ => return Err(ErrorKind::InvalidArgument("extra arguments after keyword arguments".into()).into())
+                }
+            }
+
+            for (n, default) in self.keys.iter() {
+                let v = match given.remove(n) {
+                    Some(v) => v,
+                    None => default_value(default, &mut env)?
+                };
+                env.add_binding(n.clone(), v);
+            }
+
+        }
+
+        if let Some(ref r) = self.rest {
+            let list = leftover.into_iter().rev().fold(Rc::new(LispValue::Null), |c, v| Rc::new(LispValue::Cons(v, c)));
+            env.add_binding(r.clone(), list);
+        }
+
+        Ok(env)
+
+    }
+
+}
+
+#[inline]
+fn default_value(default: &Option<Rc<Sexp>>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+    match default {
+        Some(sx) => eval(sx.as_ref(), &mut env.clone()),
+        &None => Ok(Rc::new(LispValue::Null))
+    }
+}
+
+/// The kinds of values that can be keys in a hash map, which hash by their contents.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum HashKey {
+    Integer(i64),
+    Char(char),
+    Str(String),
+    Boolean(bool),
+    Symbol(String),
+    Keyword(String)
+}
+
+impl HashKey {
+
+    /// Makes a key out of a value, if it's a kind that can be one.
+    pub fn from_value(v: &LispValue) -> Option<HashKey> {
+        Some(match v {
+            LispValue::Integer(i) => HashKey::Integer(*i),
+            LispValue::Char(c) => HashKey::Char(*c),
+            LispValue::Str(s) => HashKey::Str(s.clone()),
+            LispValue::Boolean(b) => HashKey::Boolean(*b),
+            LispValue::Symbol(s) => HashKey::Symbol(s.clone()),
+            LispValue::Keyword(s) => HashKey::Keyword(s.clone()),
+            _ => return None
+        })
+    }
+
+    pub fn to_value(&self) -> Rc<LispValue> {
+        Rc::new(match self {
+            HashKey::Integer(i) => LispValue::Integer(*i),
+            HashKey::Char(c) => LispValue::Char(*c),
+            HashKey::Str(s) => LispValue::Str(s.clone()),
+            HashKey::Boolean(b) => LispValue::Boolean(*b),
+            HashKey::Symbol(s) => LispValue::Symbol(s.clone()),
+            HashKey::Keyword(s) => LispValue::Keyword(s.clone())
+        })
+    }
+
+}
+
+/// What `define-record-type` makes, which every record of that type points back to.
+#[derive(Debug, Eq, PartialEq)]
+pub struct RecordType {
+    pub name: String,
+    pub fields: Vec<String>
+}
+
+/// An instance of some record type, with its fields in the order the type lists them.
+#[derive(Clone)]
+pub struct Record {
+    pub rtype: Rc<RecordType>,
+    pub fields: RefCell<Vec<Rc<LispValue>>>
+}
+
+impl Record {
+
+    /// Whether this was made by the same `define-record-type`, not just one with the same name.
+    pub fn is_a(&self, rtype: &Rc<RecordType>) -> bool {
+        Rc::ptr_eq(&self.rtype, rtype)
+    }
+
+}
+
+impl PartialEq for Record {
+    fn eq(&self, other: &Self) -> bool {
+        self.is_a(&other.rtype) && all_equal(&self.fields.borrow(), &other.fields.borrow(), &mut HashSet::new())
+    }
+}
+
+impl Eq for Record {}
+
+impl ::std::fmt::Debug for Record {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let mut d = f.debug_struct(&self.rtype.name);
+        for (n, v) in self.rtype.fields.iter().zip(self.fields.borrow().iter()) {
+            d.field(n, v);
+        }
+        d.finish()
+    }
+}
+
+/// Some data value.
+#[derive(Clone)]
 pub enum LispValue {
 
     /// Just nothing.
@@ -31,8 +273,11 @@ pub enum LispValue {
     /// 64-bit integer.
     Integer(i64),
 
-    /// Byte array.
-    ByteArray(Box<[i8]>),
+    /// A fixed-size array of bytes that can be changed in place, like `#u8(1 2 255)`.
+    ByteArray(RefCell<Box<[u8]>>),
+
+    /// A single Unicode character, like `#\\a`.
+    Char(char),
 
     /// UTF-8 string.
     Str(String),
@@ -43,180 +288,339 @@ pub enum LispValue {
     /// A symbol that's not a string.
     Symbol(String),
 
+    /// A keyword like `#:width`, used for naming arguments.
+    Keyword(String),
+
     /// A pairing of two values, probably an LispValue and another cons.
     Cons(Rc<LispValue>, Rc<LispValue>),
 
+    /// A mutable array that can grow, like `#(1 2 3)`.
+    Vector(RefCell<Vec<Rc<LispValue>>>),
+
+    /// A mutable hash map, like `{a 1 b 2}`.
+    HashMap(RefCell<HashMap<HashKey, Rc<LispValue>>>),
+
+    /// Immutable vectors and maps, where "changing" one makes a new one that shares most of it.
+    PersistentVector(PVec<Rc<LispValue>>),
+    PersistentMap(PMap<HashKey, Rc<LispValue>>),
+
+    /// Something made by a constructor from `define-record-type`.
+    Record(Record),
+
     /// Callable functions.  In a `Box` to take up less space, as they're somewhat larger than we want them to be.
-    Func(Box<LispFunction>)
+    Func(Box<LispFunction>),
+
+    /// An error that was caught by `guard`, so scripts can look at what went wrong.
+    Condition(Rc<EvalError>),
+
+    /// A function that can be suspended with `yield` and picked back up with `resume`.
+    Coroutine(Rc<Coroutine>)
 
 }
 
 impl LispValue {
 
-    /// Returns a new, exact, but seperate copy of the LispValue.
-    pub fn hard_clone(&self) -> Rc<LispValue> {
+    pub fn is_keyword(&self) -> bool {
+        matches!(self, &LispValue::Keyword(_))
+    }
+
+    /// The name of the value's type, as reported by `typeof`.
+    pub fn type_name(&self) -> &'static str {
         use self::LispValue::*;
         match self {
-            &Null => Rc::new(Null),
-            &Integer(i) => Rc::new(Integer(i)),
-            &ByteArray(ref a) => Rc::new(ByteArray(a.clone())),
-            &Str(ref s) => Rc::new(Str(s.clone())),
-            &Boolean(b) => Rc::new(Boolean(b)),
-            &Symbol(ref s) => Rc::new(Symbol(s.clone())),
-            &Cons(ref l, ref r) => Rc::new(Cons(l.hard_clone(), r.hard_clone())),
-            &Func(ref f) => Rc::new(Func(f.clone()))
+            &Null => "null",
+            &Integer(_) => "integer",
+            &ByteArray(_) => "bytearray",
+            &Char(_) => "char",
+            &Str(_) => "str",
+            &Boolean(_) => "bool",
+            &Symbol(_) => "symbol",
+            &Keyword(_) => "keyword",
+            &Cons(_, _) => "cons",
+            &Vector(_) => "vector",
+            &HashMap(_) => "hashmap",
+            &PersistentVector(_) => "pvector",
+            &PersistentMap(_) => "pmap",
+            &Record(_) => "record",
+            &Func(_) => "function",
+            &Condition(_) => "condition",
+            &Coroutine(_) => "coroutine"
         }
     }
 
-}
-
-impl Env {
-    pub fn new() -> Env {
-        Env {
-            bindings: BindingMap::new()
-        }
+    /// Returns a new, exact, but seperate copy of the LispValue.  Anything that shows up more than
+    /// once in it, even inside of itself, gets copied once and shared the same way in the copy.
+    pub fn hard_clone(&self) -> Rc<LispValue> {
+        self.copy_into(&mut Copies::new())
     }
 
-    pub fn add_binding(&mut self, name: String, value: Rc<LispValue>) {
-        self.bindings.insert(name, value);
-    }
+    fn copy_into(&self, copies: &mut Copies) -> Rc<LispValue> {
+        use self::LispValue::*;
 
-    pub fn compose(&self, top: &Env) -> Env {
-        let mut dup = self.bindings.clone();
-        for (k, v) in top.bindings.iter() {
-            dup.insert(k.clone(), v.clone());
-        }
-        Env {
-            bindings: dup
+        let addr = self as *const LispValue;
+        if let Some(c) = copies.get(&addr) {
+            return c.clone();
         }
-    }
 
-    pub fn resolve(&self, name: &String) -> Option<Rc<LispValue>> {
-        self.bindings.get(name).cloned()
-    }
-}
+        /*
+         * Only the mutable containers can be part of a cycle, so they're remembered before what's
+         * in them gets copied, and filled in afterwards.
+         */
+        let copy = match self {
+            Vector(v) => {
+                let copy = Rc::new(Vector(RefCell::new(Vec::new())));
+                copies.insert(addr, copy.clone());
+                let items = v.borrow().iter().map(|e| e.copy_into(copies)).collect();
+                if let Vector(c) = copy.as_ref() {
+                    *c.borrow_mut() = items;
+                }
+                return copy;
+            },
+            HashMap(m) => {
+                let copy = Rc::new(HashMap(RefCell::new(self::HashMap::new())));
+                copies.insert(addr, copy.clone());
+                let entries = m.borrow().iter().map(|(k, v)| (k.clone(), v.copy_into(copies))).collect();
+                if let HashMap(c) = copy.as_ref() {
+                    *c.borrow_mut() = entries;
+                }
+                return copy;
+            },
+            Record(r) => {
+                let copy = Rc::new(Record(self::Record {
+                    rtype: r.rtype.clone(),
+                    fields: RefCell::new(Vec::new())
+                }));
+                copies.insert(addr, copy.clone());
+                let fields = r.fields.borrow().iter().map(|e| e.copy_into(copies)).collect();
+                if let Record(c) = copy.as_ref() {
+                    *c.fields.borrow_mut() = fields;
+                }
+                return copy;
+            },
 
-impl From<BindingMap> for Env {
-    fn from(v: BindingMap) -> Env {
-        Env {
-            bindings: v
-        }
+            Null => Rc::new(Null),
+            Integer(i) => Rc::new(Integer(*i)),
+            ByteArray(a) => Rc::new(ByteArray(a.clone())),
+            Char(c) => Rc::new(Char(*c)),
+            Str(s) => Rc::new(Str(s.clone())),
+            Boolean(b) => Rc::new(Boolean(*b)),
+            Symbol(s) => Rc::new(Symbol(s.clone())),
+            Keyword(s) => Rc::new(Keyword(s.clone())),
+            Cons(l, r) => Rc::new(Cons(l.copy_into(copies), r.copy_into(copies))),
+
+            // These can't change, but what's in them might, so they still have to be rebuilt.
+            PersistentVector(v) => Rc::new(PersistentVector(v.iter().map(|e| e.copy_into(copies)).collect())),
+            PersistentMap(m) => Rc::new(PersistentMap(m.iter().map(|(k, v)| (k.clone(), v.copy_into(copies))).collect())),
+            Func(f) => Rc::new(Func(f.clone())),
+            Condition(c) => Rc::new(Condition(c.clone())),
+
+            // There's only one of the actual running coroutine, so copies still share it.
+            Coroutine(c) => Rc::new(Coroutine(c.clone()))
+        };
+        copies.insert(addr, copy.clone());
+        copy
     }
-}
 
-#[derive(Clone, Debug)]
-pub enum EvalError {
-    Msg(String),
-    Chain(Vec<EvalError>)
-}
+    /*
+     * Two values that contain themselves are equal if comparing them only ever comes back around
+     * to a pair that's already being compared, with nothing different along the way.
+     */
+    fn equal(&self, other: &LispValue, seen: &mut Pairs) -> bool {
+        use self::LispValue::*;
 
-#[allow(unreachable_patterns)]
-pub fn eval(sexp: &Sexp, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+        let container = matches!(self, Vector(_) | HashMap(_) | Record(_));
+        if container && !seen.insert((self as *const LispValue, other as *const LispValue)) {
+            return true;
+        }
 
-    use parser::sexp::Sexp::*;
-    use self::LispFunction::*;
-    use self::EvalError::*;
-    let val: Rc<LispValue> = match sexp {
-
-        // Normal data conversions.
-        &Null => Rc::new(LispValue::Null),
-        &Integer(i) => Rc::new(LispValue::Integer(i)),
-        &ByteArray(ref a) => Rc::new(LispValue::ByteArray(a.clone())),
-        &Str(ref s) => Rc::new(LispValue::Str(s.clone())),
-        &Boolean(b) => Rc::new(LispValue::Boolean(b)),
-
-        // Symbols are how variable binding works, outside of `quote` forms.
-        &Symbol(ref s) => match env.resolve(&s) {
-            Some(v) => v,
-            None => return Err(Msg(format!("unbound name {}", s)))
-        },
+        match (self, other) {
+            (Cons(a, b), Cons(c, d)) => a.equal(c, seen) && b.equal(d, seen),
+            (Vector(a), Vector(b)) => all_equal(&a.borrow(), &b.borrow(), seen),
+            (HashMap(a), HashMap(b)) => {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len() && a.iter().all(|(k, v)| b.get(k).is_some_and(|w| v.equal(w, seen)))
+            },
+            (PersistentVector(a), PersistentVector(b)) => a.len() == b.len() && a.iter().zip(b.iter()).all(|(v, w)| v.equal(w, seen)),
+            (PersistentMap(a), PersistentMap(b)) => a.len() == b.len() && a.iter().all(|(k, v)| b.get(k).is_some_and(|w| v.equal(w, seen))),
+            (Record(a), Record(b)) => a.is_a(&b.rtype) && all_equal(&a.fields.borrow(), &b.fields.borrow(), seen),
+
+            (Null, Null) => true,
+            (Integer(a), Integer(b)) => a == b,
+            (ByteArray(a), ByteArray(b)) => a == b,
+            (Char(a), Char(b)) => a == b,
+            (Str(a), Str(b)) | (Symbol(a), Symbol(b)) | (Keyword(a), Keyword(b)) => a == b,
+            (Boolean(a), Boolean(b)) => a == b,
+            (Func(a), Func(b)) => a == b,
+            (Condition(a), Condition(b)) => a == b,
+            (Coroutine(a), Coroutine(b)) => a == b,
+            _ => false
+        }
+    }
 
-        /*
-         * "Lists" in S-expressions are how function calls happen.  The first argument is the
-         * actual function being applied.  This is where the fun part of evaling happens!
-         */
-        &List(ref v) => match eval(&v[0], &mut env.clone()) {
+}
 
-            Ok(r) => {
+type Copies = HashMap<*const LispValue, Rc<LispValue>>;
 
-                /*
-                 * First we evaluate the first element so that we can figure out what we should do, and
-                 * if it matches, we figure out which kind of function it is.
-                 */
-                if let &LispValue::Func(ref fb) = r.as_ref() {
+type Pairs = HashSet<(*const LispValue, *const LispValue)>;
 
-                    match fb.as_ref() {
+fn all_equal(a: &[Rc<LispValue>], b: &[Rc<LispValue>], seen: &mut Pairs) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(v, w)| v.equal(w, seen))
+}
 
-                        // Lambdas are for normal functions defined within the engine.
-                        &Lambda(ref tmplt, ref clos, ref names) => {
+impl PartialEq for LispValue {
+    fn eq(&self, other: &LispValue) -> bool {
+        self.equal(other, &mut HashSet::new())
+    }
+}
 
-                            // First we have to
-                            // TODO Make this more f u n c t i o n a l.
-                            let mut args = Vec::with_capacity(v.len() - 1);
-                            for sx in v.iter().skip(1) {
-                                args.push(eval(sx, &mut env.clone())?);
-                            }
+impl Eq for LispValue {}
 
-                            // If they aren't the same length then report that.
-                            if args.len() != names.len() {
-                                return Err(Msg(format!("function expeced {} arguments, got {}", names.len(), args.len())));
-                            }
+thread_local! {
+    /// The containers that `Debug` is in the middle of, so one inside itself only prints once.
+    static DEBUGGING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
 
-                            // Now we have to compute the partial environment based on the arguments.
-                            let nenv: BindingMap = args
-                                .iter()
-                                .zip(names)
-                                .map(|(a, n)| (n.clone(), a.clone())) // TODO Reduce all this cloning.
-                                .collect();
+impl fmt::Debug for LispValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::LispValue::*;
 
-                            // This is where all the hardcore magic happens.
-                            eval(tmplt.as_ref(), &mut clos.compose(&nenv.into()))?
+        let addr = self as *const LispValue as usize;
+        let container = matches!(self, Vector(_) | HashMap(_) | Record(_));
+        if container {
+            if DEBUGGING.with(|d| d.borrow().contains(&addr)) {
+                return f.write_str("<cycle>");
+            }
+            DEBUGGING.with(|d| d.borrow_mut().push(addr));
+        }
 
-                        },
+        let res = match self {
+            Null => f.write_str("Null"),
+            Integer(i) => f.debug_tuple("Integer").field(i).finish(),
+            ByteArray(a) => f.debug_tuple("ByteArray").field(a).finish(),
+            Char(c) => f.debug_tuple("Char").field(c).finish(),
+            Str(s) => f.debug_tuple("Str").field(s).finish(),
+            Boolean(b) => f.debug_tuple("Boolean").field(b).finish(),
+            Symbol(s) => f.debug_tuple("Symbol").field(s).finish(),
+            Keyword(s) => f.debug_tuple("Keyword").field(s).finish(),
+            Cons(a, b) => f.debug_tuple("Cons").field(a).field(b).finish(),
+            Vector(v) => f.debug_tuple("Vector").field(v).finish(),
+            HashMap(m) => f.debug_tuple("HashMap").field(m).finish(),
+            PersistentVector(v) => f.debug_tuple("PersistentVector").field(v).finish(),
+            PersistentMap(m) => f.debug_tuple("PersistentMap").field(m).finish(),
+            Record(r) => f.debug_tuple("Record").field(r).finish(),
+            Func(func) => f.debug_tuple("Func").field(func).finish(),
+            Condition(c) => f.debug_tuple("Condition").field(c).finish(),
+            Coroutine(c) => f.debug_tuple("Coroutine").field(c).finish()
+        };
+        if container {
+            DEBUGGING.with(|d| d.borrow_mut().pop());
+        }
+        res
+    }
 
-                        &VariadicLambda(ref tmplt, ref clos) => {
+}
 
-                            // Construct a list out of the arguments, ignoring length.
-                            let mut val = Rc::new(LispValue::Null);
-                            for v in v.iter().skip(1) {
-                                val = Rc::new(LispValue::Cons(eval(v, &mut env.clone())?, val));
-                            }
+impl Env {
+    pub fn new() -> Env {
+        Env {
+            locals: None,
+            globals: Rc::new(RefCell::new(BindingMap::new()))
+        }
+    }
 
-                            // Create the eval override and then complete it.
-                            let mut arg = Env::new();
-                            arg.add_binding("args".into(), val); // Should we change the argument name?
-                            eval(tmplt.as_ref(), &mut clos.compose(&arg))?
+    /// A new scope for local bindings, still sharing the top-level ones.
+    pub fn child(&self) -> Env {
+        Env {
+            locals: Some(self.locals.clone().unwrap_or_default()),
+            globals: self.globals.clone()
+        }
+    }
 
-                        }
+    /// The same local bindings, but with the top level that `other` has.
+    pub fn with_globals_of(&self, other: &Env) -> Env {
+        Env {
+            locals: self.locals.clone(),
+            globals: other.globals.clone()
+        }
+    }
 
-                        // Instrinsics are the things that actually reach out and do magic things.
-                        &Intrinsic(ref idat) => {
+    /// Unbinds everything at the top level, for every environment that shares it.
+    pub(crate) fn clear_globals(&self) {
+        /* Taken out first, so nothing being dropped can find the map still borrowed. */
+        let old = ::std::mem::take(&mut *self.globals.borrow_mut());
+        drop(old);
+    }
 
-                            // Similar thing to the above, just don't do any transformation.
-                            let args = v.iter().map(|sx| sx.clone()).collect();
-                            match idat.func.as_ref()(&args, env) {
-                                Ok(a) => a,
-                                Err(e) => return Err(Chain(vec![Msg(format!("error in intrinsic {}", idat.name)), e]))
-                            }
+    pub fn add_binding(&mut self, name: String, value: Rc<LispValue>) {
+        match self.locals {
+            Some(ref mut l) => { l.insert(name, value); },
+            None => { self.globals.borrow_mut().insert(name, value); }
+        }
+    }
 
-                        }
+    pub fn compose(&self, top: &Env) -> Env {
+        let mut dup = self.globals.borrow().clone();
+        for (k, v) in top.globals.borrow().iter() {
+            dup.insert(k.clone(), v.clone());
+        }
+        let locals = match (&self.locals, &top.locals) {
+            (&None, &None) => None,
+            (l, t) => {
+                let mut l = l.clone().unwrap_or_default();
+                l.extend(t.iter().flat_map(|t| t.iter()).map(|(k, v)| (k.clone(), v.clone())));
+                Some(l)
+            }
+        };
+        Env {
+            locals: locals,
+            globals: Rc::new(RefCell::new(dup))
+        }
+    }
 
-                    }
+    pub fn resolve(&self, name: &String) -> Option<Rc<LispValue>> {
+        match self.locals.as_ref().and_then(|l| l.get(name)) {
+            Some(v) => Some(v.clone()),
+            None => self.globals.borrow().get(name).cloned()
+        }
+    }
+}
 
-                } else {
-                    return Err(Msg("tried to call a non-function".into()))
-                }
+impl PartialEq for Env {
+    fn eq(&self, other: &Env) -> bool {
+        self.locals == other.locals && Rc::ptr_eq(&self.globals, &other.globals)
+    }
+}
 
-            },
+impl Eq for Env {}
 
-            Err(e) => return Err(e)
+/* The top level can hold closures that point back at it, so only the locals get printed. */
+impl fmt::Debug for Env {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Env")
+            .field("locals", &self.locals)
+            .field("globals", &format_args!("<{} bindings>", self.globals.borrow().len()))
+            .finish()
+    }
+}
 
-        },
-        _ => return Err(Msg("unevaluatable S-expression".into()))
-    };
+impl From<BindingMap> for Env {
+    fn from(v: BindingMap) -> Env {
+        Env {
+            locals: None,
+            globals: Rc::new(RefCell::new(v))
+        }
+    }
+}
 
-    Ok(val)
+/// Evaluates an expression in `env`.  Top-level definitions go into the bindings it shares with
+/// every copy of it, and `env` itself never gets swapped out, even by a continuation.
+pub fn eval(sexp: &Sexp, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+    Machine::new(env.clone()).eval(Rc::new(sexp.clone()))
+}
 
+/// Calls a function with arguments that have already been evaluated.
+pub fn apply_function(func: &LispFunction, args: Vec<Rc<LispValue>>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+    Machine::new(env.clone()).apply(Rc::new(LispValue::Func(Box::new(func.clone()))), args)
 }
 
 #[cfg(test)]
@@ -238,8 +642,7 @@ mod tests {
         use parser::Token;
         use parser::sexp::Sexp;
         use intrinsics::{self, MgIntrinsic};
-        let expr: Sexp = parser::parse(&mut vec![
-            Token::OpenParen,
+        let expr: Sexp = parser::parse(&mut [Token::OpenParen,
             Token::Name("+".into()),
             Token::OpenParen,
             Token::Name("+".into()),
@@ -247,13 +650,198 @@ mod tests {
             Token::Number(3),
             Token::CloseParen,
             Token::Number(2),
-            Token::CloseParen
-            ].iter().cloned().peekable()).unwrap();
+            Token::CloseParen].iter().cloned().peekable()).unwrap();
         println!("{:?}", expr);
         let mut env = Env::new();
-        env.add_binding("+".into(), Rc::new(LispValue::Func(Box::new(LispFunction::Intrinsic(MgIntrinsic::new("+".into(), &intrinsics::math::mgi_plus))))));
+        env.add_binding("+".into(), Rc::new(LispValue::Func(Box::new(LispFunction::Intrinsic(MgIntrinsic::procedure("+".into(), &intrinsics::math::mgi_plus))))));
         let r = super::eval(&expr, &mut env);
         assert_eq!(r.unwrap(), Rc::new(LispValue::Integer(7)));
     }
 
+    fn bind_intrinsic(env: &mut Env, name: &str, func: &'static ::intrinsics::IntrinsicImpl) {
+        use intrinsics::MgIntrinsic;
+        env.add_binding(name.into(), Rc::new(LispValue::Func(Box::new(LispFunction::Intrinsic(MgIntrinsic::new(name.into(), func))))));
+    }
+
+    fn bind_procedure(env: &mut Env, name: &str, func: &'static ::intrinsics::ProcedureImpl) {
+        use intrinsics::MgIntrinsic;
+        env.add_binding(name.into(), Rc::new(LispValue::Func(Box::new(LispFunction::Intrinsic(MgIntrinsic::procedure(name.into(), func))))));
+    }
+
+    fn bind_define(env: &mut Env) {
+        use intrinsics::MgIntrinsic;
+        use machine::Native;
+        env.add_binding("define".into(), Rc::new(LispValue::Func(Box::new(LispFunction::Intrinsic(MgIntrinsic::native("define".into(), Native::Define))))));
+    }
+
+    fn list_of(vals: Vec<LispValue>) -> Rc<LispValue> {
+        vals.into_iter().rev().fold(Rc::new(LispValue::Null), |c, v| Rc::new(LispValue::Cons(Rc::new(v), c)))
+    }
+
+    #[test]
+    fn test_eval_rest_params() {
+        use intrinsics;
+        use super::LispValue::*;
+        let mut env = Env::new();
+        bind_intrinsic(&mut env, "lambda", &intrinsics::core::mgi_lambda);
+        let toks = parser::lex("((lambda (a b . rest) rest) 1 2 3 4)").unwrap();
+        let expr = parser::parse(&mut toks.into_iter().peekable()).unwrap();
+        assert_eq!(super::eval(&expr, &mut env).unwrap(), list_of(vec![Integer(3), Integer(4)]));
+        let toks = parser::lex("((lambda args args))").unwrap();
+        let expr = parser::parse(&mut toks.into_iter().peekable()).unwrap();
+        assert_eq!(super::eval(&expr, &mut env).unwrap(), Rc::new(Null));
+        let toks = parser::lex("((lambda (a . rest) a))").unwrap();
+        let expr = parser::parse(&mut toks.into_iter().peekable()).unwrap();
+        assert!(super::eval(&expr, &mut env).is_err());
+    }
+
+    fn eval_str(src: &str, env: &mut Env) -> Result<Rc<LispValue>, super::EvalError> {
+        let toks = parser::lex(src).unwrap();
+        super::eval(&parser::parse(&mut toks.into_iter().peekable()).unwrap(), env)
+    }
+
+    #[test]
+    fn test_eval_error_kinds() {
+        use super::{Arity, ErrorKind};
+        use intrinsics;
+        let mut env = Env::new();
+        bind_intrinsic(&mut env, "lambda", &intrinsics::core::mgi_lambda);
+        assert_eq!(eval_str("nope", &mut env).unwrap_err().kind(), &ErrorKind::UnboundName("nope".into()));
+        assert_eq!(eval_str("(5 1)", &mut env).unwrap_err().kind(), &ErrorKind::NotCallable("integer".into()));
+        assert_eq!(
+            eval_str("((lambda (a b) a) 1)", &mut env).unwrap_err().kind(),
+            &ErrorKind::ArityMismatch { expected: Arity::Exactly(2), got: 1 });
+        assert_eq!(
+            eval_str("(lambda (1) 1)", &mut env).unwrap_err().kind(),
+            &ErrorKind::InvalidForm("malformed lambda parameter names".into()));
+    }
+
+    #[test]
+    fn test_eval_optional_params() {
+        use intrinsics;
+        use super::LispValue::*;
+        let mut env = Env::new();
+        bind_intrinsic(&mut env, "lambda", &intrinsics::core::mgi_lambda);
+        bind_define(&mut env);
+        bind_procedure(&mut env, "+", &intrinsics::math::mgi_plus);
+        eval_str("(define f (lambda (a #:optional (b 10) (c (+ a b))) (+ a (+ b c))))", &mut env).unwrap();
+        assert_eq!(eval_str("(f 1)", &mut env).unwrap(), Rc::new(Integer(22)));
+        assert_eq!(eval_str("(f 1 2)", &mut env).unwrap(), Rc::new(Integer(6)));
+        assert_eq!(eval_str("(f 1 2 3)", &mut env).unwrap(), Rc::new(Integer(6)));
+        assert!(eval_str("(f)", &mut env).is_err());
+        assert!(eval_str("(f 1 2 3 4)", &mut env).is_err());
+    }
+
+    #[test]
+    fn test_eval_keyword_params() {
+        use intrinsics;
+        use super::LispValue::*;
+        let mut env = Env::new();
+        bind_intrinsic(&mut env, "lambda", &intrinsics::core::mgi_lambda);
+        bind_define(&mut env);
+        bind_procedure(&mut env, "-", &intrinsics::math::mgi_subtract);
+        eval_str("(define make-widget (lambda (#:key (width 1) (height 2)) (- width height)))", &mut env).unwrap();
+        assert_eq!(eval_str("(make-widget)", &mut env).unwrap(), Rc::new(Integer(-1)));
+        assert_eq!(eval_str("(make-widget #:width 10)", &mut env).unwrap(), Rc::new(Integer(8)));
+        assert_eq!(eval_str("(make-widget #:height 5 #:width 10)", &mut env).unwrap(), Rc::new(Integer(5)));
+        assert!(eval_str("(make-widget #:depth 3)", &mut env).is_err());
+        assert!(eval_str("(make-widget #:width)", &mut env).is_err());
+        assert!(eval_str("(make-widget 4)", &mut env).is_err());
+        assert!(eval_str("(lambda (#:key a #:optional b) a)", &mut env).is_err());
+    }
+
+    #[test]
+    fn test_eval_quasiquote() {
+        use intrinsics;
+        use super::LispValue::*;
+        let mut env = Env::new();
+        bind_intrinsic(&mut env, "quasiquote", &intrinsics::core::mgi_quasiquote);
+        bind_procedure(&mut env, "+", &intrinsics::math::mgi_plus);
+        env.add_binding("xs".into(), list_of(vec![Integer(3), Integer(4)]));
+        let toks = parser::lex("`(1 ,(+ 1 1) ,@xs 5)").unwrap();
+        let expr = parser::parse(&mut toks.into_iter().peekable()).unwrap();
+        assert_eq!(
+            super::eval(&expr, &mut env).unwrap(),
+            list_of(vec![Integer(1), Integer(2), Integer(3), Integer(4), Integer(5)]));
+    }
+
+    #[test]
+    fn test_eval_dotted_quasiquote() {
+        use intrinsics;
+        use super::LispValue::*;
+        let mut env = Env::new();
+        bind_intrinsic(&mut env, "quasiquote", &intrinsics::core::mgi_quasiquote);
+        env.add_binding("x".into(), Rc::new(Integer(2)));
+        let toks = parser::lex("`(1 . ,x)").unwrap();
+        let expr = parser::parse(&mut toks.into_iter().peekable()).unwrap();
+        assert_eq!(super::eval(&expr, &mut env).unwrap(), Rc::new(Cons(Rc::new(Integer(1)), Rc::new(Integer(2)))));
+    }
+
+    #[test]
+    fn test_eval_vector_quasiquote() {
+        use std::cell::RefCell;
+        use intrinsics;
+        use super::LispValue::*;
+        let mut env = Env::new();
+        bind_intrinsic(&mut env, "quasiquote", &intrinsics::core::mgi_quasiquote);
+        bind_procedure(&mut env, "+", &intrinsics::math::mgi_plus);
+        env.add_binding("xs".into(), list_of(vec![Integer(3), Integer(4)]));
+        let toks = parser::lex("`#(1 ,(+ 1 1) ,@xs (5 ,(+ 3 3)))").unwrap();
+        let expr = parser::parse(&mut toks.into_iter().peekable()).unwrap();
+        assert_eq!(
+            super::eval(&expr, &mut env).unwrap(),
+            Rc::new(Vector(RefCell::new(vec![
+                Rc::new(Integer(1)), Rc::new(Integer(2)), Rc::new(Integer(3)), Rc::new(Integer(4)),
+                list_of(vec![Integer(5), Integer(6)])]))));
+    }
+
+    #[test]
+    fn test_eval_nested_quasiquote() {
+        use intrinsics;
+        use super::LispValue::*;
+        let mut env = Env::new();
+        bind_intrinsic(&mut env, "quasiquote", &intrinsics::core::mgi_quasiquote);
+        env.add_binding("x".into(), Rc::new(Integer(2)));
+        let toks = parser::lex("`(a `(b ,(c ,x)))").unwrap();
+        let expr = parser::parse(&mut toks.into_iter().peekable()).unwrap();
+        let inner = list_of(vec![
+            Symbol("b".into()),
+            (*list_of(vec![
+                Symbol("unquote".into()),
+                (*list_of(vec![Symbol("c".into()), Integer(2)])).clone()])).clone()]);
+        assert_eq!(
+            super::eval(&expr, &mut env).unwrap(),
+            list_of(vec![
+                Symbol("a".into()),
+                (*list_of(vec![Symbol("quasiquote".into()), (*inner).clone()])).clone()]));
+    }
+
+    #[test]
+    fn test_deepcopy_cycles() {
+        use exec::LispProgram;
+        use test_util::run;
+
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define v #(1))").unwrap();
+        run(&mut prog, "(vector-set! v 0 v)").unwrap();
+        let v = run(&mut prog, "v").unwrap();
+        let c = run(&mut prog, "(deepcopy v)").unwrap();
+        match c.as_ref() {
+            LispValue::Vector(items) => {
+                assert!(Rc::ptr_eq(&items.borrow()[0], &c));
+                assert!(!Rc::ptr_eq(&items.borrow()[0], &v));
+            },
+            c => panic!("deepcopy gave back {:?}", c)
+        }
+        assert_eq!(c, v);
+        assert_eq!(format!("{:?}", c), "Vector(RefCell { value: [<cycle>] })");
+
+        // Something that shows up twice is still shared in the copy.
+        let w = run(&mut prog, "(deepcopy ((lambda (s) (vector s s)) #(1)))").unwrap();
+        match w.as_ref() {
+            LispValue::Vector(items) => assert!(Rc::ptr_eq(&items.borrow()[0], &items.borrow()[1])),
+            w => panic!("deepcopy gave back {:?}", w)
+        }
+    }
+
 }
@@ -1,49 +1,414 @@
-#![allow(unused)]
-
 use std::rc::*;
-use std::sync::*;
-
-use std::collections::*;
 
 use eval;
-use eval::{LispValue, LispFunction};
+use eval::{Env, LispValue, LispFunction};
 use parser::sexp;
-use intrinsics::{self, MgIntrinsic, IntrinsicImpl};
+use intrinsics::{self, MgIntrinsic, IntrinsicImpl, ProcedureImpl};
+use machine::{Native, Outcome};
 
+/// A program's global environment, along with the means to run code in it.
+///
+/// Starts out empty, the `with_*` functions install groups of intrinsics under their canonical
+/// names so embedders can pick which parts of the standard environment they want to expose.
+///
+/// Cloning gives another handle to the same program, so a definition made through one is seen
+/// through all of them.  Closures defined at the top level point back at it, so once the last
+/// handle is dropped the top level gets cleared to let them go; values taken out with `resolve`
+/// that are still around by then will find nothing bound there.
 #[derive(Clone)]
 pub struct LispProgram {
-    prelude: bool,
-    env: HashMap<String, Rc<LispValue>>
+    env: Env,
+    handles: Rc<()>
 }
 
 impl LispProgram {
 
-    fn new() -> LispProgram {
+    /// An empty program, with nothing bound at all.
+    pub fn new() -> LispProgram {
         LispProgram {
-            prelude: true,
-            env: HashMap::new()
+            env: Env::new(),
+            handles: Rc::new(())
         }
     }
 
-    fn with_core(mut self) -> LispProgram {
+    /// A program with the entire standard environment installed.
+    pub fn standard() -> LispProgram {
+        LispProgram::new()
+            .with_core()
+            .with_control()
+            .with_math()
+            .with_lists()
+            .with_strings()
+            .with_chars()
+            .with_vectors()
+            .with_hashes()
+            .with_persistent()
+            .with_records()
+            .with_bytes()
+    }
+
+    /// Special forms and core functions: `quote`, `quasiquote`, `eval`, `lambda`, `vlambda`,
+    /// `apply`, `define`, `if`, `begin`, `typeof`, and `deepcopy`.
+    pub fn with_core(self) -> LispProgram {
         self
+            .with_function("quote", &intrinsics::core::mgi_quote)
+            .with_function("quasiquote", &intrinsics::core::mgi_quasiquote)
+            .with_native("eval", Native::Eval)
             .with_function("lambda", &intrinsics::core::mgi_lambda)
-            .with_function("define", &intrinsics::core::mgi_define)
-            .with_function("if", &intrinsics::core::mgi_if)
-            .with_function("typeof", &intrinsics::core::mgi_typeof)
-            .with_function("begin", &intrinsics::core::mgi_begin)
-            .with_function("deepcopy", &intrinsics::core::mgi_hard_clone)
+            .with_function("vlambda", &intrinsics::core::mgi_variadic_lambda)
+            .with_native("apply", Native::Apply)
+            .with_native("define", Native::Define)
+            .with_native("if", Native::If)
+            .with_native("begin", Native::Begin)
+            .with_procedure("typeof", &intrinsics::core::mgi_typeof)
+            .with_procedure("deepcopy", &intrinsics::core::mgi_hard_clone)
+    }
+
+    /// Errors and control flow: `raise`, `error`, `guard`, `dynamic-wind`, `call/cc` (also as
+    /// `call-with-current-continuation`), `condition?`, `condition-kind`, `condition-message`,
+    /// `make-coroutine`, `yield`, `resume`, and `coroutine-status`.
+    pub fn with_control(self) -> LispProgram {
+        self
+            .with_procedure("raise", &intrinsics::control::mgi_raise)
+            .with_procedure("error", &intrinsics::control::mgi_error)
+            .with_native("guard", Native::Guard)
+            .with_native("dynamic-wind", Native::DynamicWind)
+            .with_native("call/cc", Native::CallCC)
+            .with_native("call-with-current-continuation", Native::CallCC)
+            .with_procedure("condition?", &intrinsics::control::mgi_condition_p)
+            .with_procedure("condition-kind", &intrinsics::control::mgi_condition_kind)
+            .with_procedure("condition-message", &intrinsics::control::mgi_condition_message)
+            .with_procedure("make-coroutine", &intrinsics::control::mgi_make_coroutine)
+            .with_native("yield", Native::Yield)
+            .with_procedure("resume", &intrinsics::control::mgi_resume)
+            .with_procedure("coroutine-status", &intrinsics::control::mgi_coroutine_status)
+    }
+
+    /// Integer arithmetic: `+`, `-`, `*`, `/`, `min`, `max`, `abs`, `expt`, `gcd`, and `lcm`.
+    pub fn with_math(self) -> LispProgram {
+        self
+            .with_procedure("+", &intrinsics::math::mgi_plus)
+            .with_procedure("-", &intrinsics::math::mgi_subtract)
+            .with_procedure("*", &intrinsics::math::mgi_multiply)
+            .with_procedure("/", &intrinsics::math::mgi_divide)
+            .with_procedure("min", &intrinsics::math::mgi_min)
+            .with_procedure("max", &intrinsics::math::mgi_max)
+            .with_procedure("abs", &intrinsics::math::mgi_abs)
+            .with_procedure("expt", &intrinsics::math::mgi_expt)
+            .with_procedure("gcd", &intrinsics::math::mgi_gcd)
+            .with_procedure("lcm", &intrinsics::math::mgi_lcm)
+    }
+
+    /// Cons cells: `cons`, `first`, and `rest`.
+    pub fn with_lists(self) -> LispProgram {
+        self
+            .with_procedure("cons", &intrinsics::lists::mgi_cons)
+            .with_procedure("first", &intrinsics::lists::mgi_first)
+            .with_procedure("rest", &intrinsics::lists::mgi_rest)
+    }
+
+    /// Strings, which count in chars except for `str-len` which counts bytes: `str-len`, `str-app`,
+    /// `string-ref`, `string-length`, `string-append`, `substring`, `string-index`,
+    /// `string-contains?`, `string-split`, `string-join`, `string-trim`, `string-upcase`,
+    /// `string-downcase`, `string-replace`, `string->number`, `number->string`, `string->symbol`,
+    /// `symbol->string`, and `format`, which builds a string out of a template.
+    pub fn with_strings(self) -> LispProgram {
+        self
+            .with_procedure("str-len", &intrinsics::string::mgi_str_len)
+            .with_procedure("str-app", &intrinsics::string::mgi_str_app)
+            .with_procedure("string-ref", &intrinsics::string::mgi_string_ref)
+            .with_procedure("string-length", &intrinsics::string::mgi_string_length)
+            .with_procedure("string-append", &intrinsics::string::mgi_string_append)
+            .with_procedure("substring", &intrinsics::string::mgi_substring)
+            .with_procedure("string-index", &intrinsics::string::mgi_string_index)
+            .with_procedure("string-contains?", &intrinsics::string::mgi_string_contains)
+            .with_procedure("string-split", &intrinsics::string::mgi_string_split)
+            .with_procedure("string-join", &intrinsics::string::mgi_string_join)
+            .with_procedure("string-trim", &intrinsics::string::mgi_string_trim)
+            .with_procedure("string-upcase", &intrinsics::string::mgi_string_upcase)
+            .with_procedure("string-downcase", &intrinsics::string::mgi_string_downcase)
+            .with_procedure("string-replace", &intrinsics::string::mgi_string_replace)
+            .with_procedure("string->number", &intrinsics::string::mgi_string_to_number)
+            .with_procedure("number->string", &intrinsics::string::mgi_number_to_string)
+            .with_procedure("string->symbol", &intrinsics::string::mgi_string_to_symbol)
+            .with_procedure("symbol->string", &intrinsics::string::mgi_symbol_to_string)
+            .with_procedure("format", &intrinsics::format::mgi_format)
+    }
+
+    /// Vectors: `vector`, `vector-ref`, `vector-set!`, `vector-length`, `vector-push!`,
+    /// `vector->list`, and `list->vector`.
+    pub fn with_vectors(self) -> LispProgram {
+        self
+            .with_procedure("vector", &intrinsics::vector::mgi_vector)
+            .with_procedure("vector-ref", &intrinsics::vector::mgi_vector_ref)
+            .with_procedure("vector-set!", &intrinsics::vector::mgi_vector_set)
+            .with_procedure("vector-length", &intrinsics::vector::mgi_vector_length)
+            .with_procedure("vector-push!", &intrinsics::vector::mgi_vector_push)
+            .with_procedure("vector->list", &intrinsics::vector::mgi_vector_to_list)
+            .with_procedure("list->vector", &intrinsics::vector::mgi_list_to_vector)
     }
 
-    fn with_function(mut self, name: &str, func: &'static IntrinsicImpl) -> LispProgram {
-        self.env.insert(
-            String::from(name),
-            Rc::new(LispValue::Func(Box::new(LispFunction::Intrinsic(MgIntrinsic::new(String::from(name), func))))));
+    /// Hash maps: `hash`, `hash-ref`, `hash-set!`, `hash-remove!`, `hash-has-key?`, `hash-count`,
+    /// `hash-keys`, `hash-values`, `hash->list`, and `hash-for-each`.
+    pub fn with_hashes(self) -> LispProgram {
         self
+            .with_procedure("hash", &intrinsics::hash::mgi_hash)
+            .with_procedure("hash-ref", &intrinsics::hash::mgi_hash_ref)
+            .with_procedure("hash-set!", &intrinsics::hash::mgi_hash_set)
+            .with_procedure("hash-remove!", &intrinsics::hash::mgi_hash_remove)
+            .with_procedure("hash-has-key?", &intrinsics::hash::mgi_hash_has_key)
+            .with_procedure("hash-count", &intrinsics::hash::mgi_hash_count)
+            .with_procedure("hash-keys", &intrinsics::hash::mgi_hash_keys)
+            .with_procedure("hash-values", &intrinsics::hash::mgi_hash_values)
+            .with_procedure("hash->list", &intrinsics::hash::mgi_hash_to_list)
+            .with_native("hash-for-each", Native::HashForEach)
+    }
+
+    /// Immutable collections: `pvector`, `pmap`, `persistent`, `get`, `assoc`, `dissoc`, `conj`,
+    /// `pop`, `count`, `contains?`, `pvector->list`, and `pmap->list`.
+    pub fn with_persistent(self) -> LispProgram {
+        self
+            .with_procedure("pvector", &intrinsics::persist::mgi_pvector)
+            .with_procedure("pmap", &intrinsics::persist::mgi_pmap)
+            .with_procedure("persistent", &intrinsics::persist::mgi_persistent)
+            .with_procedure("get", &intrinsics::persist::mgi_get)
+            .with_procedure("assoc", &intrinsics::persist::mgi_assoc)
+            .with_procedure("dissoc", &intrinsics::persist::mgi_dissoc)
+            .with_procedure("conj", &intrinsics::persist::mgi_conj)
+            .with_procedure("pop", &intrinsics::persist::mgi_pop)
+            .with_procedure("count", &intrinsics::persist::mgi_count)
+            .with_procedure("contains?", &intrinsics::persist::mgi_contains)
+            .with_procedure("pvector->list", &intrinsics::persist::mgi_pvector_to_list)
+            .with_procedure("pmap->list", &intrinsics::persist::mgi_pmap_to_list)
+    }
+
+    /// `define-record-type`, for making new types of data.
+    pub fn with_records(self) -> LispProgram {
+        self.with_function("define-record-type", &intrinsics::record::mgi_define_record_type)
+    }
+
+    /// Byte arrays: `bytes`, `make-bytes`, `bytes-ref`, `bytes-set!`, `bytes-length`, `bytes-slice`,
+    /// `bytes-append`, and converting them to and from hex, base64, and UTF-8 strings.
+    pub fn with_bytes(self) -> LispProgram {
+        self
+            .with_procedure("bytes", &intrinsics::bytes::mgi_bytes)
+            .with_procedure("make-bytes", &intrinsics::bytes::mgi_make_bytes)
+            .with_procedure("bytes-ref", &intrinsics::bytes::mgi_bytes_ref)
+            .with_procedure("bytes-set!", &intrinsics::bytes::mgi_bytes_set)
+            .with_procedure("bytes-length", &intrinsics::bytes::mgi_bytes_length)
+            .with_procedure("bytes-slice", &intrinsics::bytes::mgi_bytes_slice)
+            .with_procedure("bytes-append", &intrinsics::bytes::mgi_bytes_append)
+            .with_procedure("bytes->hex", &intrinsics::bytes::mgi_bytes_to_hex)
+            .with_procedure("hex->bytes", &intrinsics::bytes::mgi_hex_to_bytes)
+            .with_procedure("bytes->base64", &intrinsics::bytes::mgi_bytes_to_base64)
+            .with_procedure("base64->bytes", &intrinsics::bytes::mgi_base64_to_bytes)
+            .with_procedure("string->utf8", &intrinsics::bytes::mgi_string_to_utf8)
+            .with_procedure("utf8->string", &intrinsics::bytes::mgi_utf8_to_string)
+    }
+
+    /// Characters: `char->integer`, `integer->char`, `char-alphabetic?`, `char-numeric?`,
+    /// `char-whitespace?`, `char-upper-case?`, `char-lower-case?`, `char-upcase`, and `char-downcase`.
+    pub fn with_chars(self) -> LispProgram {
+        self
+            .with_procedure("char->integer", &intrinsics::chars::mgi_char_to_integer)
+            .with_procedure("integer->char", &intrinsics::chars::mgi_integer_to_char)
+            .with_procedure("char-alphabetic?", &intrinsics::chars::mgi_char_alphabetic)
+            .with_procedure("char-numeric?", &intrinsics::chars::mgi_char_numeric)
+            .with_procedure("char-whitespace?", &intrinsics::chars::mgi_char_whitespace)
+            .with_procedure("char-upper-case?", &intrinsics::chars::mgi_char_upper_case)
+            .with_procedure("char-lower-case?", &intrinsics::chars::mgi_char_lower_case)
+            .with_procedure("char-upcase", &intrinsics::chars::mgi_char_upcase)
+            .with_procedure("char-downcase", &intrinsics::chars::mgi_char_downcase)
+    }
+
+    /// Binds an intrinsic under some name, replacing whatever was there before.  It gets its
+    /// arguments unevaluated, like a special form.
+    pub fn with_function(self, name: &str, func: &'static IntrinsicImpl) -> LispProgram {
+        self.with_intrinsic(MgIntrinsic::new(String::from(name), func))
+    }
+
+    /// Binds an ordinary function under some name, which gets its arguments already evaluated.
+    pub fn with_procedure(self, name: &str, func: &'static ProcedureImpl) -> LispProgram {
+        self.with_intrinsic(MgIntrinsic::procedure(String::from(name), func))
+    }
+
+    fn with_native(self, name: &str, n: Native) -> LispProgram {
+        self.with_intrinsic(MgIntrinsic::native(String::from(name), n))
+    }
+
+    fn with_intrinsic(mut self, i: MgIntrinsic) -> LispProgram {
+        self.env.add_binding(i.name.clone(), Rc::new(LispValue::Func(Box::new(LispFunction::Intrinsic(i)))));
+        self
+    }
+
+    /// Evaluates an expression in the program's environment, keeping any definitions it makes.
+    pub fn exec(&mut self, sexp: &sexp::Sexp) -> Result<Rc<LispValue>, eval::EvalError> {
+        eval::eval(sexp, &mut self.env)
+    }
+
+    /// Runs a coroutine until it yields or finishes, passing it `arg`.  This returns as soon as it
+    /// yields, so a host can resume it again whenever it's ready, like on its next tick.
+    pub fn resume(&mut self, co: &LispValue, arg: Rc<LispValue>) -> Result<Outcome, eval::EvalError> {
+        match co {
+            LispValue::Coroutine(c) => c.resume(Some(arg)),
+            v => Err(eval::ErrorKind::TypeError { expected: "coroutine".into(), got: v.type_name().into(), arg_index: 1 }.into())
+        }
+    }
+
+    /// Looks up what's currently bound to a name.
+    pub fn resolve(&self, name: &str) -> Option<Rc<LispValue>> {
+        self.env.resolve(&String::from(name))
+    }
+
+}
+
+impl Drop for LispProgram {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.handles) == 1 {
+            self.env.clear_globals();
+        }
+    }
+}
+
+impl Default for LispProgram {
+    fn default() -> LispProgram {
+        LispProgram::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use parser;
+    use eval::LispValue;
+    use super::LispProgram;
+    use test_util::run;
+
+    #[test]
+    fn test_standard_env_complete() {
+        let prog = LispProgram::standard();
+        let names = [
+            "quote", "quasiquote", "eval", "lambda", "vlambda", "apply", "define", "if", "begin", "typeof", "deepcopy",
+            "raise", "error", "guard", "dynamic-wind", "call/cc", "call-with-current-continuation", "condition?", "condition-kind", "condition-message",
+            "make-coroutine", "yield", "resume", "coroutine-status",
+            "+", "-", "*", "/", "min", "max", "abs", "expt", "gcd", "lcm",
+            "cons", "first", "rest",
+            "str-len", "str-app",
+            "vector", "vector-ref", "vector-set!", "vector-length", "vector-push!", "vector->list", "list->vector",
+            "hash", "hash-ref", "hash-set!", "hash-remove!", "hash-has-key?", "hash-count", "hash-keys", "hash-values",
+            "hash->list", "hash-for-each",
+            "pvector", "pmap", "persistent", "get", "assoc", "dissoc", "conj", "pop", "count", "contains?",
+            "pvector->list", "pmap->list",
+            "define-record-type",
+            "bytes", "make-bytes", "bytes-ref", "bytes-set!", "bytes-length", "bytes-slice", "bytes-append",
+            "bytes->hex", "hex->bytes", "bytes->base64", "base64->bytes", "string->utf8", "utf8->string",
+            "string-ref", "char->integer", "integer->char", "char-alphabetic?", "char-numeric?", "char-whitespace?",
+            "char-upper-case?", "char-lower-case?", "char-upcase", "char-downcase",
+            "string-length", "string-append", "substring", "string-index", "string-contains?", "string-split",
+            "string-join", "string-trim", "string-upcase", "string-downcase", "string-replace", "string->number",
+            "number->string", "string->symbol", "symbol->string", "format"
+        ];
+        for n in names.iter() {
+            assert!(prog.resolve(n).is_some(), "{} not bound", n);
+        }
+    }
+
+    #[test]
+    fn test_subsets() {
+        let prog = LispProgram::new().with_math();
+        assert!(prog.resolve("+").is_some());
+        assert!(prog.resolve("cons").is_none());
+        assert!(prog.resolve("define").is_none());
+    }
+
+    #[test]
+    fn test_exec_keeps_definitions() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define sq (lambda (x) (* x x)))").unwrap();
+        assert_eq!(run(&mut prog, "(sq (str-len \"abc\"))").unwrap(), Rc::new(LispValue::Integer(9)));
+    }
+
+    #[test]
+    fn test_clones_share_definitions() {
+        let mut prog = LispProgram::standard();
+        let mut other = prog.clone();
+        run(&mut other, "(define x 5)").unwrap();
+        assert_eq!(run(&mut prog, "x").unwrap(), Rc::new(LispValue::Integer(5)));
+        drop(other);
+        assert_eq!(run(&mut prog, "(+ x 1)").unwrap(), Rc::new(LispValue::Integer(6)));
+    }
+
+    #[test]
+    fn test_drop_frees_closures() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define f (lambda (x) (f x)))").unwrap();
+        let f = Rc::downgrade(&prog.resolve("f").unwrap());
+        drop(prog);
+        assert!(f.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_vlambda_and_apply() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define l (vlambda args))").unwrap();
+        run(&mut prog, "(define rl (vlambda xs (first (rest xs))))").unwrap();
+        assert_eq!(run(&mut prog, "(first (l 1 2 3))").unwrap(), Rc::new(LispValue::Integer(1)));
+        assert_eq!(run(&mut prog, "(rl 1 2 3)").unwrap(), Rc::new(LispValue::Integer(2)));
+        assert_eq!(run(&mut prog, "(apply + '(1 2))").unwrap(), Rc::new(LispValue::Integer(3)));
+        assert_eq!(run(&mut prog, "(apply rl 7 '(8 9))").unwrap(), Rc::new(LispValue::Integer(8)));
+        assert_eq!(run(&mut prog, "(first (apply cons (l l '())))").unwrap(), run(&mut prog, "l").unwrap());
+    }
+
+    #[test]
+    fn test_stack_trace() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define inner (lambda (x) (+ x #t)))").unwrap();
+        run(&mut prog, "(define outer (lambda (y) (inner (* y 2))))").unwrap();
+        let toks = parser::lex_spanned(&String::from("(outer 3)")).unwrap();
+        let sexp = parser::parse(&mut toks.into_iter().peekable()).unwrap();
+        let e = prog.exec(&sexp).unwrap_err();
+        let names: Vec<_> = e.trace().iter().map(|f| f.function.clone()).collect();
+        assert_eq!(names, vec!["+", "inner", "outer"]);
+        assert_eq!(e.trace()[1].args, vec![Rc::new(LispValue::Integer(6))]);
+        assert_eq!(e.trace()[2].call_site.map(|s| (s.start, s.end)), Some((0, 9)));
+        assert_eq!(
+            e.traceback(),
+            "Traceback (most recent call last):\n  \
+             line 1, col 1, in outer (3)\n  \
+             in inner (6)\n  \
+             in intrinsic + (6 #t)\n\
+             error: argument 2 should be integer, got bool");
+
+        assert_eq!(
+            e.traceback_in("t.mg", "(outer 3)"),
+            "Traceback (most recent call last):\n  \
+             File \"t.mg\", line 1, col 1, in outer (3)\n    \
+             (outer 3)\n  \
+             in inner (6)\n  \
+             in intrinsic + (6 #t)\n\
+             error: argument 2 should be integer, got bool");
+    }
+
+    #[test]
+    fn test_quote_and_eval() {
+        let mut prog = LispProgram::standard();
+        assert_eq!(run(&mut prog, "(first (rest (rest '(1 2 3))))").unwrap(), Rc::new(LispValue::Integer(3)));
+        assert_eq!(run(&mut prog, "(rest (rest (rest '(1 2 3))))").unwrap(), Rc::new(LispValue::Null));
+        assert_eq!(run(&mut prog, "(eval '(+ 1 (* 2 3)))").unwrap(), Rc::new(LispValue::Integer(7)));
     }
 
-    fn exec(self, sexp: Box<sexp::Sexp>) -> Result<Rc<LispValue>, eval::EvalError> {
-        unimplemented!()
+    #[test]
+    fn test_host_resume() {
+        use machine::Outcome;
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define agent (make-coroutine (lambda (tick) (begin (yield 'waiting) (yield (+ tick 1)) 'done))))").unwrap();
+        let co = prog.resolve("agent").unwrap();
+        let sym = |s: &str| Rc::new(LispValue::Symbol(s.into()));
+        assert_eq!(prog.resume(&co, Rc::new(LispValue::Integer(1))).unwrap(), Outcome::Yielded(sym("waiting")));
+        assert_eq!(prog.resume(&co, Rc::new(LispValue::Null)).unwrap(), Outcome::Yielded(Rc::new(LispValue::Integer(2))));
+        assert_eq!(prog.resume(&co, Rc::new(LispValue::Null)).unwrap(), Outcome::Done(sym("done")));
+        assert!(prog.resume(&co, Rc::new(LispValue::Null)).is_err());
     }
 
 }
@@ -0,0 +1,269 @@
+use std::cell::RefCell;
+use std::rc::*;
+
+use eval::*;
+use intrinsics::*;
+
+use eval::LispValue::*;
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn new_bytes(b: Vec<u8>) -> Rc<LispValue> {
+    Rc::new(ByteArray(RefCell::new(b.into_boxed_slice())))
+}
+
+fn bytes_arg(v: &LispValue, arg_index: usize) -> Result<&RefCell<Box<[u8]>>, EvalError> {
+    match v {
+        ByteArray(b) => Ok(b),
+        v => Err(ErrorKind::TypeError { expected: "bytearray".into(), got: v.type_name().into(), arg_index: arg_index }.into())
+    }
+}
+
+fn byte_arg(v: &LispValue, arg_index: usize) -> Result<u8, EvalError> {
+    match v {
+        &Integer(i) if (0..=255).contains(&i) => Ok(i as u8),
+        &Integer(i) => Err(ErrorKind::InvalidArgument(format!("{} isn't a byte", i)).into()),
+        v => Err(ErrorKind::TypeError { expected: "integer".into(), got: v.type_name().into(), arg_index: arg_index }.into())
+    }
+}
+
+pub fn mgi_bytes(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    let b = args.iter().enumerate().map(|(i, v)| byte_arg(v, i + 1)).collect::<Result<Vec<_>, _>>()?;
+    Ok(new_bytes(b))
+}
+
+/// `(make-bytes n [fill])`, which starts out as zeroes if there's no fill.
+pub fn mgi_make_bytes(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.is_empty() || args.len() > 2 {
+        return arity_error(Arity::Between(1, 2), args);
+    }
+
+    let n = match args[0].as_ref() {
+        &Integer(n) if n >= 0 => n as usize,
+        &Integer(n) => return Err(ErrorKind::InvalidArgument(format!("can't make {} bytes", n)).into()),
+        v => return type_error("integer", v, 1)
+    };
+    let fill = match args.get(1) {
+        Some(f) => byte_arg(f, 2)?,
+        None => 0
+    };
+    Ok(new_bytes(vec![fill; n]))
+
+}
+
+pub fn mgi_bytes_ref(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 {
+        return arity_error(Arity::Exactly(2), args);
+    }
+
+    let b = bytes_arg(&args[0], 1)?.borrow();
+    let i = index_arg(args[1].as_ref(), b.len(), 2)?;
+    Ok(Rc::new(Integer(b[i] as i64)))
+
+}
+
+pub fn mgi_bytes_set(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 3 {
+        return arity_error(Arity::Exactly(3), args);
+    }
+
+    let mut b = bytes_arg(&args[0], 1)?.borrow_mut();
+    let i = index_arg(args[1].as_ref(), b.len(), 2)?;
+    b[i] = byte_arg(&args[2], 3)?;
+    Ok(Rc::new(Null))
+
+}
+
+pub fn mgi_bytes_length(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(Rc::new(Integer(bytes_arg(&args[0], 1)?.borrow().len() as i64)))
+
+}
+
+/// `(bytes-slice b start [end])`, copying out the bytes from `start` up to but not including `end`.
+pub fn mgi_bytes_slice(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 && args.len() != 3 {
+        return arity_error(Arity::Between(2, 3), args);
+    }
+
+    let b = bytes_arg(&args[0], 1)?.borrow();
+    let start = bound_arg(&args[1], b.len(), 2)?;
+    let end = match args.get(2) {
+        Some(e) => bound_arg(e, b.len(), 3)?,
+        None => b.len()
+    };
+    if end < start {
+        return Err(ErrorKind::InvalidArgument(format!("slice ends at {} before it starts at {}", end, start)).into());
+    }
+    Ok(new_bytes(b[start..end].to_vec()))
+
+}
+
+pub fn mgi_bytes_append(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    let mut out = Vec::new();
+    for (i, v) in args.iter().enumerate() {
+        out.extend_from_slice(&bytes_arg(v, i + 1)?.borrow());
+    }
+    Ok(new_bytes(out))
+}
+
+pub fn mgi_bytes_to_hex(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(Rc::new(Str(bytes_arg(&args[0], 1)?.borrow().iter().map(|b| format!("{:02x}", b)).collect())))
+
+}
+
+/// Takes either case, but there has to be an even number of digits.
+pub fn mgi_hex_to_bytes(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    let s = str_arg(&args[0], 1)?;
+    let digits = s.chars().map(|c| c.to_digit(16)).collect::<Option<Vec<_>>>();
+    match digits {
+        Some(ref d) if d.len() % 2 == 0 => Ok(new_bytes(d.chunks(2).map(|p| (p[0] * 16 + p[1]) as u8).collect())),
+        _ => Err(ErrorKind::InvalidArgument(format!("{:?} isn't hex", s)).into())
+    }
+
+}
+
+pub fn mgi_bytes_to_base64(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    let b = bytes_arg(&args[0], 1)?.borrow();
+    let mut out = String::new();
+    for chunk in b.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &c)| n | (c as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    Ok(Rc::new(Str(out)))
+
+}
+
+/// The padding on the end is optional, but anything else that's not base64 is an error.
+pub fn mgi_base64_to_bytes(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    let s = str_arg(&args[0], 1)?;
+    let bad = || ErrorKind::InvalidArgument(format!("{:?} isn't base64", s)).into();
+    let digits = s.trim_end_matches('=').bytes()
+        .map(|c| BASE64.iter().position(|&d| d == c).map(|d| d as u32))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(bad)?;
+    if digits.len() % 4 == 1 || s.len() - s.trim_end_matches('=').len() > 2 {
+        return Err(bad());
+    }
+
+    let mut out = Vec::new();
+    for chunk in digits.chunks(4) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &d)| n | d << (18 - 6 * i));
+        for i in 0..chunk.len() - 1 {
+            out.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Ok(new_bytes(out))
+
+}
+
+pub fn mgi_string_to_utf8(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(new_bytes(str_arg(&args[0], 1)?.as_bytes().to_vec()))
+
+}
+
+pub fn mgi_utf8_to_string(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match String::from_utf8(bytes_arg(&args[0], 1)?.borrow().to_vec()) {
+        Ok(s) => Ok(Rc::new(Str(s))),
+        Err(e) => Err(ErrorKind::InvalidArgument(format!("not valid UTF-8: {}", e)).into())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use eval::{ErrorKind, LispValue};
+    use exec::LispProgram;
+    use test_util::{run, int, string};
+
+    #[test]
+    fn test_bytes() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define b #u8(1 2 255))").unwrap();
+        assert_eq!(run(&mut prog, "(typeof b)").unwrap(), Rc::new(LispValue::Symbol("bytearray".into())));
+        assert_eq!(run(&mut prog, "(bytes-ref b 2)").unwrap(), int(255));
+        run(&mut prog, "(bytes-set! b 0 16)").unwrap();
+        assert_eq!(run(&mut prog, "(bytes-ref b 0)").unwrap(), int(16));
+        assert_eq!(run(&mut prog, "(bytes-length (bytes-append b (bytes 4 5) (make-bytes 2)))").unwrap(), int(7));
+        assert_eq!(run(&mut prog, "(bytes->hex (bytes-slice b 1))").unwrap(), string("02ff"));
+        assert_eq!(run(&mut prog, "(bytes->hex (bytes-slice b 1 2))").unwrap(), string("02"));
+        assert_eq!(run(&mut prog, "(bytes-length (bytes-slice b 3))").unwrap(), int(0));
+        assert_eq!(run(&mut prog, "(bytes-ref (make-bytes 3 7) 2)").unwrap(), int(7));
+    }
+
+    #[test]
+    fn test_bytes_errors() {
+        let mut prog = LispProgram::standard();
+        assert_eq!(run(&mut prog, "(bytes-ref #u8(1) 1)").unwrap_err().kind(), &ErrorKind::OutOfRange { index: 1, len: 1 });
+        assert!(run(&mut prog, "(bytes-set! #u8(1) 0 256)").is_err());
+        assert!(run(&mut prog, "(bytes 1 -1)").is_err());
+        assert!(run(&mut prog, "(bytes-slice #u8(1 2) 2 1)").is_err());
+        assert!(run(&mut prog, "(bytes-slice #u8(1 2) 0 3)").is_err());
+        assert!(run(&mut prog, "(hex->bytes \"abc\")").is_err());
+        assert!(run(&mut prog, "(hex->bytes \"zz\")").is_err());
+        assert!(run(&mut prog, "(base64->bytes \"a\")").is_err());
+        assert!(run(&mut prog, "(base64->bytes \"a!==\")").is_err());
+        assert!(run(&mut prog, "(utf8->string #u8(255))").is_err());
+    }
+
+    #[test]
+    fn test_encodings() {
+        let mut prog = LispProgram::standard();
+        assert_eq!(run(&mut prog, "(bytes->hex (hex->bytes \"00ABff\"))").unwrap(), string("00abff"));
+        for &(plain, encoded) in &[("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foobar", "Zm9vYmFy")] {
+            let enc = format!("(bytes->base64 (string->utf8 {:?}))", plain);
+            assert_eq!(run(&mut prog, &enc).unwrap(), string(encoded));
+            let dec = format!("(utf8->string (base64->bytes {:?}))", encoded);
+            assert_eq!(run(&mut prog, &dec).unwrap(), string(plain));
+        }
+        assert_eq!(run(&mut prog, "(utf8->string (base64->bytes \"Zm8\"))").unwrap(), string("fo"));
+        assert_eq!(run(&mut prog, "(bytes-length (string->utf8 \"\u{e9}\"))").unwrap(), int(2));
+    }
+
+}
@@ -0,0 +1,133 @@
+use std::rc::*;
+
+use eval::*;
+use intrinsics::*;
+
+use eval::LispValue::*;
+
+/// Gets the one char argument, for all the functions that only take that.
+fn char_arg(args: &[Rc<LispValue>]) -> Result<char, EvalError> {
+
+    if args.len() != 1 {
+        return Err(ErrorKind::ArityMismatch { expected: Arity::Exactly(1), got: args.len() }.into());
+    }
+
+    match args[0].as_ref() {
+        &Char(c) => Ok(c),
+        v => Err(ErrorKind::TypeError { expected: "char".into(), got: v.type_name().into(), arg_index: 1 }.into())
+    }
+
+}
+
+fn char_test(args: &[Rc<LispValue>], test: fn(char) -> bool) -> Result<Rc<LispValue>, EvalError> {
+    Ok(Rc::new(Boolean(test(char_arg(args)?))))
+}
+
+/// Changing case can turn one char into several, like `ß` to `SS`, and then we leave it alone.
+fn single<I: Iterator<Item = char>>(c: char, mut mapped: I) -> Rc<LispValue> {
+    match (mapped.next(), mapped.next()) {
+        (Some(m), None) => Rc::new(Char(m)),
+        _ => Rc::new(Char(c))
+    }
+}
+
+pub fn mgi_char_to_integer(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    Ok(Rc::new(Integer(char_arg(args)? as i64)))
+}
+
+pub fn mgi_integer_to_char(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match args[0].as_ref() {
+        &Integer(i) => {
+
+            // Surrogates are in range but still aren't chars, `from_u32` catches those.
+            let c = if (0..=0x10ffff).contains(&i) { ::std::char::from_u32(i as u32) } else { None };
+            match c {
+                Some(c) => Ok(Rc::new(Char(c))),
+                None => Err(ErrorKind::InvalidArgument(format!("{} isn't a Unicode scalar value", i)).into())
+            }
+
+        },
+        v => type_error("integer", v, 1)
+    }
+
+}
+
+pub fn mgi_char_alphabetic(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    char_test(args, char::is_alphabetic)
+}
+
+pub fn mgi_char_numeric(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    char_test(args, char::is_numeric)
+}
+
+pub fn mgi_char_whitespace(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    char_test(args, char::is_whitespace)
+}
+
+pub fn mgi_char_upper_case(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    char_test(args, char::is_uppercase)
+}
+
+pub fn mgi_char_lower_case(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    char_test(args, char::is_lowercase)
+}
+
+pub fn mgi_char_upcase(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    let c = char_arg(args)?;
+    Ok(single(c, c.to_uppercase()))
+}
+
+pub fn mgi_char_downcase(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    let c = char_arg(args)?;
+    Ok(single(c, c.to_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use eval::{ErrorKind, LispValue};
+    use exec::LispProgram;
+    use test_util::{run, boolean, ch};
+
+    #[test]
+    fn test_chars() {
+        let mut prog = LispProgram::standard();
+        assert_eq!(run(&mut prog, "#\\a").unwrap(), ch('a'));
+        assert_eq!(run(&mut prog, "(typeof #\\space)").unwrap(), Rc::new(LispValue::Symbol("char".into())));
+        assert_eq!(run(&mut prog, "(char->integer #\\x41)").unwrap(), Rc::new(LispValue::Integer(65)));
+        assert_eq!(run(&mut prog, "(integer->char 955)").unwrap(), ch('\u{3bb}'));
+        assert_eq!(run(&mut prog, "(char-upcase #\\a)").unwrap(), ch('A'));
+        assert_eq!(run(&mut prog, "(char-downcase #\\\u{3a3})").unwrap(), ch('\u{3c3}'));
+        assert_eq!(run(&mut prog, "(char-upcase #\\\u{df})").unwrap(), ch('\u{df}'));
+        assert_eq!(run(&mut prog, "(char-alphabetic? #\\\u{e9})").unwrap(), boolean(true));
+        assert_eq!(run(&mut prog, "(char-alphabetic? #\\1)").unwrap(), boolean(false));
+        assert_eq!(run(&mut prog, "(char-numeric? #\\1)").unwrap(), boolean(true));
+        assert_eq!(run(&mut prog, "(char-whitespace? #\\tab)").unwrap(), boolean(true));
+        assert_eq!(run(&mut prog, "(char-upper-case? #\\A)").unwrap(), boolean(true));
+        assert_eq!(run(&mut prog, "(char-lower-case? #\\A)").unwrap(), boolean(false));
+        assert_eq!(run(&mut prog, "(hash-ref {#\\a 1} #\\a)").unwrap(), Rc::new(LispValue::Integer(1)));
+        assert_eq!(run(&mut prog, "(string-ref \"a\u{3bb}b\" 2)").unwrap(), ch('b'));
+        assert_eq!(
+            run(&mut prog, "(string-ref \"a\u{3bb}\" 2)").unwrap_err().kind(),
+            &ErrorKind::OutOfRange { index: 2, len: 2 });
+    }
+
+    #[test]
+    fn test_char_errors() {
+        let mut prog = LispProgram::standard();
+        assert!(run(&mut prog, "(integer->char 55296)").is_err());
+        assert!(run(&mut prog, "(integer->char -1)").is_err());
+        assert!(run(&mut prog, "(integer->char 4294967361)").is_err());
+        assert_eq!(
+            run(&mut prog, "(char-upcase \"a\")").unwrap_err().kind(),
+            &ErrorKind::TypeError { expected: "char".into(), got: "str".into(), arg_index: 1 });
+        assert!(run(&mut prog, "(char-upcase #\\a #\\b)").is_err());
+    }
+
+}
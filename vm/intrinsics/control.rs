@@ -0,0 +1,196 @@
+use std::rc::*;
+
+use eval::*;
+use intrinsics::*;
+use machine::{Coroutine, Outcome};
+
+pub fn mgi_raise(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    // Re-raising a caught condition should give back the original error, not wrap it again.
+    match args[0].as_ref() {
+        LispValue::Condition(c) => Err(c.as_ref().clone()),
+        _ => Err(ErrorKind::UserRaised(args[0].clone()).into())
+    }
+
+}
+
+pub fn mgi_error(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match args[0].as_ref() {
+        LispValue::Str(s) => Err(EvalError::msg(s.clone())),
+        v => type_error("str", v, 1)
+    }
+
+}
+
+pub fn mgi_condition_p(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(Rc::new(LispValue::Boolean(matches!(args[0].as_ref(), &LispValue::Condition(_)))))
+
+}
+
+pub fn mgi_condition_kind(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match args[0].as_ref() {
+        LispValue::Condition(c) => Ok(Rc::new(LispValue::Symbol(c.kind().symbol().into()))),
+        v => type_error("condition", v, 1)
+    }
+
+}
+
+pub fn mgi_condition_message(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match args[0].as_ref() {
+        LispValue::Condition(c) => Ok(Rc::new(LispValue::Str(format!("{}", c.kind())))),
+        v => type_error("condition", v, 1)
+    }
+
+}
+
+pub fn mgi_make_coroutine(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match args[0].as_ref() {
+        &LispValue::Func(_) => Ok(Rc::new(LispValue::Coroutine(Rc::new(Coroutine::new(args[0].clone()))))),
+        v => type_error("function", v, 1)
+    }
+
+}
+
+pub fn mgi_resume(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.is_empty() || args.len() > 2 {
+        return arity_error(Arity::Between(1, 2), args);
+    }
+
+    // Whether it yielded or finished, the caller just gets the value.  It can check the status.
+    match args[0].as_ref() {
+        LispValue::Coroutine(co) => match co.resume(args.get(1).cloned())? {
+            Outcome::Yielded(v) | Outcome::Done(v) => Ok(v)
+        },
+        v => type_error("coroutine", v, 1)
+    }
+
+}
+
+pub fn mgi_coroutine_status(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match args[0].as_ref() {
+        LispValue::Coroutine(co) => Ok(Rc::new(LispValue::Symbol(co.status().into()))),
+        v => type_error("coroutine", v, 1)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use eval::{ErrorKind, LispValue};
+    use exec::LispProgram;
+    use test_util::run;
+
+    #[test]
+    fn test_raise_uncaught() {
+        let mut prog = LispProgram::standard();
+        let e = run(&mut prog, "(raise 'oops)").unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::UserRaised(Rc::new(LispValue::Symbol("oops".into()))));
+    }
+
+    #[test]
+    fn test_guard() {
+        let mut prog = LispProgram::standard();
+        assert_eq!(
+            run(&mut prog, "(guard (e (#t (cons 'caught e))) (+ 1 (raise 5)))").unwrap(),
+            Rc::new(LispValue::Cons(Rc::new(LispValue::Symbol("caught".into())), Rc::new(LispValue::Integer(5)))));
+        assert_eq!(run(&mut prog, "(guard (e (else 0)) 1 2)").unwrap(), Rc::new(LispValue::Integer(2)));
+        assert_eq!(
+            run(&mut prog, "(guard (e ((condition? e) (condition-kind e))) (/ 1 0))").unwrap(),
+            Rc::new(LispValue::Symbol("divide-by-zero".into())));
+        assert_eq!(
+            run(&mut prog, "(guard (e ((condition? e) (condition-message e))) (error \"bad thing\"))").unwrap(),
+            Rc::new(LispValue::Str("bad thing".into())));
+    }
+
+    #[test]
+    fn test_guard_no_match_reraises() {
+        let mut prog = LispProgram::standard();
+        let e = run(&mut prog, "(guard (e ((condition? e) 1)) (raise 2))").unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::UserRaised(Rc::new(LispValue::Integer(2))));
+        let e = run(&mut prog, "(guard (e (#f 1)) (guard (e2 ((condition? e2) (raise e2))) nope))").unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::UnboundName("nope".into()));
+    }
+
+    #[test]
+    fn test_dynamic_wind() {
+        let mut prog = LispProgram::standard();
+        assert_eq!(
+            run(&mut prog, "(dynamic-wind (lambda () 1) (lambda () 2) (lambda () 3))").unwrap(),
+            Rc::new(LispValue::Integer(2)));
+
+        // The after thunk still runs when the body raises, we can tell because its error wins.
+        assert_eq!(
+            run(&mut prog, "(guard (e (#t e)) (dynamic-wind (lambda () 1) (lambda () (raise 'body)) (lambda () (raise 'after))))").unwrap(),
+            Rc::new(LispValue::Symbol("after".into())));
+        assert_eq!(
+            run(&mut prog, "(guard (e (#t e)) (dynamic-wind (lambda () 1) (lambda () (raise 'body)) (lambda () 3)))").unwrap(),
+            Rc::new(LispValue::Symbol("body".into())));
+    }
+
+    #[test]
+    fn test_coroutines() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define gen (make-coroutine (lambda (x) (+ x (yield (+ x 1)) (yield 0)))))").unwrap();
+        assert_eq!(run(&mut prog, "(resume gen 10)").unwrap(), Rc::new(LispValue::Integer(11)));
+        assert_eq!(run(&mut prog, "(coroutine-status gen)").unwrap(), Rc::new(LispValue::Symbol("suspended".into())));
+        assert_eq!(run(&mut prog, "(resume gen 5)").unwrap(), Rc::new(LispValue::Integer(0)));
+        assert_eq!(run(&mut prog, "(resume gen 7)").unwrap(), Rc::new(LispValue::Integer(22)));
+        assert_eq!(run(&mut prog, "(coroutine-status gen)").unwrap(), Rc::new(LispValue::Symbol("dead".into())));
+        assert!(run(&mut prog, "(resume gen 1)").is_err());
+
+        // Without a value to resume with, the function isn't given one.
+        run(&mut prog, "(define bare (make-coroutine (lambda () (+ 1 (yield 1)))))").unwrap();
+        assert_eq!(run(&mut prog, "(resume bare)").unwrap(), Rc::new(LispValue::Integer(1)));
+        assert!(run(&mut prog, "(resume bare)").is_err());
+    }
+
+    #[test]
+    fn test_yield_errors() {
+        let mut prog = LispProgram::standard();
+        assert!(run(&mut prog, "(yield 1)").is_err());
+        assert!(run(&mut prog, "(resume (make-coroutine (lambda () `(a ,(yield 1)))))").is_err());
+        run(&mut prog, "(define bad (make-coroutine (lambda () (begin (yield 1) (raise 'oops)))))").unwrap();
+        run(&mut prog, "(resume bad)").unwrap();
+        let e = run(&mut prog, "(resume bad)").unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::UserRaised(Rc::new(LispValue::Symbol("oops".into()))));
+        assert_eq!(run(&mut prog, "(coroutine-status bad)").unwrap(), Rc::new(LispValue::Symbol("dead".into())));
+    }
+
+}
@@ -1,164 +1,255 @@
-
-/*
- * This is needed since we don't actually directly use most of these functions.  Arguably they
- * could be moved to their own crate
- */
-#![allow(dead_code)]
-
+use std::cell::RefCell;
 use std::rc::*;
 
-use eval::{LispValue, Env, eval, EvalError, LispFunction};
+use convert;
+use eval::{LispValue, Env, eval, Closure, EvalError, LispFunction, Params};
 use parser::sexp::Sexp;
 
 use intrinsics::*;
 
-pub fn mgi_lambda(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+pub fn mgi_lambda(args: &[Sexp], env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
 
     if args.len() != 3 {
-        return intrinsic_error("invalid form for lambda, needs 2 expressions");
+        return form_error("lambda needs 2 expressions");
     }
 
-    let mut names = Vec::new();
-    match &args[1] {
-        &Sexp::List(ref list) => for sexp in list {
-            match sexp {
-                &Sexp::Symbol(ref s) => names.push(s.clone()),
-                _ => return intrinsic_error("invalid form for lambda, malformed argument names"),
-            }
+    let params = match &args[1] {
+        Sexp::List(list, _) => parse_params(list, None)?,
+        Sexp::DottedList(list, rest) => parse_params(list, Some(rest))?,
+        Sexp::Symbol(s) => Params {
+            rest: Some(s.clone()),
+            .. Params::required(Vec::new())
         },
-        _ => return intrinsic_error("invalid form for lambda, first argument not list"),
+        _ => return form_error("lambda parameters are not a list"),
     };
 
     // TODO Make this pretty to read.
-    Ok(Rc::new(LispValue::Func(Box::new(LispFunction::Lambda(Rc::new(args[2].clone()), env.clone(), names)))))
+    Ok(Rc::new(LispValue::Func(Box::new(LispFunction::Lambda(Closure {
+        name: None,
+        params: params,
+        body: Rc::new(args[2].clone()),
+        env: env.clone()
+    })))))
 
 }
 
-pub fn mgi_variadic_lambda(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+#[inline]
+fn malformed_params() -> EvalError {
+    ErrorKind::InvalidForm("malformed lambda parameter names".into()).into()
+}
 
-    if args.len() != 2 {
-        return intrinsic_error("invalid form for vlambda, needs 1 expression");
+fn param_name(s: &Sexp) -> Result<String, EvalError> {
+    match s {
+        Sexp::Symbol(s) => Ok(s.clone()),
+        _ => Err(malformed_params())
     }
+}
 
-    // Interestingly, this is even simpler than the non-variadic one.  Although the line is longer.
-    Ok(Rc::new(LispValue::Func(Box::new(LispFunction::VariadicLambda(Rc::new(args[1].clone()), env.clone())))))
-
+/// Parses a parameter that can have a default, like `c` or `(c 10)`.
+fn param_with_default(s: &Sexp) -> Result<(String, Option<Rc<Sexp>>), EvalError> {
+    match s {
+        Sexp::Symbol(s) => Ok((s.clone(), None)),
+        Sexp::List(l, _) if l.len() == 2 => Ok((param_name(&l[0])?, Some(Rc::new(l[1].clone())))),
+        _ => Err(malformed_params())
+    }
 }
 
-pub fn mgi_define(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+/*
+ * Parameter lists look like `(a b #:optional c (d 2) #:key e (f 3) . rest)`.  The sections have
+ * to come in that order but any of them can be left out.
+ */
+fn parse_params(list: &[Sexp], rest: Option<&Sexp>) -> Result<Params, EvalError> {
+
+    #[derive(PartialEq, PartialOrd)]
+    enum Section { Required, Optional, Key }
+
+    let mut params = Params::required(Vec::new());
+    let mut section = Section::Required;
+    for sexp in list {
+        match sexp {
+            Sexp::Keyword(k) => {
+                let next = match k.as_str() {
+                    "optional" => Section::Optional,
+                    "key" => Section::Key,
+                    _ => return Err(malformed_params())
+                };
+                if next <= section {
+                    return Err(malformed_params());
+                }
+                section = next;
+            },
+            _ => match section {
+                Section::Required => params.required.push(param_name(sexp)?),
+                Section::Optional => params.optional.push(param_with_default(sexp)?),
+                Section::Key => params.keys.push(param_with_default(sexp)?)
+            }
+        }
+    }
 
-    if args.len() != 3 {
-        return intrinsic_error("invalid form for define, needs 2 expressions");
+    if let Some(r) = rest {
+        params.rest = Some(param_name(r)?);
     }
 
-    let binding = match &args[1] {
-        &Sexp::Symbol(ref s) => s.clone(),
-        _ => return intrinsic_error("invalid form for define, first argument is not symbol"),
-    };
+    Ok(params)
 
-    let value = eval(&args[2], &mut env.clone())?;
+}
 
-    // This is where we actually mutate the environment.
-    env.add_binding(binding, value);
+pub fn mgi_variadic_lambda(args: &[Sexp], env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
 
-    Ok(Rc::new(LispValue::Null))
+    // The name for the arguments list is optional, like `(vlambda xs body)`.
+    let (name, body) = match args.len() {
+        2 => (String::from("args"), &args[1]),
+        3 => (param_name(&args[1])?, &args[2]),
+        _ => return form_error("vlambda needs 1 or 2 expressions")
+    };
+
+    // This is really just a lambda that only has a rest parameter.
+    let params = Params {
+        rest: Some(name),
+        .. Params::required(Vec::new())
+    };
+    Ok(Rc::new(LispValue::Func(Box::new(LispFunction::Lambda(Closure {
+        name: None,
+        params: params,
+        body: Rc::new(body.clone()),
+        env: env.clone()
+    })))))
 
 }
 
-pub fn mgi_if(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+pub fn mgi_typeof(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
 
-    if args.len() != 4 {
-        return intrinsic_error("invalid form for if, needs 3 expressions");
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
     }
 
-    let cond = match eval(&args[1], env) {
-        Ok(v) => v,
-        e @ Err(_) => return e
-    };
-
-    match cond.as_ref() {
-        &LispValue::Boolean(true) => eval(&args[2], env),
-        &LispValue::Boolean(false) => eval(&args[3], env),
-        _ => return intrinsic_error("conditional expression in if is non-boolean")
-    }
+    // Records are named after their type, rather than all being "record".
+    Ok(Rc::new(LispValue::Symbol(match args[0].as_ref() {
+        LispValue::Record(r) => r.rtype.name.clone(),
+        v => v.type_name().into()
+    })))
 
 }
 
-pub fn mgi_typeof(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
-
-    use eval::LispValue::*;
+pub fn mgi_hard_clone(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    if args.len() != 1 {
+        arity_error(Arity::Exactly(1), args)
+    } else {
+        Ok(args[0].hard_clone())
+    }
+}
 
+pub fn mgi_quote(args: &[Sexp], _env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
     if args.len() != 2 {
-        return intrinsic_error("typeof takes 1 argument");
+        form_error("quote needs 1 expression")
+    } else {
+        Ok(convert::sexp_to_value(&args[1]))
     }
+}
 
-    // TODO Does this need to be cloned?
-    Ok(Rc::new(Symbol(match (eval(&args[1], &mut env.clone())?).as_ref() {
-        &Null => "null",
-        &Integer(_) => "integer",
-        &ByteArray(_) => "bytearray",
-        &Str(_) => "str",
-        &Boolean(_) => "bool",
-        &Symbol(_) => "symbol",
-        &Cons(_, _) => "cons",
-        &Func(_) => "function"
-    }.into())))
+pub fn mgi_quasiquote(args: &[Sexp], env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+    if args.len() != 2 {
+        form_error("quasiquote needs 1 expression")
+    } else {
+        quasiquote_level(&args[1], 1, env)
+    }
+}
 
+/// Returns the inner expression if the sexp is a `(name x)` form.
+#[inline]
+fn unwrap_form<'a>(s: &'a Sexp, name: &str) -> Option<&'a Sexp> {
+    match s {
+        Sexp::List(l, _) if l.len() == 2 => match &l[0] {
+            Sexp::Symbol(n) if n == name => Some(&l[1]),
+            _ => None
+        },
+        _ => None
+    }
 }
 
-pub fn mgi_begin(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+/// Builds a 2-element list like `(unquote x)`, for when we're too deep to evaluate the form.
+#[inline]
+fn make_form(name: &str, v: Rc<LispValue>) -> Rc<LispValue> {
+    Rc::new(LispValue::Cons(
+        Rc::new(LispValue::Symbol(String::from(name))),
+        Rc::new(LispValue::Cons(v, Rc::new(LispValue::Null)))))
+}
 
-    let mut last = None;
+/*
+ * The depth is how many quasiquotes deep we are, only unquotes at depth 1 actually get evaluated
+ * and the ones deeper than that get copied with their inner forms one level shallower.
+ */
+fn quasiquote_level(s: &Sexp, depth: usize, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+
+    if let Some(inner) = unwrap_form(s, "unquote") {
+        return if depth == 1 {
+            eval(inner, &mut env.clone())
+        } else {
+            Ok(make_form("unquote", quasiquote_level(inner, depth - 1, env)?))
+        };
+    }
 
-    /*
-     * We just have to evaluate each of the entries in order.  Using the same env because that's
-     * just how the semantics of `begin` works.
-     */
-    for i in args {
-        last = Some(eval(i, env)?);
+    if let Some(inner) = unwrap_form(s, "quasiquote") {
+        return Ok(make_form("quasiquote", quasiquote_level(inner, depth + 1, env)?));
     }
 
-    match last {
-        Some(v) => Ok(v),
-        None => Ok(Rc::new(LispValue::Null))
+    if unwrap_form(s, "unquote-splicing").is_some() {
+        return form_error("unquote-splicing not in list");
     }
 
-}
+    let (l, tail) = match s {
+        Sexp::List(l, _) => (&l[..], None),
+        Sexp::DottedList(l, t) => (&l[..], Some(t.as_ref().clone())),
+        Sexp::Vector(l) => {
+            let items = quasiquote_items(l, depth, env)?;
+            return Ok(Rc::new(LispValue::Vector(RefCell::new(items))));
+        },
+        _ => return Ok(convert::sexp_to_value(s))
+    };
 
-pub fn mgi_hard_clone(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
-    if args.len() != 2 {
-        intrinsic_error("invalid form for hardclone, needs 1 expression")
+    // The reader turns `(a . ,b)` into `(a unquote b)`, so we have to pull that back apart.
+    let n = l.len();
+    let (l, tail) = if tail.is_none() && n >= 3 && l[n - 2] == Sexp::symb_str("unquote") {
+        (&l[..n - 2], Some(Sexp::list(l[n - 2..].to_vec())))
     } else {
-        eval(&args[1], env).map(|v| v.as_ref().hard_clone())
+        (l, tail)
+    };
+
+    let mut c = match tail {
+        Some(t) => quasiquote_level(&t, depth, env)?,
+        None => Rc::new(LispValue::Null)
+    };
+    for v in quasiquote_items(l, depth, env)?.into_iter().rev() {
+        c = Rc::new(LispValue::Cons(v, c));
     }
-}
+    Ok(c)
 
-#[inline]
-fn convert_sexp_to_lispvalue_literally(s: &Sexp) -> Rc<LispValue> {
-    use parser::sexp::Sexp::*;
-    Rc::new(match s {
-        &Null => LispValue::Null,
-        &Integer(i) => LispValue::Integer(i),
-        &ByteArray(ref a) => LispValue::ByteArray(a.clone()),
-        &Str(ref s) => LispValue::Str(s.clone()),
-        &Boolean(b) => LispValue::Boolean(b),
-        &Symbol(ref s) => LispValue::Symbol(s.clone()),
-        &List(ref l) => {
-            // Do some weird reverse-traversal to build up the list structure.
-            let mut c = Rc::new(LispValue::Null);
-            for i in 1..l.len() {
-                let iv = &l[l.len() - i - 1];
-                c = Rc::new(LispValue::Cons(convert_sexp_to_lispvalue_literally(iv), c));
-            }
-            return c; // Not pretty, but works.
-        }
-    })
 }
 
-pub fn mgi_quote(args: &Vec<Sexp>, _env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
-    if args.len() != 2 {
-        intrinsic_error("invalid form for quote, needs 1 expression")
-    } else {
-        Ok(convert_sexp_to_lispvalue_literally(&args[1]))
+/// The elements of a list or vector in a quasiquote, with any `unquote-splicing` spliced in.
+fn quasiquote_items(l: &[Sexp], depth: usize, env: &mut Env) -> Result<Vec<Rc<LispValue>>, EvalError> {
+
+    let mut items = Vec::new();
+    for item in l.iter() {
+        match unwrap_form(item, "unquote-splicing") {
+            Some(inner) if depth == 1 => {
+                let mut cur = eval(inner, &mut env.clone())?;
+                loop {
+                    let next = match cur.as_ref() {
+                        LispValue::Cons(f, r) => {
+                            items.push(f.clone());
+                            r.clone()
+                        },
+                        LispValue::Null => break,
+                        _ => return Err(EvalError::msg("unquote-splicing expects a list"))
+                    };
+                    cur = next;
+                }
+            },
+            Some(inner) => items.push(make_form("unquote-splicing", quasiquote_level(inner, depth - 1, env)?)),
+            None => items.push(quasiquote_level(item, depth, env)?)
+        }
     }
+    Ok(items)
+
 }
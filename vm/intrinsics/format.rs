@@ -0,0 +1,238 @@
+use std::iter::Peekable;
+use std::rc::*;
+use std::str::Chars;
+
+use eval::*;
+use intrinsics::*;
+use intrinsics::string::to_radix;
+use printer;
+
+use eval::LispValue::*;
+
+/// A parameter before a directive, like the `5` and `'0` in `~5,'0d`.
+enum Param {
+    Number(usize),
+    Char(char)
+}
+
+fn format_error(msg: String) -> EvalError {
+    ErrorKind::InvalidArgument(msg).into()
+}
+
+fn read_params(chars: &mut Peekable<Chars>) -> Result<Vec<Option<Param>>, EvalError> {
+
+    let mut params = Vec::new();
+    loop {
+        let p = match chars.peek().cloned() {
+            Some('\'') => {
+                chars.next();
+                match chars.next() {
+                    Some(c) => Some(Param::Char(c)),
+                    None => return Err(format_error("format string ends in the middle of a directive".into()))
+                }
+            },
+            Some(c) if c.is_ascii_digit() => {
+                let mut n = 0usize;
+                while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    n = n.saturating_mul(10).saturating_add(d as usize);
+                    chars.next();
+                }
+                Some(Param::Number(n))
+            },
+            _ => None
+        };
+        params.push(p);
+        if chars.peek() == Some(&',') {
+            chars.next();
+        } else {
+            return Ok(params);
+        }
+    }
+
+}
+
+fn number_param(params: &[Option<Param>], i: usize, default: usize) -> Result<usize, EvalError> {
+    match params.get(i) {
+        Some(&Some(Param::Number(n))) => Ok(n),
+        Some(&Some(Param::Char(c))) => Err(format_error(format!("expected a number in a format directive, not '{}", c))),
+        _ => Ok(default)
+    }
+}
+
+fn char_param(params: &[Option<Param>], i: usize) -> Result<char, EvalError> {
+    match params.get(i) {
+        Some(&Some(Param::Char(c))) => Ok(c),
+        Some(&Some(Param::Number(n))) => Err(format_error(format!("expected a 'char in a format directive, not {}", n))),
+        _ => Ok(' ')
+    }
+}
+
+/// Pads `s` out to `width` chars, on the left if `left` or otherwise on the right.
+fn pad(out: &mut String, s: &str, width: usize, fill: char, left: bool) {
+    let fill: String = ::std::iter::repeat_n(fill, width.saturating_sub(s.chars().count())).collect();
+    if left {
+        out.push_str(&fill);
+        out.push_str(s);
+    } else {
+        out.push_str(s);
+        out.push_str(&fill);
+    }
+}
+
+/*
+ * `(format "~a is ~5,'0d" x n)`, a lot like Common Lisp but it always gives back a string.  The
+ * directives are:
+ *
+ *   ~a  display the argument, like strings without quotes
+ *   ~s  write the argument, so strings are quoted
+ *   ~d ~b ~o ~x  an integer in decimal, binary, octal, or hex
+ *   ~r  an integer in the radix given by the first parameter, like `~36r`
+ *   ~c  a char
+ *   ~%  a newline
+ *   ~~  a tilde
+ *
+ * `~a` and `~s` take a minimum width and pad char, and pad on the right unless there's a `@`
+ * like `~10@a`.  The integer ones take the same (after the radix for `~r`) and pad on the left,
+ * and `@` makes them always show the sign.
+ */
+pub fn mgi_format(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.is_empty() {
+        return arity_error(Arity::AtLeast(1), args);
+    }
+
+    let fmt = str_arg(&args[0], 1)?;
+    let mut next = 1;
+    let mut out = String::new();
+    let mut chars = fmt.chars().peekable();
+
+    while let Some(c) = chars.next() {
+
+        if c != '~' {
+            out.push(c);
+            continue;
+        }
+
+        let params = read_params(&mut chars)?;
+        let at = chars.peek() == Some(&'@');
+        if at {
+            chars.next();
+        }
+        let directive = match chars.next() {
+            Some(d) => d.to_ascii_lowercase(),
+            None => return Err(format_error("format string ends in the middle of a directive".into()))
+        };
+
+        match directive {
+            '%' => {
+                out.push('\n');
+                continue;
+            },
+            '~' => {
+                out.push('~');
+                continue;
+            },
+            _ => {}
+        }
+
+        let arg = match args.get(next) {
+            Some(a) => a,
+            None => return Err(format_error(format!("format string wants more than {} arguments", args.len() - 1)))
+        };
+        next += 1;
+
+        match directive {
+            'a' | 's' => {
+                let s = if directive == 'a' { printer::display(arg) } else { printer::write(arg) };
+                pad(&mut out, &s, number_param(&params, 0, 0)?, char_param(&params, 1)?, at);
+            },
+            'd' | 'b' | 'o' | 'x' | 'r' => {
+                let (radix, rest) = match directive {
+                    'd' => (10, 0),
+                    'b' => (2, 0),
+                    'o' => (8, 0),
+                    'x' => (16, 0),
+                    _ => (number_param(&params, 0, 10)?, 1)
+                };
+                if !(2..=36).contains(&radix) {
+                    return Err(format_error(format!("radix {} isn't between 2 and 36", radix)));
+                }
+                let n = match arg.as_ref() {
+                    &Integer(n) => n,
+                    v => return type_error("integer", v, next)
+                };
+                let mut s = to_radix(n, radix as u32);
+                if at && n >= 0 {
+                    s.insert(0, '+');
+                }
+                pad(&mut out, &s, number_param(&params, rest, 0)?, char_param(&params, rest + 1)?, true);
+            },
+            'c' => match arg.as_ref() {
+                &Char(c) => out.push(c),
+                v => return type_error("char", v, next)
+            },
+            d => return Err(format_error(format!("unknown format directive ~{}", d)))
+        }
+
+    }
+
+    if next < args.len() {
+        return Err(format_error(format!("format string only uses {} of the {} arguments", next - 1, args.len() - 1)));
+    }
+
+    Ok(Rc::new(Str(out)))
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use eval::{ErrorKind, LispValue};
+    use exec::LispProgram;
+    use test_util::run;
+
+    fn format(src: &str) -> String {
+        match run(&mut LispProgram::standard(), src).unwrap().as_ref() {
+            LispValue::Str(s) => s.clone(),
+            v => panic!("format gave back {:?}", v)
+        }
+    }
+
+    #[test]
+    fn test_directives() {
+        assert_eq!(format("(format \"plain\")"), "plain");
+        assert_eq!(format("(format \"~a and ~s\" \"x\" \"x\")"), "x and \"x\"");
+        assert_eq!(format("(format \"~a~%~~\" '(1 \"two\" #\\3))"), "(1 two 3)\n~");
+        assert_eq!(format("(format \"~s\" '(1 \"two\" #\\3))"), "(1 \"two\" #\\3)");
+        assert_eq!(format("(format \"~d ~b ~o ~x ~X\" -12 5 8 255 255)"), "-12 101 10 ff ff");
+        assert_eq!(format("(format \"~36r ~3r\" 35 5)"), "z 12");
+        assert_eq!(format("(format \"~c\" #\\z)"), "z");
+    }
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(format("(format \"[~5d]\" 42)"), "[   42]");
+        assert_eq!(format("(format \"[~5,'0d]\" 42)"), "[00042]");
+        assert_eq!(format("(format \"[~@d ~@d]\" 3 -3)"), "[+3 -3]");
+        assert_eq!(format("(format \"[~8,'0b]\" 5)"), "[00000101]");
+        assert_eq!(format("(format \"[~16,4,'0r]\" 255)"), "[00ff]");
+        assert_eq!(format("(format \"[~5a]\" 'ab)"), "[ab   ]");
+        assert_eq!(format("(format \"[~5@a]\" 'ab)"), "[   ab]");
+        assert_eq!(format("(format \"[~4,'.s]\" \"\u{e9}\")"), "[\"\u{e9}\".]");
+        assert_eq!(format("(format \"[~1d]\" 123)"), "[123]");
+    }
+
+    #[test]
+    fn test_format_errors() {
+        let mut prog = LispProgram::standard();
+        assert!(run(&mut prog, "(format \"~a ~a\" 1)").is_err());
+        assert!(run(&mut prog, "(format \"~a\" 1 2)").is_err());
+        assert!(run(&mut prog, "(format \"~q\" 1)").is_err());
+        assert!(run(&mut prog, "(format \"~\")").is_err());
+        assert!(run(&mut prog, "(format \"~37r\" 1)").is_err());
+        assert_eq!(
+            run(&mut prog, "(format \"~a ~d\" 1 \"x\")").unwrap_err().kind(),
+            &ErrorKind::TypeError { expected: "integer".into(), got: "str".into(), arg_index: 3 });
+    }
+
+}
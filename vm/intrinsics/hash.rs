@@ -0,0 +1,204 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::*;
+
+use convert;
+use eval::*;
+use intrinsics::*;
+
+type Map = RefCell<HashMap<HashKey, Rc<LispValue>>>;
+
+/// The keys and values of a map, in no particular order.
+pub type Entries = Vec<(Rc<LispValue>, Rc<LispValue>)>;
+
+/// Checks that the first argument is a hash map, so the others can be looked at.
+fn map_arg(args: &[Rc<LispValue>]) -> Result<&Map, EvalError> {
+    match args[0].as_ref() {
+        LispValue::HashMap(m) => Ok(m),
+        v => Err(ErrorKind::TypeError { expected: "hashmap".into(), got: v.type_name().into(), arg_index: 1 }.into())
+    }
+}
+
+pub fn mgi_hash(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if !args.len().is_multiple_of(2) {
+        return Err(ErrorKind::InvalidArgument("hash needs a value for every key".into()).into());
+    }
+
+    let mut m = HashMap::new();
+    for i in (0..args.len()).step_by(2) {
+        m.insert(key_arg(&args[i], i + 1)?, args[i + 1].clone());
+    }
+    Ok(Rc::new(LispValue::HashMap(RefCell::new(m))))
+
+}
+
+pub fn mgi_hash_ref(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 && args.len() != 3 {
+        return arity_error(Arity::Between(2, 3), args);
+    }
+
+    // Missing keys are an error unless there's a default to give back instead.
+    let key = key_arg(&args[1], 2)?;
+    match (map_arg(args)?.borrow().get(&key), args.get(2)) {
+        (Some(v), _) => Ok(v.clone()),
+        (None, Some(d)) => Ok(d.clone()),
+        (None, None) => Err(ErrorKind::InvalidArgument(format!("no value for key {:#}", args[1])).into())
+    }
+
+}
+
+pub fn mgi_hash_set(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 3 {
+        return arity_error(Arity::Exactly(3), args);
+    }
+
+    let key = key_arg(&args[1], 2)?;
+    map_arg(args)?.borrow_mut().insert(key, args[2].clone());
+    Ok(Rc::new(LispValue::Null))
+
+}
+
+pub fn mgi_hash_remove(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 {
+        return arity_error(Arity::Exactly(2), args);
+    }
+
+    let key = key_arg(&args[1], 2)?;
+    map_arg(args)?.borrow_mut().remove(&key);
+    Ok(Rc::new(LispValue::Null))
+
+}
+
+pub fn mgi_hash_has_key(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 {
+        return arity_error(Arity::Exactly(2), args);
+    }
+
+    let key = key_arg(&args[1], 2)?;
+    Ok(Rc::new(LispValue::Boolean(map_arg(args)?.borrow().contains_key(&key))))
+
+}
+
+pub fn mgi_hash_count(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(Rc::new(LispValue::Integer(map_arg(args)?.borrow().len() as i64)))
+
+}
+
+pub fn mgi_hash_keys(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(convert::vec_to_list(map_arg(args)?.borrow().keys().map(HashKey::to_value).collect()))
+
+}
+
+pub fn mgi_hash_values(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(convert::vec_to_list(map_arg(args)?.borrow().values().cloned().collect()))
+
+}
+
+/// Gives the entries as a list of `(key . value)` pairs, for iterating over.
+pub fn mgi_hash_to_list(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(convert::vec_to_list(map_arg(args)?.borrow().iter()
+        .map(|(k, v)| Rc::new(LispValue::Cons(k.to_value(), v.clone())))
+        .collect()))
+
+}
+
+/*
+ * Checks the arguments of `hash-for-each`, and gives back its entries and the function to call
+ * with them.  The machine does the calling, so the function can yield or jump like anywhere else.
+ * This is a copy of the entries, so the function is allowed to change the map.
+ */
+pub fn for_each_args(args: &[Rc<LispValue>]) -> Result<(Entries, Rc<LispValue>), EvalError> {
+
+    if args.len() != 2 {
+        return Err(ErrorKind::ArityMismatch { expected: Arity::Exactly(2), got: args.len() }.into());
+    }
+
+    let entries = map_arg(args)?.borrow().iter().map(|(k, v)| (k.to_value(), v.clone())).collect();
+    match args[1].as_ref() {
+        LispValue::Func(_) => Ok((entries, args[1].clone())),
+        v => Err(ErrorKind::TypeError { expected: "function".into(), got: v.type_name().into(), arg_index: 2 }.into())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use eval::{ErrorKind, LispValue};
+    use exec::LispProgram;
+    use test_util::{run, int};
+
+    #[test]
+    fn test_hashes() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define h {a 1 \"a\" 2 3 4 #t 5})").unwrap();
+        assert_eq!(run(&mut prog, "(typeof h)").unwrap(), Rc::new(LispValue::Symbol("hashmap".into())));
+        assert_eq!(run(&mut prog, "(hash-ref h 'a)").unwrap(), int(1));
+        assert_eq!(run(&mut prog, "(hash-ref h \"a\")").unwrap(), int(2));
+        assert_eq!(run(&mut prog, "(hash-ref h 3)").unwrap(), int(4));
+        assert_eq!(run(&mut prog, "(hash-ref h #t)").unwrap(), int(5));
+        assert_eq!(run(&mut prog, "(hash-ref h 'nope 0)").unwrap(), int(0));
+        run(&mut prog, "(hash-set! h 'b (+ 1 1))").unwrap();
+        run(&mut prog, "(hash-remove! h 3)").unwrap();
+        assert_eq!(run(&mut prog, "(hash-count h)").unwrap(), int(4));
+        assert_eq!(run(&mut prog, "(hash-has-key? h 3)").unwrap(), Rc::new(LispValue::Boolean(false)));
+        assert_eq!(run(&mut prog, "(hash-ref (hash 'x 1 'y 2) 'y)").unwrap(), int(2));
+        assert_eq!(run(&mut prog, "(apply + (hash-values {a 1 b 2 c 3}))").unwrap(), int(6));
+        assert_eq!(run(&mut prog, "(first (hash-keys {only 1}))").unwrap(), Rc::new(LispValue::Symbol("only".into())));
+        assert_eq!(run(&mut prog, "(rest (first (hash->list {k 7})))").unwrap(), int(7));
+    }
+
+    #[test]
+    fn test_hash_for_each() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define h {a 1 b 2})").unwrap();
+        run(&mut prog, "(define out {})").unwrap();
+        run(&mut prog, "(hash-for-each h (lambda (k v) (hash-set! out k (* v 10))))").unwrap();
+        assert_eq!(run(&mut prog, "(hash-ref out 'b)").unwrap(), int(20));
+
+        // The function runs on the caller's machine, so it can yield out of a coroutine.
+        run(&mut prog, "(define co (make-coroutine (lambda (x) (begin (hash-for-each {a 1} (lambda (k v) (yield v))) 'done))))").unwrap();
+        assert_eq!(run(&mut prog, "(resume co 0)").unwrap(), int(1));
+        assert_eq!(run(&mut prog, "(resume co 0)").unwrap(), Rc::new(LispValue::Symbol("done".into())));
+        assert_eq!(
+            run(&mut prog, "(hash-for-each h 1)").unwrap_err().kind(),
+            &ErrorKind::TypeError { expected: "function".into(), got: "integer".into(), arg_index: 2 });
+    }
+
+    #[test]
+    fn test_hash_errors() {
+        let mut prog = LispProgram::standard();
+        assert!(run(&mut prog, "(hash-ref {} 'a)").is_err());
+        assert_eq!(
+            run(&mut prog, "(hash-set! {} '(a) 1)").unwrap_err().kind(),
+            &ErrorKind::TypeError { expected: "hashable key".into(), got: "cons".into(), arg_index: 2 });
+        assert!(run(&mut prog, "(hash 'a)").is_err());
+    }
+
+}
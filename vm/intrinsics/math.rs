@@ -1,10 +1,3 @@
-
-/*
- * This is needed since we don't actually directly use most of these functions.  Arguably they
- * could be moved to their own crate
- */
-#![allow(dead_code)]
-
 use std::rc::*;
 
 use eval::*;
@@ -12,79 +5,211 @@ use intrinsics::*;
 
 use eval::LispValue::*;
 
-pub fn mgi_plus(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+/// Makes sure that all of the arguments are integers.
+fn integers(args: &[Rc<LispValue>]) -> Result<Vec<i64>, EvalError> {
 
-    if args.len() != 3 {
-        return intrinsic_error("invalid form for '+', needs 2 expressions");
+    let mut nums = Vec::with_capacity(args.len());
+
+    for (i, v) in args.iter().enumerate() {
+        match v.as_ref() {
+            &Integer(n) => nums.push(n),
+            v => return Err(ErrorKind::TypeError { expected: "integer".into(), got: v.type_name().into(), arg_index: i + 1 }.into())
+        }
     }
 
-    // TODO Do these need to be cloned?
-    let av = eval(&args[1], &mut env.clone())?;
-    let bv = eval(&args[2], &mut env.clone())?;
+    Ok(nums)
+
+}
+
+#[inline]
+fn overflow() -> Result<Rc<LispValue>, EvalError> {
+    Err(ErrorKind::Overflow.into())
+}
 
-    match (av.as_ref(), bv.as_ref()) {
-        (&Integer(a), &Integer(b)) => Ok(Rc::new(Integer(a + b))),
-        (&Integer(_), _) => intrinsic_error("argument 2 for '+' is not an integer"),
-        (_, &Integer(_)) => intrinsic_error("argument 1 for '+' is not an integer"),
-        (_, _) => intrinsic_error("arguments 1 and 2 for '+' are not integers")
+/// Folds the arguments together, bailing out if it overflows.
+fn fold_checked(init: i64, nums: &[i64], op: fn(i64, i64) -> Option<i64>) -> Result<Rc<LispValue>, EvalError> {
+    let mut acc = init;
+    for n in nums {
+        acc = match op(acc, *n) {
+            Some(v) => v,
+            None => return overflow()
+        };
     }
+    Ok(Rc::new(Integer(acc)))
+}
 
+pub fn mgi_plus(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    let nums = integers(args)?;
+    fold_checked(0, &nums, i64::checked_add)
 }
 
-pub fn mgi_subtract(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+pub fn mgi_subtract(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    let nums = integers(args)?;
 
-    if args.len() != 3 {
-        return intrinsic_error("invalid form for '-', needs 2 expressions");
+    // With one argument it's negation, otherwise subtract the rest from the first.
+    match nums.len() {
+        0 => arity_error(Arity::AtLeast(1), args),
+        1 => fold_checked(0, &nums, i64::checked_sub),
+        _ => fold_checked(nums[0], &nums[1..], i64::checked_sub)
     }
 
-    // TODO Do these need to be cloned?
-    let av = eval(&args[1], &mut env.clone())?;
-    let bv = eval(&args[2], &mut env.clone())?;
+}
+
+pub fn mgi_multiply(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    let nums = integers(args)?;
+    fold_checked(1, &nums, i64::checked_mul)
+}
+
+pub fn mgi_divide(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
 
-    match (av.as_ref(), bv.as_ref()) {
-        (&Integer(a), &Integer(b)) => Ok(Rc::new(Integer(a - b))),
-        (&Integer(_), _) => intrinsic_error("argument 2 for '-' is not an integer"),
-        (_, &Integer(_)) => intrinsic_error("argument 1 for '-' is not an integer"),
-        (_, _) => intrinsic_error("arguments 1 and 2 for '-' are not integers")
+    let nums = integers(args)?;
+
+    if nums.iter().skip(if nums.len() == 1 { 0 } else { 1 }).any(|n| *n == 0) {
+        return Err(ErrorKind::DivideByZero.into());
+    }
+
+    // TODO This will change eventually, since we're adding floating-point ops later.
+    match nums.len() {
+        0 => arity_error(Arity::AtLeast(1), args),
+        1 => fold_checked(1, &nums, i64::checked_div),
+        _ => fold_checked(nums[0], &nums[1..], i64::checked_div)
     }
 
 }
 
-pub fn mgi_multiply(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+pub fn mgi_min(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    match integers(args)?.into_iter().min() {
+        Some(n) => Ok(Rc::new(Integer(n))),
+        None => arity_error(Arity::AtLeast(1), args)
+    }
+}
 
-    if args.len() != 3 {
-        return intrinsic_error("invalid form for '*', needs 2 expressions");
+pub fn mgi_max(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    match integers(args)?.into_iter().max() {
+        Some(n) => Ok(Rc::new(Integer(n))),
+        None => arity_error(Arity::AtLeast(1), args)
     }
+}
+
+pub fn mgi_abs(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
 
-    // TODO Do these need to be cloned?
-    let av = eval(&args[1], &mut env.clone())?;
-    let bv = eval(&args[2], &mut env.clone())?;
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
 
-    match (av.as_ref(), bv.as_ref()) {
-        (&Integer(a), &Integer(b)) => Ok(Rc::new(Integer(a * b))),
-        (&Integer(_), _) => intrinsic_error("argument 2 for '*' is not an integer"),
-        (_, &Integer(_)) => intrinsic_error("argument 1 for '*' is not an integer"),
-        (_, _) => intrinsic_error("arguments 1 and 2 for '*' are not integers")
+    match integers(args)?[0].checked_abs() {
+        Some(n) => Ok(Rc::new(Integer(n))),
+        None => overflow()
     }
 
 }
 
-pub fn mgi_divide(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+pub fn mgi_expt(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
 
-    if args.len() != 3 {
-        return intrinsic_error("invalid form for '/', needs 2 expressions");
+    if args.len() != 2 {
+        return arity_error(Arity::Exactly(2), args);
     }
 
-    // TODO Do these need to be cloned?
-    let av = eval(&args[1], &mut env.clone())?;
-    let bv = eval(&args[2], &mut env.clone())?;
+    let nums = integers(args)?;
+    if nums[1] < 0 {
+        return Err(ErrorKind::InvalidArgument("negative exponent".into()).into());
+    }
 
-    // TODO This will change eventually, since we're adding floating-point ops later.
-    match (av.as_ref(), bv.as_ref()) {
-        (&Integer(a), &Integer(b)) => Ok(Rc::new(Integer(a / b))),
-        (&Integer(_), _) => intrinsic_error("argument 2 for '/' is not an integer"),
-        (_, &Integer(_)) => intrinsic_error("argument 1 for '/' is not an integer"),
-        (_, _) => intrinsic_error("arguments 1 and 2 for '/' are not integers")
+    if nums[1] > u32::MAX as i64 {
+        return overflow();
+    }
+
+    match nums[0].checked_pow(nums[1] as u32) {
+        Some(n) => Ok(Rc::new(Integer(n))),
+        None => overflow()
+    }
+
+}
+
+fn gcd(a: i64, b: i64) -> Option<i64> {
+    let (mut a, mut b) = (a.checked_abs()?, b.checked_abs()?);
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    Some(a)
+}
+
+fn lcm(a: i64, b: i64) -> Option<i64> {
+    if a == 0 || b == 0 {
+        return Some(0);
+    }
+    (a / gcd(a, b)?).checked_mul(b)?.checked_abs()
+}
+
+pub fn mgi_gcd(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    let nums = integers(args)?;
+    fold_checked(0, &nums, gcd)
+}
+
+pub fn mgi_lcm(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    let nums = integers(args)?;
+    fold_checked(1, &nums, lcm)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use parser;
+    use eval::{Arity, ErrorKind, EvalError, LispValue};
+    use exec::LispProgram;
+
+    fn run(src: &str) -> Result<Rc<LispValue>, EvalError> {
+        let toks = parser::lex(&String::from(src)).unwrap();
+        let sexp = parser::parse(&mut toks.into_iter().peekable()).unwrap();
+        LispProgram::standard().exec(&sexp)
+    }
+
+    fn int(src: &str) -> i64 {
+        match run(src).unwrap().as_ref() {
+            &LispValue::Integer(n) => n,
+            v => panic!("{} gave {:?}", src, v)
+        }
+    }
+
+    #[test]
+    fn test_variadic() {
+        assert_eq!(int("(+)"), 0);
+        assert_eq!(int("(+ 1 2 3 4)"), 10);
+        assert_eq!(int("(*)"), 1);
+        assert_eq!(int("(* 2 3 4)"), 24);
+        assert_eq!(int("(- 5)"), -5);
+        assert_eq!(int("(- 10 1 2)"), 7);
+        assert_eq!(int("(/ 100 5 2)"), 10);
+        assert_eq!(int("(/ 1)"), 1);
+        assert_eq!(int("(/ 2)"), 0);
+    }
+
+    #[test]
+    fn test_others() {
+        assert_eq!(int("(min 3 -1 2)"), -1);
+        assert_eq!(int("(max 3 -1 2)"), 3);
+        assert_eq!(int("(abs -4)"), 4);
+        assert_eq!(int("(expt 2 10)"), 1024);
+        assert_eq!(int("(gcd 12 18 -8)"), 2);
+        assert_eq!(int("(gcd)"), 0);
+        assert_eq!(int("(lcm 4 6)"), 12);
+        assert_eq!(int("(lcm)"), 1);
+    }
+
+    #[test]
+    fn test_errors() {
+        let e = run("(+ 1 2 #t)").unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::TypeError { expected: "integer".into(), got: "bool".into(), arg_index: 3 });
+        assert_eq!(format!("{}", e), "argument 3 should be integer, got bool");
+        assert_eq!(e.trace()[0].function, "+");
+        assert_eq!(run("(/ 1 0)").unwrap_err().kind(), &ErrorKind::DivideByZero);
+        assert_eq!(run("(-)").unwrap_err().kind(), &ErrorKind::ArityMismatch { expected: Arity::AtLeast(1), got: 0 });
+        assert!(run("(expt 2 -1)").is_err());
+        assert_eq!(run("(* 9223372036854775807 2)").unwrap_err().kind(), &ErrorKind::Overflow);
     }
 
 }
@@ -2,31 +2,74 @@ use std::rc::*;
 
 use parser::sexp::Sexp;
 
-use eval::LispValue;
+use eval::{HashKey, LispValue};
 use eval::Env;
-use eval::EvalError;
+use eval::{Arity, ErrorKind, EvalError};
+use machine::Native;
 
+pub mod bytes;
+pub mod chars;
+pub mod control;
 pub mod core;
+pub mod format;
+pub mod hash;
 pub mod lists;
 pub mod math;
+pub mod persist;
+pub mod record;
 pub mod string;
+pub mod vector;
 
-pub type IntrinsicImpl = Fn(&Vec<Sexp>, &mut Env) -> Result<Rc<LispValue>, EvalError>;
+/// A special form, which gets the whole form unevaluated (the function itself is `args[0]`) and
+/// the environment it's in.
+pub type IntrinsicImpl = dyn Fn(&[Sexp], &mut Env) -> Result<Rc<LispValue>, EvalError>;
+
+/// An ordinary function, which only gets its arguments after they've been evaluated.
+pub type ProcedureImpl = dyn Fn(&[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError>;
+
+#[derive(Clone)]
+pub enum IntrinsicFunc {
+
+    /// Does its own evaluation.  Anything it evaluates happens in a separate machine, so
+    /// continuations can only escape out of it and not come back in.
+    Form(Rc<IntrinsicImpl>),
+
+    Procedure(Rc<ProcedureImpl>),
+
+    /// Built into the evaluator, for things that need to control what gets evaluated next.
+    Native(Native)
+
+}
 
 #[derive(Clone)]
 pub struct MgIntrinsic {
     pub name: String,
-    pub func: Rc<IntrinsicImpl>
+    pub func: IntrinsicFunc
 }
 
 impl MgIntrinsic {
-    #[allow(unreachable_code)]
+
     pub fn new(name: String, func: &'static IntrinsicImpl) -> MgIntrinsic {
         MgIntrinsic {
             name: name,
-            func: Rc::new(func)
+            func: IntrinsicFunc::Form(Rc::new(func))
         }
     }
+
+    pub fn procedure(name: String, func: &'static ProcedureImpl) -> MgIntrinsic {
+        MgIntrinsic {
+            name: name,
+            func: IntrinsicFunc::Procedure(Rc::new(func))
+        }
+    }
+
+    pub fn native(name: String, n: Native) -> MgIntrinsic {
+        MgIntrinsic {
+            name: name,
+            func: IntrinsicFunc::Native(n)
+        }
+    }
+
 }
 
 impl ::std::fmt::Debug for MgIntrinsic {
@@ -45,5 +88,56 @@ impl Eq for MgIntrinsic {}
 
 #[inline]
 pub fn intrinsic_error(err: &str) -> Result<Rc<LispValue>, EvalError> {
-    Err(EvalError::Msg(format!("error: {}", err)))
+    Err(EvalError::msg(err))
+}
+
+/// For malformed special forms, like `(if a b)`.
+#[inline]
+pub fn form_error(err: &str) -> Result<Rc<LispValue>, EvalError> {
+    Err(ErrorKind::InvalidForm(err.into()).into())
+}
+
+/// For calls with the wrong number of arguments.
+#[inline]
+pub fn arity_error(expected: Arity, args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    Err(ErrorKind::ArityMismatch { expected: expected, got: args.len() }.into())
+}
+
+/// Checks that argument `arg_index` is an integer that indexes into something of length `len`.
+pub fn index_arg(v: &LispValue, len: usize, arg_index: usize) -> Result<usize, EvalError> {
+    match v {
+        &LispValue::Integer(i) if i >= 0 && (i as u64) < len as u64 => Ok(i as usize),
+        &LispValue::Integer(i) => Err(ErrorKind::OutOfRange { index: i, len: len }.into()),
+        _ => Err(ErrorKind::TypeError { expected: "integer".into(), got: v.type_name().into(), arg_index: arg_index }.into())
+    }
+}
+
+/// Checks that argument `arg_index` is a string.
+pub fn str_arg(v: &LispValue, arg_index: usize) -> Result<&str, EvalError> {
+    match v {
+        LispValue::Str(s) => Ok(s),
+        v => Err(ErrorKind::TypeError { expected: "str".into(), got: v.type_name().into(), arg_index: arg_index }.into())
+    }
+}
+
+/// Checks that argument `arg_index` can be used as a key in a map.
+pub fn key_arg(v: &LispValue, arg_index: usize) -> Result<HashKey, EvalError> {
+    match HashKey::from_value(v) {
+        Some(k) => Ok(k),
+        None => Err(ErrorKind::TypeError { expected: "hashable key".into(), got: v.type_name().into(), arg_index: arg_index }.into())
+    }
+}
+
+/// Like `index_arg`, but for the ends of ranges, so `len` itself is allowed too.
+pub fn bound_arg(v: &LispValue, len: usize, arg_index: usize) -> Result<usize, EvalError> {
+    match v {
+        &LispValue::Integer(i) if i as u64 == len as u64 => Ok(len),
+        v => index_arg(v, len, arg_index)
+    }
+}
+
+/// For when argument `arg_index` (starting at 1) isn't what it should be.
+#[inline]
+pub fn type_error(expected: &str, got: &LispValue, arg_index: usize) -> Result<Rc<LispValue>, EvalError> {
+    Err(ErrorKind::TypeError { expected: expected.into(), got: got.type_name().into(), arg_index: arg_index }.into())
 }
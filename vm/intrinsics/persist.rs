@@ -0,0 +1,284 @@
+use std::rc::*;
+
+use convert;
+use eval::*;
+use intrinsics::*;
+use persist::PMap;
+
+use eval::LispValue::*;
+
+fn collection_error(v: &LispValue) -> Result<Rc<LispValue>, EvalError> {
+    type_error("pvector or pmap", v, 1)
+}
+
+pub fn mgi_pvector(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    Ok(Rc::new(PersistentVector(args.iter().cloned().collect())))
+}
+
+pub fn mgi_pmap(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if !args.len().is_multiple_of(2) {
+        return Err(ErrorKind::InvalidArgument("pmap needs a value for every key".into()).into());
+    }
+
+    let mut m = PMap::new();
+    for i in (0..args.len()).step_by(2) {
+        m = m.insert(key_arg(&args[i], i + 1)?, args[i + 1].clone());
+    }
+    Ok(Rc::new(PersistentMap(m)))
+
+}
+
+/// Makes a persistent copy of a vector, hash map, or list.
+pub fn mgi_persistent(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match args[0].as_ref() {
+        Vector(v) => Ok(Rc::new(PersistentVector(v.borrow().iter().cloned().collect()))),
+        HashMap(m) => Ok(Rc::new(PersistentMap(m.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect()))),
+        PersistentVector(_) | PersistentMap(_) => Ok(args[0].clone()),
+        v => match convert::list_to_vec(&args[0]) {
+            Some(items) => Ok(Rc::new(PersistentVector(items.into_iter().collect()))),
+            None => type_error("vector, hashmap, or list", v, 1)
+        }
+    }
+
+}
+
+/// `(get coll key [default])`, where the key is an index for pvectors.
+pub fn mgi_get(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 && args.len() != 3 {
+        return arity_error(Arity::Between(2, 3), args);
+    }
+
+    let found = match args[0].as_ref() {
+        PersistentVector(v) => match (args[1].as_ref(), args.get(2)) {
+            (&Integer(i), Some(_)) if i < 0 || i as u64 >= v.len() as u64 => None,
+            (k, _) => v.get(index_arg(k, v.len(), 2)?).cloned()
+        },
+        PersistentMap(m) => {
+            let k = key_arg(args[1].as_ref(), 2)?;
+            match (m.get(&k), args.get(2)) {
+                (Some(v), _) => Some(v.clone()),
+                (None, Some(_)) => None,
+                (None, None) => return Err(ErrorKind::InvalidArgument(format!("no value for key {:#}", args[1])).into())
+            }
+        },
+        v => return collection_error(v)
+    };
+    Ok(found.unwrap_or_else(|| args[2].clone()))
+
+}
+
+/// `(assoc coll key val ...)`, giving a copy with each key set.  On pvectors the index can be one
+/// past the end, which adds to it.
+pub fn mgi_assoc(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() < 3 || args.len().is_multiple_of(2) {
+        return Err(ErrorKind::InvalidArgument("assoc needs a collection and then keys with values".into()).into());
+    }
+
+    match args[0].as_ref() {
+        PersistentVector(v) => {
+            let mut v = v.clone();
+            for i in (1..args.len()).step_by(2) {
+                v = match args[i].as_ref() {
+                    &Integer(n) if n >= 0 && n as usize == v.len() => v.push(args[i + 1].clone()),
+                    _ => v.set(index_arg(args[i].as_ref(), v.len(), i + 1)?, args[i + 1].clone()).unwrap()
+                };
+            }
+            Ok(Rc::new(PersistentVector(v)))
+        },
+        PersistentMap(m) => {
+            let mut m = m.clone();
+            for i in (1..args.len()).step_by(2) {
+                m = m.insert(key_arg(args[i].as_ref(), i + 1)?, args[i + 1].clone());
+            }
+            Ok(Rc::new(PersistentMap(m)))
+        },
+        v => collection_error(v)
+    }
+
+}
+
+pub fn mgi_dissoc(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.is_empty() {
+        return arity_error(Arity::AtLeast(1), args);
+    }
+
+    let mut m = match args[0].as_ref() {
+        PersistentMap(m) => m.clone(),
+        v => return type_error("pmap", v, 1)
+    };
+    for (i, k) in args.iter().enumerate().skip(1) {
+        m = m.remove(&key_arg(k.as_ref(), i + 1)?);
+    }
+    Ok(Rc::new(PersistentMap(m)))
+
+}
+
+/// Adds items to the end of a pvector, or `(key . value)` pairs to a pmap.
+pub fn mgi_conj(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.is_empty() {
+        return arity_error(Arity::AtLeast(1), args);
+    }
+
+    match args[0].as_ref() {
+        PersistentVector(v) => Ok(Rc::new(PersistentVector(args[1..].iter().fold(v.clone(), |v, e| v.push(e.clone()))))),
+        PersistentMap(m) => {
+            let mut m = m.clone();
+            for (i, pair) in args.iter().enumerate().skip(1) {
+                m = match pair.as_ref() {
+                    Cons(k, v) => m.insert(key_arg(k.as_ref(), i + 1)?, v.clone()),
+                    v => return type_error("cons", v, i + 1)
+                };
+            }
+            Ok(Rc::new(PersistentMap(m)))
+        },
+        v => collection_error(v)
+    }
+
+}
+
+pub fn mgi_pop(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match args[0].as_ref() {
+        PersistentVector(v) => match v.pop() {
+            Some(v) => Ok(Rc::new(PersistentVector(v))),
+            None => Err(ErrorKind::InvalidArgument("can't pop an empty pvector".into()).into())
+        },
+        v => type_error("pvector", v, 1)
+    }
+
+}
+
+/// How many items are in any kind of vector or map.
+pub fn mgi_count(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    let n = match args[0].as_ref() {
+        Vector(v) => v.borrow().len(),
+        HashMap(m) => m.borrow().len(),
+        PersistentVector(v) => v.len(),
+        PersistentMap(m) => m.len(),
+        v => return type_error("collection", v, 1)
+    };
+    Ok(Rc::new(Integer(n as i64)))
+
+}
+
+pub fn mgi_contains(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 {
+        return arity_error(Arity::Exactly(2), args);
+    }
+
+    let has = match (args[0].as_ref(), args[1].as_ref()) {
+        (PersistentVector(v), &Integer(i)) => i >= 0 && (i as u64) < v.len() as u64,
+        (PersistentVector(_), k) => return type_error("integer", k, 2),
+        (PersistentMap(m), k) => m.contains_key(&key_arg(k, 2)?),
+        (v, _) => return collection_error(v)
+    };
+    Ok(Rc::new(Boolean(has)))
+
+}
+
+pub fn mgi_pvector_to_list(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match args[0].as_ref() {
+        PersistentVector(v) => Ok(convert::vec_to_list(v.iter().cloned().collect())),
+        v => type_error("pvector", v, 1)
+    }
+
+}
+
+/// Gives the entries as a list of `(key . value)` pairs, like `hash->list`.
+pub fn mgi_pmap_to_list(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match args[0].as_ref() {
+        PersistentMap(m) => Ok(convert::vec_to_list(m.iter().map(|(k, v)| Rc::new(Cons(k.to_value(), v.clone()))).collect())),
+        v => type_error("pmap", v, 1)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use eval::{ErrorKind, LispValue};
+    use exec::LispProgram;
+    use test_util::{run, int, boolean};
+
+    #[test]
+    fn test_pvectors() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define a (pvector 1 2 3))").unwrap();
+        run(&mut prog, "(define b (assoc (conj a 4 5) 0 10))").unwrap();
+        assert_eq!(run(&mut prog, "(typeof a)").unwrap(), Rc::new(LispValue::Symbol("pvector".into())));
+        assert_eq!(run(&mut prog, "(count a)").unwrap(), int(3));
+        assert_eq!(run(&mut prog, "(get a 0)").unwrap(), int(1));
+        assert_eq!(run(&mut prog, "(count b)").unwrap(), int(5));
+        assert_eq!(run(&mut prog, "(get b 0)").unwrap(), int(10));
+        assert_eq!(run(&mut prog, "(get b 4)").unwrap(), int(5));
+        assert_eq!(run(&mut prog, "(get b 9 'none)").unwrap(), Rc::new(LispValue::Symbol("none".into())));
+        assert_eq!(run(&mut prog, "(count (assoc a 3 4))").unwrap(), int(4));
+        assert_eq!(run(&mut prog, "(count (pop b))").unwrap(), int(4));
+        assert_eq!(run(&mut prog, "(apply + (pvector->list b))").unwrap(), int(24));
+        assert_eq!(run(&mut prog, "(contains? a 2)").unwrap(), boolean(true));
+        assert_eq!(run(&mut prog, "(contains? a 3)").unwrap(), boolean(false));
+        assert_eq!(run(&mut prog, "(get b 3)").unwrap(), int(4));
+        assert_eq!(run(&mut prog, "(get b 5)").unwrap_err().kind(), &ErrorKind::OutOfRange { index: 5, len: 5 });
+    }
+
+    #[test]
+    fn test_pmaps() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define a (pmap 'x 1 \"y\" 2))").unwrap();
+        run(&mut prog, "(define b (dissoc (assoc a 'z 3 'x 10) \"y\"))").unwrap();
+        assert_eq!(run(&mut prog, "(typeof a)").unwrap(), Rc::new(LispValue::Symbol("pmap".into())));
+        assert_eq!(run(&mut prog, "(get a 'x)").unwrap(), int(1));
+        assert_eq!(run(&mut prog, "(get b 'x)").unwrap(), int(10));
+        assert_eq!(run(&mut prog, "(count a)").unwrap(), int(2));
+        assert_eq!(run(&mut prog, "(count b)").unwrap(), int(2));
+        assert_eq!(run(&mut prog, "(contains? b \"y\")").unwrap(), boolean(false));
+        assert_eq!(run(&mut prog, "(get b \"y\" 0)").unwrap(), int(0));
+        assert_eq!(run(&mut prog, "(get (conj a '(w . 4)) 'w)").unwrap(), int(4));
+        assert_eq!(run(&mut prog, "(count (persistent (pmap->list b)))").unwrap(), int(2));
+        assert!(run(&mut prog, "(get b \"y\")").is_err());
+        assert!(run(&mut prog, "(assoc a '(1) 2)").is_err());
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define v #(1 2))").unwrap();
+        run(&mut prog, "(define snap (persistent v))").unwrap();
+        run(&mut prog, "(vector-set! v 0 5)").unwrap();
+        assert_eq!(run(&mut prog, "(get snap 0)").unwrap(), int(1));
+        assert_eq!(run(&mut prog, "(get (persistent {a 1}) 'a)").unwrap(), int(1));
+        assert_eq!(run(&mut prog, "(count (persistent '(1 2 3)))").unwrap(), int(3));
+    }
+
+}
@@ -0,0 +1,185 @@
+use std::cell::RefCell;
+use std::rc::*;
+
+use eval::*;
+use intrinsics::*;
+use parser::sexp::Sexp;
+
+fn symbol(s: &Sexp, what: &str) -> Result<String, EvalError> {
+    match s {
+        Sexp::Symbol(name) => Ok(name.clone()),
+        _ => Err(ErrorKind::InvalidForm(format!("define-record-type needs a symbol for the {}", what)).into())
+    }
+}
+
+fn bind(env: &mut Env, name: String, func: Rc<ProcedureImpl>) {
+    let i = MgIntrinsic { name: name.clone(), func: IntrinsicFunc::Procedure(func) };
+    env.add_binding(name, Rc::new(LispValue::Func(Box::new(LispFunction::Intrinsic(i)))));
+}
+
+/// Checks that the first argument is a record of the right type.
+fn record_arg<'a>(rtype: &Rc<RecordType>, args: &'a [Rc<LispValue>]) -> Result<&'a Record, EvalError> {
+    match args[0].as_ref() {
+        LispValue::Record(r) if r.is_a(rtype) => Ok(r),
+        LispValue::Record(r) => Err(ErrorKind::TypeError { expected: rtype.name.clone(), got: r.rtype.name.clone(), arg_index: 1 }.into()),
+        v => Err(ErrorKind::TypeError { expected: rtype.name.clone(), got: v.type_name().into(), arg_index: 1 }.into())
+    }
+}
+
+/*
+ * Like R7RS, `(define-record-type point (make-point x y) point? (x point-x set-point-x!) (y point-y))`.
+ * After the type name comes the constructor and which fields it takes, then the predicate, then a
+ * `(field accessor [modifier])` for each field.  Fields the constructor doesn't take start null.
+ */
+pub fn mgi_define_record_type(args: &[Sexp], env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() < 4 {
+        return form_error("define-record-type needs a name, constructor, and predicate");
+    }
+
+    let name = symbol(&args[1], "type name")?;
+    let (ctor, ctor_fields) = match &args[2] {
+        Sexp::List(l, _) if !l.is_empty() => {
+            let fields = l[1..].iter().map(|f| symbol(f, "constructor's fields")).collect::<Result<Vec<_>, _>>()?;
+            (symbol(&l[0], "constructor")?, fields)
+        },
+        _ => return form_error("define-record-type needs a constructor like (make-thing field ...)")
+    };
+    let pred = symbol(&args[3], "predicate")?;
+
+    let mut fields = Vec::new();
+    let mut procs = Vec::new();
+    for spec in &args[4..] {
+        let parts = match spec {
+            Sexp::List(l, _) if l.len() == 2 || l.len() == 3 => l,
+            _ => return form_error("record fields look like (field accessor [modifier])")
+        };
+        let field = symbol(&parts[0], "field name")?;
+        if fields.contains(&field) {
+            return Err(ErrorKind::InvalidForm(format!("record field {} is there twice", field)).into());
+        }
+        procs.push((fields.len(), symbol(&parts[1], "accessor")?, match parts.get(2) {
+            Some(m) => Some(symbol(m, "modifier")?),
+            None => None
+        }));
+        fields.push(field);
+    }
+
+    // Where each of the constructor's arguments goes.
+    let mut slots = Vec::new();
+    for f in &ctor_fields {
+        match fields.iter().position(|g| g == f) {
+            Some(i) if !slots.contains(&i) => slots.push(i),
+            Some(_) => return Err(ErrorKind::InvalidForm(format!("constructor takes {} twice", f)).into()),
+            None => return Err(ErrorKind::InvalidForm(format!("constructor takes {}, which isn't a field", f)).into())
+        }
+    }
+
+    let rtype = Rc::new(RecordType { name: name, fields: fields });
+
+    let rt = rtype.clone();
+    bind(env, ctor, Rc::new(move |args: &[Rc<LispValue>]| {
+        if args.len() != slots.len() {
+            return arity_error(Arity::Exactly(slots.len()), args);
+        }
+        let mut vals: Vec<_> = rt.fields.iter().map(|_| Rc::new(LispValue::Null)).collect();
+        for (&i, v) in slots.iter().zip(args) {
+            vals[i] = v.clone();
+        }
+        Ok(Rc::new(LispValue::Record(Record { rtype: rt.clone(), fields: RefCell::new(vals) })))
+    }));
+
+    let rt = rtype.clone();
+    bind(env, pred, Rc::new(move |args: &[Rc<LispValue>]| {
+        if args.len() != 1 {
+            return arity_error(Arity::Exactly(1), args);
+        }
+        Ok(Rc::new(LispValue::Boolean(match args[0].as_ref() {
+            LispValue::Record(r) => r.is_a(&rt),
+            _ => false
+        })))
+    }));
+
+    for (i, accessor, modifier) in procs {
+
+        let rt = rtype.clone();
+        bind(env, accessor, Rc::new(move |args: &[Rc<LispValue>]| {
+            if args.len() != 1 {
+                return arity_error(Arity::Exactly(1), args);
+            }
+            Ok(record_arg(&rt, args)?.fields.borrow()[i].clone())
+        }));
+
+        if let Some(m) = modifier {
+            let rt = rtype.clone();
+            bind(env, m, Rc::new(move |args: &[Rc<LispValue>]| {
+                if args.len() != 2 {
+                    return arity_error(Arity::Exactly(2), args);
+                }
+                record_arg(&rt, args)?.fields.borrow_mut()[i] = args[1].clone();
+                Ok(Rc::new(LispValue::Null))
+            }));
+        }
+
+    }
+
+    Ok(Rc::new(LispValue::Null))
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use eval::{ErrorKind, LispValue};
+    use exec::LispProgram;
+    use test_util::run;
+
+    fn point(prog: &mut LispProgram) {
+        run(prog, "(define-record-type point (make-point x y) point? (x point-x set-point-x!) (y point-y) (tag point-tag))").unwrap();
+    }
+
+    #[test]
+    fn test_records() {
+        let mut prog = LispProgram::standard();
+        point(&mut prog);
+        run(&mut prog, "(define p (make-point 1 2))").unwrap();
+        assert_eq!(run(&mut prog, "(typeof p)").unwrap(), Rc::new(LispValue::Symbol("point".into())));
+        assert_eq!(run(&mut prog, "(point? p)").unwrap(), Rc::new(LispValue::Boolean(true)));
+        assert_eq!(run(&mut prog, "(point? '(1 2))").unwrap(), Rc::new(LispValue::Boolean(false)));
+        assert_eq!(run(&mut prog, "(point-y p)").unwrap(), Rc::new(LispValue::Integer(2)));
+        assert_eq!(run(&mut prog, "(point-tag p)").unwrap(), Rc::new(LispValue::Null));
+        run(&mut prog, "(set-point-x! p 10)").unwrap();
+        assert_eq!(run(&mut prog, "(point-x p)").unwrap(), Rc::new(LispValue::Integer(10)));
+        assert_eq!(format!("{:?}", run(&mut prog, "p").unwrap()), "Record(point { x: Integer(10), y: Integer(2), tag: Null })");
+    }
+
+    #[test]
+    fn test_types_are_distinct() {
+        let mut prog = LispProgram::standard();
+        point(&mut prog);
+        run(&mut prog, "(define old (make-point 1 2))").unwrap();
+        point(&mut prog);
+        assert_eq!(run(&mut prog, "(point? old)").unwrap(), Rc::new(LispValue::Boolean(false)));
+        assert_eq!(
+            run(&mut prog, "(point-x old)").unwrap_err().kind(),
+            &ErrorKind::TypeError { expected: "point".into(), got: "point".into(), arg_index: 1 });
+        assert!(run(&mut prog, "(make-point 1)").is_err());
+
+        // Some other type of record is reported by its own name.
+        run(&mut prog, "(define-record-type q (mk-q a) q? (a q-a))").unwrap();
+        assert_eq!(
+            run(&mut prog, "(point-x (mk-q 1))").unwrap_err().kind(),
+            &ErrorKind::TypeError { expected: "point".into(), got: "q".into(), arg_index: 1 });
+    }
+
+    #[test]
+    fn test_bad_definitions() {
+        let mut prog = LispProgram::standard();
+        assert!(run(&mut prog, "(define-record-type p (make-p z) p? (x p-x))").is_err());
+        assert!(run(&mut prog, "(define-record-type p (make-p x) p? (x p-x) (x p-x2))").is_err());
+        assert!(run(&mut prog, "(define-record-type p make-p p?)").is_err());
+        assert!(run(&mut prog, "(define-record-type p (make-p) p? (x))").is_err());
+    }
+
+}
@@ -1,50 +1,363 @@
-
-/*
- * This is needed since we don't actually directly use most of these functions.  Arguably they
- * could be moved to their own crate
- */
-#![allow(dead_code)]
-
 use std::rc::*;
 
+use convert;
 use eval::*;
 use intrinsics::*;
 
 use eval::LispValue::*;
 
-pub fn mgi_str_len(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+pub fn mgi_str_len(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
 
-    if args.len() != 2 {
-        return intrinsic_error("str-len takes 1 argument");
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
     }
 
-    // TODO Does this need to be cloned?
-    match (eval(&args[1], &mut env.clone())?).as_ref() {
-        &Str(ref s) => Ok(Rc::new(Integer(s.len() as i64))),
-        _ => intrinsic_error("argument to str-len must be str")
+    match args[0].as_ref() {
+        Str(s) => Ok(Rc::new(Integer(s.len() as i64))),
+        v => type_error("str", v, 1)
     }
 
 }
 
-pub fn mgi_str_app(args: &Vec<Sexp>, env: &mut Env) -> Result<Rc<LispValue>, EvalError> {
+pub fn mgi_str_app(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
 
-    if args.len() != 3 {
-        return intrinsic_error("str-app takes 2 arguments");
+    if args.len() != 2 {
+        return arity_error(Arity::Exactly(2), args);
     }
 
-    // TODO Do these need to be cloned?
-    let av = eval(&args[1], &mut env.clone())?;
-    let bv = eval(&args[2], &mut env.clone())?;
-
-    match (av.as_ref(), bv.as_ref()) {
-        (&Str(ref a), &Str(ref b)) => Ok(Rc::new(Str({
+    match (args[0].as_ref(), args[1].as_ref()) {
+        (Str(a), Str(b)) => Ok(Rc::new(Str({
             let mut c = a.clone();
             c.push_str(b.as_str());
             c
         }))),
-        (&Str(_), _) => intrinsic_error("argument 2 for str-app is not a str"),
-        (_, &Str(_)) => intrinsic_error("argument 1 for str-app is not a str"),
-        (_, _) => intrinsic_error("arguments 1 and 2 for str-app are not str")
+        (Str(_), b) => type_error("str", b, 2),
+        (a, _) => type_error("str", a, 1)
+    }
+
+}
+
+/// Gets the char at some index, counting in chars rather than bytes.
+pub fn mgi_string_ref(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 {
+        return arity_error(Arity::Exactly(2), args);
+    }
+
+    match args[0].as_ref() {
+        Str(s) => {
+            let i = index_arg(args[1].as_ref(), s.chars().count(), 2)?;
+            Ok(Rc::new(Char(s.chars().nth(i).unwrap())))
+        },
+        v => type_error("str", v, 1)
+    }
+
+}
+
+pub fn mgi_string_length(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(Rc::new(Integer(str_arg(&args[0], 1)?.chars().count() as i64)))
+
+}
+
+pub fn mgi_string_append(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    let mut out = String::new();
+    for (i, v) in args.iter().enumerate() {
+        out.push_str(str_arg(v, i + 1)?);
+    }
+    Ok(Rc::new(Str(out)))
+}
+
+/// Where the char at index `i` starts, in bytes, or the end if it's one past the last char.
+fn byte_offset(s: &str, i: usize) -> usize {
+    s.char_indices().nth(i).map(|(b, _)| b).unwrap_or(s.len())
+}
+
+/// `(substring s start [end])`, counting in chars.
+pub fn mgi_substring(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 && args.len() != 3 {
+        return arity_error(Arity::Between(2, 3), args);
+    }
+
+    let s = str_arg(&args[0], 1)?;
+    let len = s.chars().count();
+    let start = bound_arg(&args[1], len, 2)?;
+    let end = match args.get(2) {
+        Some(e) => bound_arg(e, len, 3)?,
+        None => len
+    };
+    if end < start {
+        return Err(ErrorKind::InvalidArgument(format!("substring ends at {} before it starts at {}", end, start)).into());
+    }
+    Ok(Rc::new(Str(s[byte_offset(s, start)..byte_offset(s, end)].into())))
+
+}
+
+/// What to look for in a string, which can be a char or another string.
+fn needle_arg(v: &LispValue, arg_index: usize) -> Result<String, EvalError> {
+    match v {
+        Str(s) => Ok(s.clone()),
+        Char(c) => Ok(c.to_string()),
+        v => Err(ErrorKind::TypeError { expected: "str or char".into(), got: v.type_name().into(), arg_index: arg_index }.into())
+    }
+}
+
+/// The char index where the needle first shows up, or `#f` if it doesn't.
+pub fn mgi_string_index(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 {
+        return arity_error(Arity::Exactly(2), args);
+    }
+
+    let s = str_arg(&args[0], 1)?;
+    Ok(Rc::new(match s.find(needle_arg(&args[1], 2)?.as_str()) {
+        Some(b) => Integer(s[..b].chars().count() as i64),
+        None => Boolean(false)
+    }))
+
+}
+
+pub fn mgi_string_contains(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 {
+        return arity_error(Arity::Exactly(2), args);
+    }
+
+    Ok(Rc::new(Boolean(str_arg(&args[0], 1)?.contains(needle_arg(&args[1], 2)?.as_str()))))
+
+}
+
+/// `(string-split s [sep])`, which splits on runs of whitespace if there's no separator.
+pub fn mgi_string_split(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.is_empty() || args.len() > 2 {
+        return arity_error(Arity::Between(1, 2), args);
+    }
+
+    let s = str_arg(&args[0], 1)?;
+    let parts: Vec<&str> = match args.get(1) {
+        Some(sep) => {
+            let sep = needle_arg(sep, 2)?;
+            if sep.is_empty() {
+                return Err(ErrorKind::InvalidArgument("can't split on an empty string".into()).into());
+            }
+            s.split(sep.as_str()).collect()
+        },
+        None => s.split_whitespace().collect()
+    };
+    Ok(convert::vec_to_list(parts.into_iter().map(|p| Rc::new(Str(p.into()))).collect()))
+
+}
+
+/// `(string-join strs [sep])`, the other way around from `string-split`.
+pub fn mgi_string_join(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.is_empty() || args.len() > 2 {
+        return arity_error(Arity::Between(1, 2), args);
+    }
+
+    let items = match convert::list_to_vec(&args[0]) {
+        Some(items) => items,
+        None => return type_error("list", args[0].as_ref(), 1)
+    };
+    let sep = match args.get(1) {
+        Some(sep) => needle_arg(sep, 2)?,
+        None => String::new()
+    };
+
+    let mut parts = Vec::new();
+    for v in items.iter() {
+        match v.as_ref() {
+            Str(s) => parts.push(s.as_str()),
+            v => return Err(ErrorKind::InvalidArgument(format!("can only join strings, not {}", v.type_name())).into())
+        }
+    }
+    Ok(Rc::new(Str(parts.join(&sep))))
+
+}
+
+pub fn mgi_string_trim(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(Rc::new(Str(str_arg(&args[0], 1)?.trim().into())))
+
+}
+
+pub fn mgi_string_upcase(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(Rc::new(Str(str_arg(&args[0], 1)?.to_uppercase())))
+
+}
+
+pub fn mgi_string_downcase(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(Rc::new(Str(str_arg(&args[0], 1)?.to_lowercase())))
+
+}
+
+/// `(string-replace s from to)`, replacing every time `from` shows up.
+pub fn mgi_string_replace(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 3 {
+        return arity_error(Arity::Exactly(3), args);
+    }
+
+    let from = needle_arg(&args[1], 2)?;
+    if from.is_empty() {
+        return Err(ErrorKind::InvalidArgument("can't replace an empty string".into()).into());
+    }
+    Ok(Rc::new(Str(str_arg(&args[0], 1)?.replace(from.as_str(), needle_arg(&args[2], 3)?.as_str()))))
+
+}
+
+fn radix_arg(args: &[Rc<LispValue>], i: usize) -> Result<u32, EvalError> {
+    match args.get(i).map(|r| r.as_ref()) {
+        None => Ok(10),
+        Some(&Integer(r)) if (2..=36).contains(&r) => Ok(r as u32),
+        Some(&Integer(r)) => Err(ErrorKind::InvalidArgument(format!("radix {} isn't between 2 and 36", r)).into()),
+        Some(v) => Err(ErrorKind::TypeError { expected: "integer".into(), got: v.type_name().into(), arg_index: i + 1 }.into())
+    }
+}
+
+/// `(string->number s [radix])`, giving `#f` if it isn't a number.
+pub fn mgi_string_to_number(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.is_empty() || args.len() > 2 {
+        return arity_error(Arity::Between(1, 2), args);
+    }
+
+    let s = str_arg(&args[0], 1)?;
+    Ok(Rc::new(match i64::from_str_radix(s, radix_arg(args, 1)?) {
+        Ok(n) => Integer(n),
+        Err(_) => Boolean(false)
+    }))
+
+}
+
+/// `(number->string n [radix])`, with lowercase letters for digits past 9.
+pub fn mgi_number_to_string(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.is_empty() || args.len() > 2 {
+        return arity_error(Arity::Between(1, 2), args);
+    }
+
+    let n = match args[0].as_ref() {
+        &Integer(n) => n,
+        v => return type_error("integer", v, 1)
+    };
+    Ok(Rc::new(Str(to_radix(n, radix_arg(args, 1)?))))
+
+}
+
+pub fn to_radix(n: i64, radix: u32) -> String {
+
+    // Going through u64 so that the most negative number doesn't overflow.
+    let mut m = n.unsigned_abs();
+    let mut digits = Vec::new();
+    loop {
+        digits.push(::std::char::from_digit((m % radix as u64) as u32, radix).unwrap());
+        m /= radix as u64;
+        if m == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        digits.push('-');
+    }
+    digits.into_iter().rev().collect()
+
+}
+
+pub fn mgi_string_to_symbol(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(Rc::new(Symbol(str_arg(&args[0], 1)?.into())))
+
+}
+
+pub fn mgi_symbol_to_string(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match args[0].as_ref() {
+        Symbol(s) => Ok(Rc::new(Str(s.clone()))),
+        v => type_error("symbol", v, 1)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use eval::{ErrorKind, LispValue};
+    use exec::LispProgram;
+    use test_util::{run, int, boolean, string};
+
+    #[test]
+    fn test_unicode_lengths() {
+        let mut prog = LispProgram::standard();
+        assert_eq!(run(&mut prog, "(string-length \"h\u{e9}llo\")").unwrap(), int(5));
+        assert_eq!(run(&mut prog, "(str-len \"h\u{e9}llo\")").unwrap(), int(6));
+        assert_eq!(run(&mut prog, "(substring \"h\u{e9}llo\" 1 3)").unwrap(), string("\u{e9}l"));
+        assert_eq!(run(&mut prog, "(substring \"h\u{e9}llo\" 5)").unwrap(), string(""));
+        assert_eq!(run(&mut prog, "(string-index \"h\u{e9}llo\" #\\l)").unwrap(), int(2));
+        assert_eq!(run(&mut prog, "(string-index \"hello\" \"z\")").unwrap(), boolean(false));
+        assert_eq!(run(&mut prog, "(substring \"abc\" 0 4)").unwrap_err().kind(), &ErrorKind::OutOfRange { index: 4, len: 3 });
+        assert!(run(&mut prog, "(substring \"abc\" 2 1)").is_err());
+    }
+
+    #[test]
+    fn test_string_functions() {
+        let mut prog = LispProgram::standard();
+        assert_eq!(run(&mut prog, "(string-append)").unwrap(), string(""));
+        assert_eq!(run(&mut prog, "(string-append \"a\" \"b\" \"c\")").unwrap(), string("abc"));
+        assert_eq!(run(&mut prog, "(first (rest (string-split \" a  b \")))").unwrap(), string("b"));
+        assert_eq!(run(&mut prog, "(string-join (string-split \"a,b,,c\" #\\,) \"-\")").unwrap(), string("a-b--c"));
+        assert_eq!(run(&mut prog, "(string-join '(\"x\" \"y\"))").unwrap(), string("xy"));
+        assert_eq!(run(&mut prog, "(string-trim \"\t hi \\n\")").unwrap(), string("hi"));
+        assert_eq!(run(&mut prog, "(string-upcase \"stra\u{df}e\")").unwrap(), string("STRASSE"));
+        assert_eq!(run(&mut prog, "(string-downcase \"ABC\")").unwrap(), string("abc"));
+        assert_eq!(run(&mut prog, "(string-contains? \"hello\" \"ell\")").unwrap(), boolean(true));
+        assert_eq!(run(&mut prog, "(string-replace \"a.b.c\" \".\" \"::\")").unwrap(), string("a::b::c"));
+        assert!(run(&mut prog, "(string-join '(\"a\" 1))").is_err());
+        assert!(run(&mut prog, "(string-append \"a\" 1)").is_err());
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut prog = LispProgram::standard();
+        assert_eq!(run(&mut prog, "(string->number \"-42\")").unwrap(), int(-42));
+        assert_eq!(run(&mut prog, "(string->number \"ff\" 16)").unwrap(), int(255));
+        assert_eq!(run(&mut prog, "(string->number \"nope\")").unwrap(), boolean(false));
+        assert_eq!(run(&mut prog, "(number->string 255 16)").unwrap(), string("ff"));
+        assert_eq!(run(&mut prog, "(number->string -5 2)").unwrap(), string("-101"));
+        assert_eq!(run(&mut prog, "(number->string 0)").unwrap(), string("0"));
+        assert_eq!(run(&mut prog, "(string->symbol \"abc\")").unwrap(), Rc::new(LispValue::Symbol("abc".into())));
+        assert_eq!(run(&mut prog, "(symbol->string 'abc)").unwrap(), string("abc"));
+        assert!(run(&mut prog, "(number->string 1 37)").is_err());
+        assert_eq!(super::to_radix(i64::MIN, 16), "-8000000000000000");
     }
 
 }
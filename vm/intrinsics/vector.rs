@@ -0,0 +1,132 @@
+use std::cell::RefCell;
+use std::rc::*;
+
+use convert;
+use eval::*;
+use intrinsics::*;
+
+use eval::LispValue::*;
+
+/// Checks that the first argument is a vector, so the others can be looked at.
+fn vector_arg(args: &[Rc<LispValue>]) -> Result<&RefCell<Vec<Rc<LispValue>>>, EvalError> {
+    match args[0].as_ref() {
+        Vector(v) => Ok(v),
+        v => Err(ErrorKind::TypeError { expected: "vector".into(), got: v.type_name().into(), arg_index: 1 }.into())
+    }
+}
+
+pub fn mgi_vector(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+    Ok(Rc::new(Vector(RefCell::new(args.to_vec()))))
+}
+
+pub fn mgi_vector_ref(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 {
+        return arity_error(Arity::Exactly(2), args);
+    }
+
+    let v = vector_arg(args)?.borrow();
+    let i = index_arg(args[1].as_ref(), v.len(), 2)?;
+    Ok(v[i].clone())
+
+}
+
+pub fn mgi_vector_set(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 3 {
+        return arity_error(Arity::Exactly(3), args);
+    }
+
+    let mut v = vector_arg(args)?.borrow_mut();
+    let i = index_arg(args[1].as_ref(), v.len(), 2)?;
+    v[i] = args[2].clone();
+    Ok(Rc::new(Null))
+
+}
+
+pub fn mgi_vector_length(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(Rc::new(Integer(vector_arg(args)?.borrow().len() as i64)))
+
+}
+
+pub fn mgi_vector_push(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 2 {
+        return arity_error(Arity::Exactly(2), args);
+    }
+
+    vector_arg(args)?.borrow_mut().push(args[1].clone());
+    Ok(Rc::new(Null))
+
+}
+
+pub fn mgi_vector_to_list(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    Ok(convert::vec_to_list(vector_arg(args)?.borrow().clone()))
+
+}
+
+pub fn mgi_list_to_vector(args: &[Rc<LispValue>]) -> Result<Rc<LispValue>, EvalError> {
+
+    if args.len() != 1 {
+        return arity_error(Arity::Exactly(1), args);
+    }
+
+    match convert::list_to_vec(&args[0]) {
+        Some(items) => Ok(Rc::new(Vector(RefCell::new(items)))),
+        None => type_error("list", args[0].as_ref(), 1)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use eval::{ErrorKind, LispValue};
+    use exec::LispProgram;
+    use test_util::{run, int};
+
+    #[test]
+    fn test_vectors() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define v #(1 2 3))").unwrap();
+        assert_eq!(run(&mut prog, "(typeof v)").unwrap(), Rc::new(LispValue::Symbol("vector".into())));
+        assert_eq!(run(&mut prog, "(vector-ref v 1)").unwrap(), int(2));
+        run(&mut prog, "(vector-set! v 1 20)").unwrap();
+        run(&mut prog, "(vector-push! v 4)").unwrap();
+        assert_eq!(run(&mut prog, "(vector-length v)").unwrap(), int(4));
+        assert_eq!(run(&mut prog, "(vector-ref v 1)").unwrap(), int(20));
+        assert_eq!(run(&mut prog, "(first (rest (vector->list v)))").unwrap(), int(20));
+        assert_eq!(run(&mut prog, "(vector-ref (list->vector '(5 6)) 1)").unwrap(), int(6));
+        assert_eq!(run(&mut prog, "(vector-length (vector 1 (+ 1 1) 3))").unwrap(), int(3));
+    }
+
+    #[test]
+    fn test_vector_errors() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define v #(1 2 3))").unwrap();
+        assert_eq!(run(&mut prog, "(vector-ref v 3)").unwrap_err().kind(), &ErrorKind::OutOfRange { index: 3, len: 3 });
+        assert_eq!(run(&mut prog, "(vector-ref v -1)").unwrap_err().kind(), &ErrorKind::OutOfRange { index: -1, len: 3 });
+        assert!(run(&mut prog, "(vector-ref '(1) 0)").is_err());
+        assert!(run(&mut prog, "(list->vector 5)").is_err());
+    }
+
+    #[test]
+    fn test_literals_are_fresh() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define f (lambda () #(0)))").unwrap();
+        run(&mut prog, "(vector-set! (f) 0 1)").unwrap();
+        assert_eq!(run(&mut prog, "(vector-ref (f) 0)").unwrap(), int(0));
+    }
+
+}
@@ -1,5 +1,20 @@
+#![allow(
+    clippy::match_ref_pats,
+    clippy::redundant_field_names
+)]
+
 extern crate mglisp_parser as parser;
 
-mod eval;
-mod exec;
-mod intrinsics;
+pub mod convert;
+pub mod error;
+pub mod eval;
+pub mod exec;
+pub mod intrinsics;
+pub mod machine;
+pub mod persist;
+pub mod printer;
+
+#[cfg(test)]
+mod test_util;
+
+pub use exec::LispProgram;
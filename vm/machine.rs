@@ -0,0 +1,1023 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::rc::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use convert;
+use eval::{Closure, Env, LispFunction, LispValue};
+use error::{Arity, ErrorKind, EvalError, Frame};
+use intrinsics::{hash, IntrinsicFunc, MgIntrinsic};
+use parser::sexp::Sexp;
+use parser::span::Span;
+
+/*
+ * The evaluator keeps its own stack of frames instead of recursing in Rust, so that the rest of a
+ * computation is just data.  That's what lets `call/cc` grab it and jump back into it later, as
+ * many times as it wants.
+ *
+ * There's an environment "register" that each step evaluates in, and frames that evaluate things
+ * in a copy of it save the original to put back afterwards.  So `define` changes the environment
+ * of whatever it's directly in, the same as it always has.
+ */
+
+/// Special forms and functions that are part of the machine itself, because they decide what
+/// gets evaluated next.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Native {
+
+    // These get their arguments unevaluated.
+    Define,
+    If,
+    Begin,
+    Guard,
+
+    // And these get them evaluated, like normal functions.
+    Eval,
+    Apply,
+    DynamicWind,
+    CallCC,
+    Yield,
+    HashForEach
+
+}
+
+impl Native {
+
+    fn is_syntax(self) -> bool {
+        matches!(self, Native::Define | Native::If | Native::Begin | Native::Guard)
+    }
+
+}
+
+/// Somewhere inside of an S-expression, so frames can point into code without copying it.
+#[derive(Clone, Debug)]
+struct Code {
+    root: Rc<Sexp>,
+    path: Vec<usize>
+}
+
+impl Code {
+
+    fn new(root: Rc<Sexp>) -> Code {
+        Code {
+            root: root,
+            path: Vec::new()
+        }
+    }
+
+    fn get(&self) -> &Sexp {
+        let mut cur = self.root.as_ref();
+        for i in self.path.iter() {
+            cur = match cur {
+                Sexp::List(l, _) => &l[*i],
+                _ => unreachable!()
+            };
+        }
+        cur
+    }
+
+    fn child(&self, i: usize) -> Code {
+        let mut path = self.path.clone();
+        path.push(i);
+        Code {
+            root: self.root.clone(),
+            path: path
+        }
+    }
+
+    /// The elements, or nothing if it's not a list.
+    fn items(&self) -> &[Sexp] {
+        match self.get() {
+            Sexp::List(l, _) => l,
+            _ => &[]
+        }
+    }
+
+    fn site(&self) -> Option<Span> {
+        match self.get() {
+            Sexp::List(_, loc) => loc.0,
+            _ => None
+        }
+    }
+
+}
+
+/// A `dynamic-wind` that we're inside of.
+#[derive(Debug)]
+struct Winder {
+    before: Rc<LispValue>,
+    after: Rc<LispValue>
+}
+
+/// What to do with the value of whatever's being evaluated, once it's done.
+#[derive(Clone)]
+enum Kont {
+
+    /// Evaluating the head of a call to find out what it is.
+    Head { form: Code, env: Env },
+
+    /// Evaluating the arguments of a call, left to right.
+    Args { form: Code, func: Rc<LispValue>, values: Vec<Rc<LispValue>>, env: Env },
+
+    /// Running a lambda's body, goes back to the caller's environment afterwards.
+    Body { frame: Frame, env: Env },
+
+    /// Puts an environment back once something's done with another one.
+    Restore(Env),
+
+    Define { form: Code, env: Env },
+
+    If { form: Code },
+
+    /// The rest of a sequence of expressions, starting at `next`.
+    Begin { form: Code, next: usize },
+
+    Guard { form: Code, env: Env },
+
+    /// Trying the tests of a guard's clauses after it caught something.
+    Handler { form: Code, clause: usize, env: Env, err: EvalError },
+
+    WindBefore { winder: Rc<Winder>, thunk: Rc<LispValue> },
+
+    /// The body of a `dynamic-wind` is running.
+    Wound(Rc<Winder>),
+
+    /// The after thunk is running, and then we give back the body's value.
+    WindAfter(Rc<LispValue>),
+
+    /// Keeps unwinding once a `dynamic-wind` after thunk is done.
+    Reraise(EvalError),
+
+    /// Calling a function with each of a hash map's entries, starting at `next`.
+    ForEach { func: Rc<LispValue>, entries: Rc<hash::Entries>, next: usize }
+
+}
+
+/// The rest of a computation, as captured by `call/cc`.
+pub struct Continuation {
+    machine: usize,
+    stack: Vec<Kont>,
+    env: Env,
+    winders: Vec<Rc<Winder>>
+}
+
+impl fmt::Debug for Continuation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[continuation]")
+    }
+}
+
+impl PartialEq for Continuation {
+    fn eq(&self, other: &Self) -> bool {
+        ::std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Continuation {}
+
+enum Step {
+    Eval(Code),
+    Return(Rc<LispValue>),
+    Apply(Rc<LispValue>, Vec<Rc<LispValue>>, Option<Span>),
+    Raise(EvalError),
+    Yield(Rc<LispValue>)
+}
+
+/// How far a coroutine got when it was resumed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outcome {
+
+    /// It yielded this value, and can be resumed again later.
+    Yielded(Rc<LispValue>),
+
+    /// It returned this value, and it's finished now.
+    Done(Rc<LispValue>)
+
+}
+
+impl Outcome {
+
+    /// Only used by machines that aren't coroutines, since those can't yield.
+    fn value(self) -> Rc<LispValue> {
+        match self {
+            Outcome::Done(v) => v,
+            Outcome::Yielded(_) => unreachable!()
+        }
+    }
+
+}
+
+enum CoState {
+    Fresh(Rc<LispValue>),
+    Suspended(Machine),
+    Running,
+    Dead
+}
+
+/*
+ * A coroutine is its own machine that gets put aside whenever it yields, so resuming it just
+ * picks the stack back up where it was.  Nothing blocks while it's suspended, the host can resume
+ * it whenever it wants to.
+ */
+pub struct Coroutine {
+    state: RefCell<CoState>
+}
+
+impl Coroutine {
+
+    /// A coroutine that calls `func` with the first value it's resumed with.
+    pub fn new(func: Rc<LispValue>) -> Coroutine {
+        Coroutine {
+            state: RefCell::new(CoState::Fresh(func))
+        }
+    }
+
+    /// Runs the coroutine until it yields or returns.  Errors finish it too.
+    ///
+    /// The first time, the function only gets an argument if there is one.  After that, `yield`
+    /// returns it, or `()` without one.
+    pub fn resume(&self, v: Option<Rc<LispValue>>) -> Result<Outcome, EvalError> {
+
+        let state = mem::replace(&mut *self.state.borrow_mut(), CoState::Running);
+        let (mut m, step) = match state {
+            CoState::Fresh(f) => {
+                /* A closure brings the top level it was made in, so a jump back into it keeps that. */
+                let env = match f.as_ref() {
+                    LispValue::Func(func) => match func.as_ref() {
+                        LispFunction::Lambda(c) => c.env.clone(),
+                        _ => Env::new()
+                    },
+                    _ => Env::new()
+                };
+                let mut m = Machine::new(env);
+                m.coroutine = true;
+                (m, Step::Apply(f, v.into_iter().collect(), None))
+            },
+            CoState::Suspended(m) => (m, Step::Return(v.unwrap_or_else(|| Rc::new(LispValue::Null)))),
+            CoState::Running => return Err(ErrorKind::InvalidArgument("coroutine is already running".into()).into()),
+            CoState::Dead => {
+                *self.state.borrow_mut() = CoState::Dead;
+                return Err(ErrorKind::InvalidArgument("coroutine is already finished".into()).into());
+            }
+        };
+
+        let res = m.run(step);
+        *self.state.borrow_mut() = match res {
+            Ok(Outcome::Yielded(_)) => CoState::Suspended(m),
+            _ => CoState::Dead
+        };
+        res
+
+    }
+
+    /// Either `suspended`, `running`, or `dead`, like Lua.
+    pub fn status(&self) -> &'static str {
+        match *self.state.borrow() {
+            CoState::Fresh(_) | CoState::Suspended(_) => "suspended",
+            CoState::Running => "running",
+            CoState::Dead => "dead"
+        }
+    }
+
+}
+
+impl fmt::Debug for Coroutine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[coroutine {}]", self.status())
+    }
+}
+
+impl PartialEq for Coroutine {
+    fn eq(&self, other: &Self) -> bool {
+        ::std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Coroutine {}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Machines that are in the middle of running, innermost last.
+    static RUNNING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+fn is_running(id: usize) -> bool {
+    RUNNING.with(|r| r.borrow().contains(&id))
+}
+
+pub struct Machine {
+    id: usize,
+    stack: Vec<Kont>,
+    env: Env,
+    winders: Vec<Rc<Winder>>,
+
+    /// If this is a coroutine's machine, which is the only place `yield` can go.
+    coroutine: bool
+}
+
+impl Machine {
+
+    pub fn new(env: Env) -> Machine {
+        Machine {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            stack: Vec::new(),
+            env: env,
+            winders: Vec::new(),
+            coroutine: false
+        }
+    }
+
+    /// Evaluates an expression all the way.
+    pub fn eval(&mut self, sexp: Rc<Sexp>) -> Result<Rc<LispValue>, EvalError> {
+        self.run(Step::Eval(Code::new(sexp))).map(Outcome::value)
+    }
+
+    /// Calls a function with arguments that have already been evaluated.
+    pub fn apply(&mut self, func: Rc<LispValue>, args: Vec<Rc<LispValue>>) -> Result<Rc<LispValue>, EvalError> {
+        self.run(Step::Apply(func, args, None)).map(Outcome::value)
+    }
+
+    fn run(&mut self, step: Step) -> Result<Outcome, EvalError> {
+        RUNNING.with(|r| r.borrow_mut().push(self.id));
+        let res = self.run_steps(step);
+        RUNNING.with(|r| r.borrow_mut().pop());
+        res
+    }
+
+    fn run_steps(&mut self, mut step: Step) -> Result<Outcome, EvalError> {
+        loop {
+            let next = match step {
+                Step::Eval(code) => self.eval_code(code),
+                Step::Apply(f, args, site) => self.apply_function(f, args, site),
+                Step::Return(v) => match self.stack.pop() {
+                    Some(k) => self.resume(k, v),
+                    None => return Ok(Outcome::Done(v))
+                },
+                Step::Yield(v) => return Ok(Outcome::Yielded(v)),
+                Step::Raise(e) => match self.unwind(e) {
+                    Ok(s) => Ok(s),
+                    Err(e) => return Err(e)
+                }
+            };
+            step = next.unwrap_or_else(Step::Raise);
+        }
+    }
+
+    fn eval_code(&mut self, code: Code) -> Result<Step, EvalError> {
+
+        use parser::sexp::Sexp::*;
+        let val = match code.get() {
+
+            // Normal data conversions.
+            Null => LispValue::Null,
+            Integer(i) => LispValue::Integer(*i),
+            Char(c) => LispValue::Char(*c),
+            Str(s) => LispValue::Str(s.clone()),
+            Boolean(b) => LispValue::Boolean(*b),
+            Keyword(s) => LispValue::Keyword(s.clone()),
+
+            // Vector, map, and byte array literals don't evaluate their items, but each time makes a new one.
+            Vector(_) | Map(_) | ByteArray(_) => return Ok(Step::Return(convert::sexp_to_value(code.get()))),
+
+            // Symbols are how variable binding works, outside of `quote` forms.
+            Symbol(s) => return match self.env.resolve(s) {
+                Some(v) => Ok(Step::Return(v)),
+                None => Err(ErrorKind::UnboundName(s.clone()).into())
+            },
+
+            // The empty list is just null, there's nothing to call.
+            List(v, _) if v.is_empty() => LispValue::Null,
+
+            /*
+             * "Lists" in S-expressions are how function calls happen.  First we have to evaluate
+             * the head to figure out what kind of thing we're calling.
+             */
+            List(_, _) => {
+                let head = code.child(0);
+                self.stack.push(Kont::Head { form: code, env: self.env.clone() });
+                return Ok(Step::Eval(head));
+            },
+
+            _ => return Err(ErrorKind::InvalidForm("unevaluatable S-expression".into()).into())
+
+        };
+
+        Ok(Step::Return(Rc::new(val)))
+
+    }
+
+    /// Figures out what to do with a call once we know what's being called.
+    fn call(&mut self, form: Code, f: Rc<LispValue>) -> Result<Step, EvalError> {
+
+        let native = match f.as_ref() {
+            LispValue::Func(fb) => match fb.as_ref() {
+
+                // Intrinsic forms do their own evaluation, so they get the code as-is.
+                &LispFunction::Intrinsic(MgIntrinsic { ref name, func: IntrinsicFunc::Form(ref imp) }) => {
+                    let args = match form.get() {
+                        Sexp::List(l, _) => l,
+                        _ => unreachable!()
+                    };
+                    return match imp.as_ref()(args, &mut self.env) {
+                        Ok(v) => Ok(Step::Return(v)),
+                        Err(e) => Err(e.with_frame(form_frame(name.clone(), &form)))
+                    };
+                },
+
+                &LispFunction::Intrinsic(MgIntrinsic { func: IntrinsicFunc::Native(n), .. }) if n.is_syntax() => Some(n),
+                _ => None
+
+            },
+            v => return Err(ErrorKind::NotCallable(v.type_name().into()).into())
+        };
+
+        if let Some(n) = native {
+            return self.syntax(n, form);
+        }
+
+        // Anything else gets its arguments evaluated first.
+        if form.items().len() == 1 {
+            return Ok(Step::Apply(f, Vec::new(), form.site()));
+        }
+        let first = form.child(1);
+        self.stack.push(Kont::Args {
+            form: form,
+            func: f,
+            values: Vec::new(),
+            env: self.env.clone()
+        });
+        Ok(Step::Eval(first))
+
+    }
+
+    /// Starts one of the special forms built into the machine.
+    fn syntax(&mut self, n: Native, form: Code) -> Result<Step, EvalError> {
+
+        let items = form.items();
+        let res = match n {
+
+            Native::Define => if items.len() != 3 {
+                Err(ErrorKind::InvalidForm("define needs 2 expressions".into()))
+            } else if let &Sexp::Symbol(_) = &items[1] {
+                self.stack.push(Kont::Define { form: form.clone(), env: self.env.clone() });
+                return Ok(Step::Eval(form.child(2)));
+            } else {
+                Err(ErrorKind::InvalidForm("define needs a symbol to bind".into()))
+            },
+
+            Native::If => if items.len() != 4 {
+                Err(ErrorKind::InvalidForm("if needs 3 expressions".into()))
+            } else {
+                self.stack.push(Kont::If { form: form.clone() });
+                return Ok(Step::Eval(form.child(1)));
+            },
+
+            Native::Begin => return Ok(self.sequence(form, 1)),
+
+            /*
+             * This is like R7RS, `(guard (e clause ...) body ...)`.  Each clause is
+             * `(test expr ...)` with `e` bound to what was raised, and the first one whose test is
+             * true handles it.  An `else` clause catches everything.  If nothing matches then the
+             * error keeps going.
+             */
+            Native::Guard => {
+                let problem = if items.len() < 3 {
+                    Some("guard needs a clause list and a body")
+                } else {
+                    match &items[1] {
+                        Sexp::List(l, _) if !l.is_empty() => match &l[0] {
+                            &Sexp::Symbol(_) => None,
+                            _ => Some("guard needs a symbol to bind the error to")
+                        },
+                        _ => Some("guard needs a clause list")
+                    }
+                };
+                match problem {
+                    Some(m) => Err(ErrorKind::InvalidForm(m.into())),
+                    None => {
+                        self.stack.push(Kont::Guard { form: form.clone(), env: self.env.clone() });
+                        return Ok(self.sequence(form, 2));
+                    }
+                }
+            },
+
+            _ => unreachable!()
+
+        };
+
+        res.map_err(|k| EvalError::from(k).with_frame(form_frame(head_name(&form), &form)))
+
+    }
+
+    /// Evaluates the items of a list from `start` on, giving back the last value.
+    fn sequence(&mut self, form: Code, start: usize) -> Step {
+        let n = form.items().len();
+        if start >= n {
+            return Step::Return(Rc::new(LispValue::Null));
+        }
+        let cur = form.child(start);
+        if start + 1 < n {
+            self.stack.push(Kont::Begin { form: form, next: start + 1 });
+        }
+        Step::Eval(cur)
+    }
+
+    fn apply_function(&mut self, f: Rc<LispValue>, args: Vec<Rc<LispValue>>, site: Option<Span>) -> Result<Step, EvalError> {
+
+        let func = match f.as_ref() {
+            LispValue::Func(fb) => fb,
+            v => return Err(ErrorKind::NotCallable(v.type_name().into()).into())
+        };
+
+        match func.as_ref() {
+
+            LispFunction::Lambda(clos) => {
+                let frame = Frame {
+                    function: func.name(),
+                    intrinsic: false,
+                    call_site: site,
+                    args: args.clone()
+                };
+                let nenv = match clos.params.bind(args, &clos.env) {
+                    Ok(e) => e,
+                    Err(e) => return Err(e.with_frame(frame))
+                };
+                let env = mem::replace(&mut self.env, nenv);
+                self.stack.push(Kont::Body { frame: frame, env: env });
+                Ok(Step::Eval(Code::new(clos.body.clone())))
+            },
+
+            LispFunction::Intrinsic(idat) => {
+                let res = match idat.func {
+                    IntrinsicFunc::Procedure(ref p) => p.as_ref()(&args).map(Step::Return),
+
+                    // Special forms need their arguments as code, so they can't be called with values.
+                    IntrinsicFunc::Form(_) => Err(ErrorKind::NotCallable("special form".into()).into()),
+                    IntrinsicFunc::Native(n) if n.is_syntax() => Err(ErrorKind::NotCallable("special form".into()).into()),
+                    IntrinsicFunc::Native(n) => self.native(n, &args, site)
+                };
+                res.map_err(|e| e.with_frame(Frame {
+                    function: idat.name.clone(),
+                    intrinsic: true,
+                    call_site: site,
+                    args: args
+                }))
+            },
+
+            LispFunction::Continuation(k) => {
+                let v = match args.len() {
+                    0 => Rc::new(LispValue::Null),
+                    1 => args[0].clone(),
+                    n => return Err(ErrorKind::ArityMismatch { expected: Arity::Between(0, 1), got: n }.into())
+                };
+
+                // If its machine is further out then we have to get back through the Rust in between.
+                if k.machine != self.id && is_running(k.machine) {
+                    return Err(EvalError::escape(k.clone(), v));
+                }
+                self.jump(k, v)
+            }
+
+        }
+
+    }
+
+    /// Runs the native functions, that get evaluated arguments.
+    fn native(&mut self, n: Native, args: &[Rc<LispValue>], site: Option<Span>) -> Result<Step, EvalError> {
+        match n {
+
+            // Evaluates code in the caller's own environment, so it can define things.
+            Native::Eval => {
+                check_arity(Arity::Exactly(1), args)?;
+                let code = convert::value_to_sexp(args[0].as_ref())?;
+                Ok(Step::Eval(Code::new(Rc::new(code))))
+            },
+
+            // Like `(apply f 1 2 '(3 4))`, anything before the list gets put in front of it.
+            Native::Apply => {
+                check_arity(Arity::AtLeast(2), args)?;
+                check_function(args, 0)?;
+                let mut fargs = args[1..args.len() - 1].to_vec();
+                let mut cur = args[args.len() - 1].clone();
+                loop {
+                    let next = match cur.as_ref() {
+                        LispValue::Cons(f, r) => {
+                            fargs.push(f.clone());
+                            r.clone()
+                        },
+                        LispValue::Null => break,
+                        v => return Err(type_error("list", v, args.len()))
+                    };
+                    cur = next;
+                }
+                Ok(Step::Apply(args[0].clone(), fargs, site))
+            },
+
+            // The after thunk always runs, and if it fails that error wins.
+            Native::DynamicWind => {
+                check_arity(Arity::Exactly(3), args)?;
+                for i in 0..3 {
+                    check_function(args, i)?;
+                }
+                let w = Rc::new(Winder {
+                    before: args[0].clone(),
+                    after: args[2].clone()
+                });
+                self.stack.push(Kont::WindBefore { winder: w, thunk: args[1].clone() });
+                Ok(Step::Apply(args[0].clone(), Vec::new(), None))
+            },
+
+            Native::CallCC => {
+                check_arity(Arity::Exactly(1), args)?;
+                check_function(args, 0)?;
+                let k = Continuation {
+                    machine: self.id,
+                    stack: self.stack.clone(),
+                    env: self.env.clone(),
+                    winders: self.winders.clone()
+                };
+                let kv = Rc::new(LispValue::Func(Box::new(LispFunction::Continuation(Rc::new(k)))));
+                Ok(Step::Apply(args[0].clone(), vec![kv], site))
+            },
+
+            // This only stops the machine, whoever resumed the coroutine gets the value.
+            Native::Yield => {
+                check_arity(Arity::Between(0, 1), args)?;
+                if !self.coroutine {
+                    return Err(EvalError::msg("yield outside of a coroutine, or inside of an intrinsic form"));
+                }
+                Ok(Step::Yield(args.first().cloned().unwrap_or_else(|| Rc::new(LispValue::Null))))
+            },
+
+            // The frame starts the loop once it gets a value, so it's handed one straight away.
+            Native::HashForEach => {
+                let (entries, func) = hash::for_each_args(args)?;
+                self.stack.push(Kont::ForEach { func: func, entries: Rc::new(entries), next: 0 });
+                Ok(Step::Return(Rc::new(LispValue::Null)))
+            },
+
+            _ => unreachable!()
+
+        }
+    }
+
+    /// Gives a value to the frame that was waiting on it.
+    fn resume(&mut self, k: Kont, v: Rc<LispValue>) -> Result<Step, EvalError> {
+        match k {
+
+            Kont::Head { form, env } => {
+                self.env = env;
+                self.call(form, v)
+            },
+
+            Kont::Args { form, func, mut values, env } => {
+                self.env = env.clone();
+                values.push(v);
+                let n = values.len();
+                if n + 1 < form.items().len() {
+                    let next = form.child(n + 1);
+                    self.stack.push(Kont::Args { form: form, func: func, values: values, env: env });
+                    Ok(Step::Eval(next))
+                } else {
+                    Ok(Step::Apply(func, values, form.site()))
+                }
+            },
+
+            Kont::Body { env, .. } | Kont::Restore(env) => {
+                self.env = env;
+                Ok(Step::Return(v))
+            },
+
+            Kont::Define { form, env } => {
+                self.env = env;
+                let name = match &form.items()[1] {
+                    Sexp::Symbol(s) => s.clone(),
+                    _ => unreachable!()
+                };
+
+                // This is where we actually mutate the environment.
+                let named = name_lambda(v, &name);
+                self.env.add_binding(name, named);
+                Ok(Step::Return(Rc::new(LispValue::Null)))
+            },
+
+            Kont::If { form } => match v.as_ref() {
+                &LispValue::Boolean(true) => Ok(Step::Eval(form.child(2))),
+                &LispValue::Boolean(false) => Ok(Step::Eval(form.child(3))),
+                c => Err(type_error("bool", c, 1).with_frame(form_frame(head_name(&form), &form)))
+            },
+
+            Kont::Begin { form, next } => Ok(self.sequence(form, next)),
+
+            Kont::Guard { .. } => Ok(Step::Return(v)),
+
+            Kont::Handler { form, clause, env, err } => match v.as_ref() {
+                &LispValue::Boolean(true) => {
+                    self.env = env;
+                    let c = form.child(1).child(clause + 1);
+                    Ok(if c.items().len() == 1 {
+                        Step::Return(v.clone())
+                    } else {
+                        self.sequence(c, 1)
+                    })
+                },
+                &LispValue::Boolean(false) => self.try_clause(form, clause + 1, env, err),
+                c => Err(type_error("bool", c, 1).with_frame(form_frame(head_name(&form), &form)))
+            },
+
+            Kont::WindBefore { winder, thunk } => {
+                self.winders.push(winder.clone());
+                self.stack.push(Kont::Wound(winder));
+                Ok(Step::Apply(thunk, Vec::new(), None))
+            },
+
+            Kont::Wound(w) => {
+                self.winders.pop();
+                self.stack.push(Kont::WindAfter(v));
+                Ok(Step::Apply(w.after.clone(), Vec::new(), None))
+            },
+
+            Kont::WindAfter(saved) => Ok(Step::Return(saved)),
+
+            Kont::Reraise(e) => Err(e),
+
+            Kont::ForEach { func, entries, next } => match entries.get(next).cloned() {
+                Some((k, v)) => {
+                    self.stack.push(Kont::ForEach { func: func.clone(), entries: entries, next: next + 1 });
+                    Ok(Step::Apply(func, vec![k, v], None))
+                },
+                None => Ok(Step::Return(Rc::new(LispValue::Null)))
+            }
+
+        }
+    }
+
+    /// Pops frames until something handles the error.  If nothing does then it comes back out.
+    fn unwind(&mut self, mut e: EvalError) -> Result<Step, EvalError> {
+
+        if let Some((k, v)) = e.escaping().cloned() {
+            if k.machine == self.id {
+                return Ok(self.jump(&k, v).unwrap_or_else(Step::Raise));
+            }
+        }
+
+        while let Some(k) = self.stack.pop() {
+            match k {
+                Kont::Head { env, .. } | Kont::Args { env, .. } | Kont::Define { env, .. } | Kont::Restore(env) => self.env = env,
+                Kont::Body { frame, env } => {
+                    self.env = env;
+                    e = e.with_frame(frame);
+                },
+                Kont::Guard { form, env } => {
+                    if e.escaping().is_some() {
+                        self.env = env;
+                        continue;
+                    }
+                    return Ok(self.catch(form, env, e).unwrap_or_else(Step::Raise));
+                },
+                Kont::Wound(w) if self.winders.last().is_some_and(|l| Rc::ptr_eq(l, &w)) => {
+                    self.winders.pop();
+                    self.stack.push(Kont::Reraise(e));
+                    return Ok(Step::Apply(w.after.clone(), Vec::new(), None));
+                },
+                _ => {}
+            }
+        }
+
+        Err(e)
+
+    }
+
+    /// Starts handling an error that a guard caught.
+    fn catch(&mut self, form: Code, env: Env, err: EvalError) -> Result<Step, EvalError> {
+        let var = match &form.items()[1] {
+            Sexp::List(l, _) => match &l[0] {
+                Sexp::Symbol(s) => s.clone(),
+                _ => unreachable!()
+            },
+            _ => unreachable!()
+        };
+        self.stack.push(Kont::Restore(env.clone()));
+        let mut henv = env.child();
+        henv.add_binding(var, error_value(&err));
+        self.try_clause(form, 0, henv, err)
+    }
+
+    fn try_clause(&mut self, form: Code, clause: usize, env: Env, err: EvalError) -> Result<Step, EvalError> {
+
+        let clauses = form.child(1);
+        if clause + 1 >= clauses.items().len() {
+            return Err(err);
+        }
+
+        let c = clauses.child(clause + 1);
+        if c.items().is_empty() {
+            return Err(EvalError::from(ErrorKind::InvalidForm("guard clauses need a test".into()))
+                .with_frame(form_frame(head_name(&form), &form)));
+        }
+
+        self.env = env.clone();
+        if c.items()[0] == Sexp::symb_str("else") {
+            return Ok(if c.items().len() == 1 {
+                Step::Return(Rc::new(LispValue::Boolean(true)))
+            } else {
+                self.sequence(c, 1)
+            });
+        }
+
+        self.stack.push(Kont::Handler { form: form, clause: clause, env: env, err: err });
+        Ok(Step::Eval(c.child(0)))
+
+    }
+
+    /// Replaces the stack with a continuation's, running any `dynamic-wind` thunks on the way.
+    fn jump(&mut self, k: &Continuation, v: Rc<LispValue>) -> Result<Step, EvalError> {
+
+        let common = self.winders.iter()
+            .zip(k.winders.iter())
+            .take_while(|&(a, b)| Rc::ptr_eq(a, b))
+            .count();
+
+        while self.winders.len() > common {
+            let w = self.winders.pop().unwrap();
+            Machine::new(self.env.clone()).apply(w.after.clone(), Vec::new())?;
+        }
+        for w in k.winders[common..].iter() {
+            Machine::new(self.env.clone()).apply(w.before.clone(), Vec::new())?;
+            self.winders.push(w.clone());
+        }
+
+        // The continuation could be from a machine that had some other top level, but this one's stays.
+        self.stack = k.stack.clone();
+        self.env = k.env.with_globals_of(&self.env);
+        Ok(Step::Return(v))
+
+    }
+
+}
+
+fn check_arity(expected: Arity, args: &[Rc<LispValue>]) -> Result<(), EvalError> {
+    let ok = match expected {
+        Arity::Exactly(n) => args.len() == n,
+        Arity::AtLeast(n) => args.len() >= n,
+        Arity::Between(a, b) => args.len() >= a && args.len() <= b
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(ErrorKind::ArityMismatch { expected: expected, got: args.len() }.into())
+    }
+}
+
+fn check_function(args: &[Rc<LispValue>], i: usize) -> Result<(), EvalError> {
+    match args[i].as_ref() {
+        &LispValue::Func(_) => Ok(()),
+        v => Err(type_error("function", v, i + 1))
+    }
+}
+
+#[inline]
+fn type_error(expected: &str, got: &LispValue, arg_index: usize) -> EvalError {
+    ErrorKind::TypeError { expected: expected.into(), got: got.type_name().into(), arg_index: arg_index }.into()
+}
+
+fn head_name(form: &Code) -> String {
+    match form.items().first() {
+        Some(Sexp::Symbol(s)) => s.clone(),
+        _ => String::from("<form>")
+    }
+}
+
+/// Forms get their arguments unevaluated, so for their frames the arguments are the expressions.
+fn form_frame(name: String, form: &Code) -> Frame {
+    Frame {
+        function: name,
+        intrinsic: true,
+        call_site: form.site(),
+        args: form.items().iter().skip(1).map(convert::sexp_to_value).collect()
+    }
+}
+
+/// Anonymous lambdas take the name they're first defined as, so stack traces can show it.
+fn name_lambda(v: Rc<LispValue>, name: &str) -> Rc<LispValue> {
+    if let LispValue::Func(f) = v.as_ref() {
+        if let LispFunction::Lambda(c) = f.as_ref() {
+            if c.name.is_none() {
+                return Rc::new(LispValue::Func(Box::new(LispFunction::Lambda(Closure {
+                    name: Some(name.into()),
+                    .. c.clone()
+                }))));
+            }
+        }
+    }
+    v
+}
+
+/// What a guard's handler gets for an error.  Raised values are passed through as they are,
+/// anything else gets wrapped up as a condition.
+fn error_value(err: &EvalError) -> Rc<LispValue> {
+    match err.kind() {
+        ErrorKind::UserRaised(v) => v.clone(),
+        _ => Rc::new(LispValue::Condition(Rc::new(err.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use eval::{ErrorKind, LispValue};
+    use exec::LispProgram;
+    use test_util::run;
+
+    #[test]
+    fn test_callcc_escape() {
+        let mut prog = LispProgram::standard();
+        assert_eq!(run(&mut prog, "(+ 1 (call/cc (lambda (k) (+ 10 (k 5)))))").unwrap(), Rc::new(LispValue::Integer(6)));
+        assert_eq!(run(&mut prog, "(+ 1 (call/cc (lambda (k) 2)))").unwrap(), Rc::new(LispValue::Integer(3)));
+    }
+
+    #[test]
+    fn test_callcc_reentry() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define k (call/cc (lambda (c) c)))").unwrap();
+        assert_eq!(run(&mut prog, "(typeof k)").unwrap(), Rc::new(LispValue::Symbol("function".into())));
+
+        // Going back into the define binds it again, even from a later expression.
+        run(&mut prog, "(k 42)").unwrap();
+        assert_eq!(run(&mut prog, "k").unwrap(), Rc::new(LispValue::Integer(42)));
+
+        let e = run(&mut prog, "(begin (define j (call/cc (lambda (c) c))) (j 7))").unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::NotCallable("integer".into()));
+    }
+
+    #[test]
+    fn test_callcc_keeps_later_definitions() {
+        let mut prog = LispProgram::standard();
+        run(&mut prog, "(define k (call/cc (lambda (c) c)))").unwrap();
+        run(&mut prog, "(define x 5)").unwrap();
+        run(&mut prog, "(k 42)").unwrap();
+        assert_eq!(run(&mut prog, "x").unwrap(), Rc::new(LispValue::Integer(5)));
+        assert_eq!(run(&mut prog, "k").unwrap(), Rc::new(LispValue::Integer(42)));
+
+        // A continuation from inside an intrinsic's callback can't take the program's bindings with it.
+        run(&mut prog, "(define h {a 1 b 2})").unwrap();
+        run(&mut prog, "(define kk (call/cc (lambda (k) (hash-for-each h (lambda (a b) (call/cc (lambda (k2) (k k2))))))))").unwrap();
+        let _ = run(&mut prog, "(kk 1)");
+        assert_eq!(run(&mut prog, "(+ 1 2)").unwrap(), Rc::new(LispValue::Integer(3)));
+        assert!(run(&mut prog, "h").is_ok());
+        assert!(run(&mut prog, "kk").is_ok());
+
+        // Or from inside a coroutine, after it's finished.
+        run(&mut prog, "(define co (make-coroutine (lambda (x) (call/cc (lambda (k) k)))))").unwrap();
+        run(&mut prog, "(define kc (resume co 1))").unwrap();
+        let _ = run(&mut prog, "(kc 5)");
+        assert_eq!(run(&mut prog, "(+ 1 2)").unwrap(), Rc::new(LispValue::Integer(3)));
+        assert!(run(&mut prog, "co").is_ok());
+
+        // And a jump made from inside a coroutine still lands in the program's top level.
+        run(&mut prog, "(define back (call/cc (lambda (k) k)))").unwrap();
+        run(&mut prog, "(resume (make-coroutine (lambda () (back 7))))").unwrap();
+        assert_eq!(run(&mut prog, "back").unwrap(), Rc::new(LispValue::Integer(7)));
+
+        // Names bound for a function call still go away afterwards.
+        run(&mut prog, "((lambda (y) y) 1)").unwrap();
+        assert!(run(&mut prog, "y").is_err());
+    }
+
+    #[test]
+    fn test_callcc_through_intrinsic_form() {
+        let mut prog = LispProgram::standard();
+        assert_eq!(
+            run(&mut prog, "(+ 1 (call/cc (lambda (k) `(a ,(k 5)))))").unwrap(),
+            Rc::new(LispValue::Integer(6)));
+    }
+
+    #[test]
+    fn test_callcc_runs_winders() {
+        let mut prog = LispProgram::standard();
+        let e = run(&mut prog, "(call/cc (lambda (k) (dynamic-wind (lambda () 1) (lambda () (k 2)) (lambda () (raise 'after)))))").unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::UserRaised(Rc::new(LispValue::Symbol("after".into()))));
+    }
+
+    #[test]
+    fn test_definitions_survive_errors() {
+        let mut prog = LispProgram::standard();
+        assert!(run(&mut prog, "(begin (define a 1) ((lambda (x) (define b 2) (raise x)) 3))").is_err());
+        assert_eq!(run(&mut prog, "a").unwrap(), Rc::new(LispValue::Integer(1)));
+        assert!(run(&mut prog, "b").is_err());
+    }
+
+    #[test]
+    fn test_apply_special_forms() {
+        let mut prog = LispProgram::standard();
+        let not_callable = ErrorKind::NotCallable("special form".into());
+        assert_eq!(run(&mut prog, "(apply quote '(1))").unwrap_err().kind(), &not_callable);
+        assert_eq!(run(&mut prog, "(apply lambda '((x) x))").unwrap_err().kind(), &not_callable);
+        assert_eq!(run(&mut prog, "(apply define '(zz 5))").unwrap_err().kind(), &not_callable);
+        assert!(run(&mut prog, "zz").is_err());
+    }
+
+}
@@ -0,0 +1,676 @@
+//! Immutable collections that share structure between versions.
+//!
+//! Changing one of these gives back a new collection and leaves the old one alone, but only the
+//! path down to what changed gets copied.  Cloning one is just bumping a refcount, so keeping
+//! snapshots of big state around is cheap.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::*;
+
+const BITS: u32 = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+/// A persistent vector, as a trie with 32-way branching and the last few items kept off to the
+/// side in a "tail" so pushing onto the end is usually just copying that.
+pub struct PVec<T> {
+    len: usize,
+    shift: u32,
+    root: Rc<VecNode<T>>,
+    tail: Rc<Vec<T>>
+}
+
+enum VecNode<T> {
+    Branch(Vec<Rc<VecNode<T>>>),
+    Leaf(Vec<T>)
+}
+
+impl<T: Clone> PVec<T> {
+
+    pub fn new() -> PVec<T> {
+        PVec {
+            len: 0,
+            shift: BITS,
+            root: Rc::new(VecNode::Branch(Vec::new())),
+            tail: Rc::new(Vec::new())
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Where the tail starts, everything before this is in the trie.
+    fn tail_offset(&self) -> usize {
+        if self.len < WIDTH {
+            0
+        } else {
+            ((self.len - 1) >> BITS) << BITS
+        }
+    }
+
+    /// The leaf that has index `i` in it.
+    fn leaf_for(&self, i: usize) -> &Vec<T> {
+
+        if i >= self.tail_offset() {
+            return &self.tail;
+        }
+
+        let mut node = self.root.as_ref();
+        let mut level = self.shift;
+        loop {
+            match node {
+                VecNode::Branch(children) => {
+                    node = children[(i >> level) & MASK].as_ref();
+                    level -= BITS;
+                },
+                VecNode::Leaf(items) => return items
+            }
+        }
+
+    }
+
+    pub fn get(&self, i: usize) -> Option<&T> {
+        if i < self.len {
+            Some(&self.leaf_for(i)[i & MASK])
+        } else {
+            None
+        }
+    }
+
+    /// A copy with item `i` replaced, or `None` if it's out of range.
+    pub fn set(&self, i: usize, v: T) -> Option<PVec<T>> {
+
+        if i >= self.len {
+            return None;
+        }
+
+        if i >= self.tail_offset() {
+            let mut tail = self.tail.as_ref().clone();
+            tail[i & MASK] = v;
+            return Some(PVec { tail: Rc::new(tail), root: self.root.clone(), ..*self });
+        }
+
+        Some(PVec { root: Rc::new(set_in(&self.root, self.shift, i, v)), tail: self.tail.clone(), ..*self })
+
+    }
+
+    /// A copy with `v` on the end.
+    pub fn push(&self, v: T) -> PVec<T> {
+
+        // Room in the tail, so nothing in the trie has to change.
+        if self.len - self.tail_offset() < WIDTH {
+            let mut tail = self.tail.as_ref().clone();
+            tail.push(v);
+            return PVec { len: self.len + 1, tail: Rc::new(tail), root: self.root.clone(), ..*self };
+        }
+
+        // Otherwise the full tail goes into the trie, which might need to get taller first.
+        let leaf = Rc::new(VecNode::Leaf(self.tail.as_ref().clone()));
+        let (root, shift) = if (self.len >> BITS) > (1 << self.shift) {
+            (VecNode::Branch(vec![self.root.clone(), new_path(self.shift, leaf)]), self.shift + BITS)
+        } else {
+            (push_tail(self.len, &self.root, self.shift, leaf), self.shift)
+        };
+
+        PVec {
+            len: self.len + 1,
+            shift: shift,
+            root: Rc::new(root),
+            tail: Rc::new(vec![v])
+        }
+
+    }
+
+    /// A copy without the last item, or `None` if it's empty.
+    pub fn pop(&self) -> Option<PVec<T>> {
+
+        if self.len == 0 {
+            return None;
+        } else if self.len == 1 {
+            return Some(PVec::new());
+        }
+
+        if self.len - self.tail_offset() > 1 {
+            let mut tail = self.tail.as_ref().clone();
+            tail.pop();
+            return Some(PVec { len: self.len - 1, tail: Rc::new(tail), root: self.root.clone(), ..*self });
+        }
+
+        // The tail's about to be empty, so the last leaf in the trie becomes the new tail.
+        let tail = self.leaf_for(self.len - 2).clone();
+        let mut root = pop_tail(self.len, &self.root, self.shift).unwrap_or_else(|| Rc::new(VecNode::Branch(Vec::new())));
+        let mut shift = self.shift;
+        if shift > BITS {
+            let only = match root.as_ref() {
+                VecNode::Branch(children) if children.len() == 1 => Some(children[0].clone()),
+                _ => None
+            };
+            if let Some(child) = only {
+                root = child;
+                shift -= BITS;
+            }
+        }
+
+        Some(PVec {
+            len: self.len - 1,
+            shift: shift,
+            root: root,
+            tail: Rc::new(tail)
+        })
+
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len).map(move |i| &self.leaf_for(i)[i & MASK])
+    }
+
+}
+
+fn set_in<T: Clone>(node: &VecNode<T>, level: u32, i: usize, v: T) -> VecNode<T> {
+    match node {
+        VecNode::Branch(children) => {
+            let mut children = children.clone();
+            let sub = (i >> level) & MASK;
+            children[sub] = Rc::new(set_in(&children[sub], level - BITS, i, v));
+            VecNode::Branch(children)
+        },
+        VecNode::Leaf(items) => {
+            let mut items = items.clone();
+            items[i & MASK] = v;
+            VecNode::Leaf(items)
+        }
+    }
+}
+
+/// A chain of single-child branches down to the leaf, for a part of the trie that's new.
+fn new_path<T>(level: u32, leaf: Rc<VecNode<T>>) -> Rc<VecNode<T>> {
+    if level == 0 {
+        leaf
+    } else {
+        Rc::new(VecNode::Branch(vec![new_path(level - BITS, leaf)]))
+    }
+}
+
+/// Puts the old tail in as the leaf after the last one, `len` being how many there were before.
+fn push_tail<T>(len: usize, node: &VecNode<T>, level: u32, leaf: Rc<VecNode<T>>) -> VecNode<T> {
+
+    let mut children = match node {
+        VecNode::Branch(children) => children.clone(),
+        VecNode::Leaf(_) => unreachable!("leaves are only at the bottom")
+    };
+
+    let sub = ((len - 1) >> level) & MASK;
+    if level == BITS {
+        children.push(leaf);
+    } else if sub < children.len() {
+        children[sub] = Rc::new(push_tail(len, &children[sub], level - BITS, leaf));
+    } else {
+        children.push(new_path(level - BITS, leaf));
+    }
+
+    VecNode::Branch(children)
+
+}
+
+/// Takes the last leaf out of the trie, giving `None` if that leaves this node empty.
+fn pop_tail<T>(len: usize, node: &VecNode<T>, level: u32) -> Option<Rc<VecNode<T>>> {
+
+    let children = match node {
+        VecNode::Branch(children) => children,
+        VecNode::Leaf(_) => unreachable!("leaves are only at the bottom")
+    };
+
+    let sub = ((len - 2) >> level) & MASK;
+    let mut children = children.clone();
+    if level > BITS {
+        match pop_tail(len, &children[sub], level - BITS) {
+            Some(child) => children[sub] = child,
+            None if sub == 0 => return None,
+            None => children.truncate(sub)
+        }
+    } else if sub == 0 {
+        return None;
+    } else {
+        children.truncate(sub);
+    }
+
+    Some(Rc::new(VecNode::Branch(children)))
+
+}
+
+impl<T: Clone> Default for PVec<T> {
+    fn default() -> PVec<T> {
+        PVec::new()
+    }
+}
+
+impl<T> Clone for PVec<T> {
+    fn clone(&self) -> PVec<T> {
+        PVec {
+            len: self.len,
+            shift: self.shift,
+            root: self.root.clone(),
+            tail: self.tail.clone()
+        }
+    }
+}
+
+impl<T: Clone> ::std::iter::FromIterator<T> for PVec<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(items: I) -> PVec<T> {
+        items.into_iter().fold(PVec::new(), |v, e| v.push(e))
+    }
+}
+
+impl<T: Clone + PartialEq> PartialEq for PVec<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl<T: Clone + Eq> Eq for PVec<T> {}
+
+impl<T: Clone + fmt::Debug> fmt::Debug for PVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// A persistent hash map, as a hash array mapped trie.  Each level looks at 5 more bits of the
+/// key's hash, and keys whose hashes are entirely the same share a bucket at the bottom.
+pub struct PMap<K, V> {
+    len: usize,
+    root: Option<Rc<MapNode<K, V>>>
+}
+
+enum MapNode<K, V> {
+
+    /// Only has room for the slots that are used, `bitmap` says which those are.
+    Branch(u32, Vec<MapEntry<K, V>>),
+
+    Collision(u64, Vec<(K, V)>)
+
+}
+
+enum MapEntry<K, V> {
+    Leaf(u64, K, V),
+    Node(Rc<MapNode<K, V>>)
+}
+
+fn hash_of<K: Hash>(k: &K) -> u64 {
+    let mut h = DefaultHasher::new();
+    k.hash(&mut h);
+    h.finish()
+}
+
+/// Which bit of a branch's bitmap a hash lands on, at some depth.
+fn bit_for(hash: u64, shift: u32) -> u32 {
+    1 << ((hash >> shift) as usize & MASK)
+}
+
+/// Where in a branch's children the entry for a bit is.
+fn slot_for(bitmap: u32, bit: u32) -> usize {
+    (bitmap & (bit - 1)).count_ones() as usize
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> PMap<K, V> {
+
+    pub fn new() -> PMap<K, V> {
+        PMap {
+            len: 0,
+            root: None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, k: &K) -> Option<&V> {
+
+        let hash = hash_of(k);
+        let mut node = match self.root {
+            Some(ref r) => r.as_ref(),
+            None => return None
+        };
+
+        let mut shift = 0;
+        loop {
+            match node {
+                MapNode::Branch(bitmap, children) => {
+                    let bit = bit_for(hash, shift);
+                    if bitmap & bit == 0 {
+                        return None;
+                    }
+                    match &children[slot_for(*bitmap, bit)] {
+                        MapEntry::Leaf(_, k2, v) => return if k2 == k { Some(v) } else { None },
+                        MapEntry::Node(n) => {
+                            node = n.as_ref();
+                            shift += BITS;
+                        }
+                    }
+                },
+                MapNode::Collision(_, entries) => return entries.iter().find(|e| &e.0 == k).map(|e| &e.1)
+            }
+        }
+
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.get(k).is_some()
+    }
+
+    /// A copy with `k` mapped to `v`, replacing whatever it was before.
+    pub fn insert(&self, k: K, v: V) -> PMap<K, V> {
+        let hash = hash_of(&k);
+        let (root, added) = match self.root {
+            Some(ref r) => insert_in(r, 0, hash, k, v),
+            None => (MapNode::Branch(bit_for(hash, 0), vec![MapEntry::Leaf(hash, k, v)]), true)
+        };
+        PMap {
+            len: if added { self.len + 1 } else { self.len },
+            root: Some(Rc::new(root))
+        }
+    }
+
+    /// A copy without `k`.  If it wasn't there then this shares everything with the original.
+    pub fn remove(&self, k: &K) -> PMap<K, V> {
+        let root = match self.root {
+            Some(ref r) => r,
+            None => return self.clone()
+        };
+        match remove_from(root, 0, hash_of(k), k) {
+            None => self.clone(),
+            Some(root) => PMap { len: self.len - 1, root: root.map(Rc::new) }
+        }
+    }
+
+    /// Everything in the map, in no particular order.
+    pub fn iter(&self) -> ::std::vec::IntoIter<(&K, &V)> {
+        let mut entries = Vec::with_capacity(self.len);
+        if let Some(ref r) = self.root {
+            collect_entries(r, &mut entries);
+        }
+        entries.into_iter()
+    }
+
+}
+
+/// Gives back the new node, and whether the key is new rather than replaced.
+fn insert_in<K: Clone + Eq, V: Clone>(node: &MapNode<K, V>, shift: u32, hash: u64, k: K, v: V) -> (MapNode<K, V>, bool) {
+    match node {
+        &MapNode::Branch(bitmap, ref children) => {
+
+            let bit = bit_for(hash, shift);
+            let slot = slot_for(bitmap, bit);
+            let mut children = children.clone();
+            if bitmap & bit == 0 {
+                children.insert(slot, MapEntry::Leaf(hash, k, v));
+                return (MapNode::Branch(bitmap | bit, children), true);
+            }
+
+            let (entry, added) = match &children[slot] {
+                MapEntry::Leaf(h2, k2, _) if k2 == &k => (MapEntry::Leaf(*h2, k, v), false),
+                MapEntry::Leaf(h2, k2, v2) => {
+                    (MapEntry::Node(Rc::new(merge(shift + BITS, *h2, k2.clone(), v2.clone(), hash, k, v))), true)
+                },
+                MapEntry::Node(n) => {
+                    let (n, added) = insert_in(n, shift + BITS, hash, k, v);
+                    (MapEntry::Node(Rc::new(n)), added)
+                }
+            };
+            children[slot] = entry;
+            (MapNode::Branch(bitmap, children), added)
+
+        },
+        &MapNode::Collision(h, ref entries) => {
+            let mut entries = entries.clone();
+            match entries.iter().position(|e| e.0 == k) {
+                Some(i) => {
+                    entries[i].1 = v;
+                    (MapNode::Collision(h, entries), false)
+                },
+                None => {
+                    entries.push((k, v));
+                    (MapNode::Collision(h, entries), true)
+                }
+            }
+        }
+    }
+}
+
+/// A node with two different keys under it, which might be as deep as their hashes agree.
+fn merge<K, V>(shift: u32, h1: u64, k1: K, v1: V, h2: u64, k2: K, v2: V) -> MapNode<K, V> {
+
+    // Past the end of the hash, so there's nothing left to tell them apart by.
+    if shift >= 64 {
+        return MapNode::Collision(h1, vec![(k1, v1), (k2, v2)]);
+    }
+
+    let b1 = bit_for(h1, shift);
+    let b2 = bit_for(h2, shift);
+    if b1 == b2 {
+        MapNode::Branch(b1, vec![MapEntry::Node(Rc::new(merge(shift + BITS, h1, k1, v1, h2, k2, v2)))])
+    } else if b1 < b2 {
+        MapNode::Branch(b1 | b2, vec![MapEntry::Leaf(h1, k1, v1), MapEntry::Leaf(h2, k2, v2)])
+    } else {
+        MapNode::Branch(b1 | b2, vec![MapEntry::Leaf(h2, k2, v2), MapEntry::Leaf(h1, k1, v1)])
+    }
+
+}
+
+/*
+ * `None` is when the key isn't there at all.  Otherwise it's the new node, which is `None` itself
+ * if the node doesn't have anything left in it and should be dropped by the parent.
+ */
+fn remove_from<K: Clone + Eq, V: Clone>(node: &MapNode<K, V>, shift: u32, hash: u64, k: &K) -> Option<Option<MapNode<K, V>>> {
+    match node {
+        &MapNode::Branch(bitmap, ref children) => {
+
+            let bit = bit_for(hash, shift);
+            if bitmap & bit == 0 {
+                return None;
+            }
+
+            let slot = slot_for(bitmap, bit);
+            let replacement = match &children[slot] {
+                MapEntry::Leaf(_, k2, _) if k2 == k => None,
+                MapEntry::Leaf(_, _, _) => return None,
+                MapEntry::Node(n) => remove_from(n, shift + BITS, hash, k)?.map(|n| MapEntry::Node(Rc::new(n)))
+            };
+
+            let mut children = children.clone();
+            match replacement {
+                Some(e) => children[slot] = e,
+                None => {
+                    children.remove(slot);
+                    if children.is_empty() {
+                        return Some(None);
+                    }
+                    return Some(Some(MapNode::Branch(bitmap & !bit, children)));
+                }
+            }
+            Some(Some(MapNode::Branch(bitmap, children)))
+
+        },
+        &MapNode::Collision(h, ref entries) => {
+            let i = entries.iter().position(|e| &e.0 == k)?;
+            let mut entries = entries.clone();
+            entries.remove(i);
+            if entries.is_empty() {
+                Some(None)
+            } else {
+                Some(Some(MapNode::Collision(h, entries)))
+            }
+        }
+    }
+}
+
+fn collect_entries<'a, K, V>(node: &'a MapNode<K, V>, into: &mut Vec<(&'a K, &'a V)>) {
+    match node {
+        MapNode::Branch(_, children) => {
+            for c in children {
+                match c {
+                    MapEntry::Leaf(_, k, v) => into.push((k, v)),
+                    MapEntry::Node(n) => collect_entries(n, into)
+                }
+            }
+        },
+        MapNode::Collision(_, entries) => into.extend(entries.iter().map(|e| (&e.0, &e.1)))
+    }
+}
+
+impl<K: Clone, V: Clone> Clone for MapEntry<K, V> {
+    fn clone(&self) -> MapEntry<K, V> {
+        match self {
+            MapEntry::Leaf(h, k, v) => MapEntry::Leaf(*h, k.clone(), v.clone()),
+            MapEntry::Node(n) => MapEntry::Node(n.clone())
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Default for PMap<K, V> {
+    fn default() -> PMap<K, V> {
+        PMap::new()
+    }
+}
+
+impl<K, V> Clone for PMap<K, V> {
+    fn clone(&self) -> PMap<K, V> {
+        PMap {
+            len: self.len,
+            root: self.root.clone()
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> ::std::iter::FromIterator<(K, V)> for PMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(items: I) -> PMap<K, V> {
+        items.into_iter().fold(PMap::new(), |m, (k, v)| m.insert(k, v))
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone + PartialEq> PartialEq for PMap<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone + Eq> Eq for PMap<K, V> {}
+
+impl<K: Clone + Eq + Hash + fmt::Debug, V: Clone + fmt::Debug> fmt::Debug for PMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::hash::{Hash, Hasher};
+    use super::{PMap, PVec};
+
+    #[test]
+    fn test_pvec_push_get() {
+        let v: PVec<usize> = (0..5000).collect();
+        assert_eq!(v.len(), 5000);
+        for i in 0..5000 {
+            assert_eq!(v.get(i), Some(&i));
+        }
+        assert_eq!(v.get(5000), None);
+        assert!(v.iter().cloned().eq(0..5000));
+    }
+
+    #[test]
+    fn test_pvec_versions_are_separate() {
+        let a: PVec<usize> = (0..100).collect();
+        let b = a.set(50, 0).unwrap().push(100);
+        let c = a.set(99, 7).unwrap();
+        assert_eq!(a.get(50), Some(&50));
+        assert_eq!(a.len(), 100);
+        assert_eq!(b.get(50), Some(&0));
+        assert_eq!(b.get(100), Some(&100));
+        assert_eq!(c.get(99), Some(&7));
+        assert_eq!(a.get(99), Some(&99));
+        assert!(a.set(100, 0).is_none());
+    }
+
+    #[test]
+    fn test_pvec_pop() {
+        let mut v: PVec<usize> = (0..2000).collect();
+        for n in (0..2000).rev() {
+            assert_eq!(v.get(n), Some(&n));
+            v = v.pop().unwrap();
+            assert_eq!(v.len(), n);
+            if n > 0 {
+                assert_eq!(v.get(n - 1), Some(&(n - 1)));
+                assert_eq!(v.get(0), Some(&0));
+            }
+        }
+        assert!(v.pop().is_none());
+
+        // Growing it back out after shrinking has to work too.
+        let v: PVec<usize> = (0..1100).collect();
+        let v = (0..70).fold(v, |v, _| v.pop().unwrap());
+        let v = (1030..1200).fold(v, |v, i| v.push(i));
+        assert!(v.iter().cloned().eq((0..1030).chain(1030..1200)));
+    }
+
+    #[test]
+    fn test_pmap() {
+        let m: PMap<i64, i64> = (0..3000).map(|i| (i, i * 2)).collect();
+        assert_eq!(m.len(), 3000);
+        for i in 0..3000 {
+            assert_eq!(m.get(&i), Some(&(i * 2)));
+        }
+        assert_eq!(m.get(&3000), None);
+
+        let m2 = m.insert(5, 0).insert(3000, 1);
+        assert_eq!(m2.len(), 3001);
+        assert_eq!(m2.get(&5), Some(&0));
+        assert_eq!(m.get(&5), Some(&10));
+
+        let m3 = (0..3000).filter(|i| i % 3 != 0).fold(m.clone(), |m, i| m.remove(&i));
+        assert_eq!(m3.len(), 1000);
+        assert_eq!(m3.get(&3), Some(&6));
+        assert_eq!(m3.get(&4), None);
+        assert_eq!(m3.iter().count(), 1000);
+        assert_eq!(m.len(), 3000);
+        assert_eq!(m3.remove(&4).len(), 1000);
+    }
+
+    /// Always hashes the same, so every key collides.
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    struct Clash(u32);
+
+    impl Hash for Clash {
+        fn hash<H: Hasher>(&self, h: &mut H) {
+            h.write_u32(0);
+        }
+    }
+
+    #[test]
+    fn test_pmap_collisions() {
+        let m: PMap<Clash, u32> = (0..10).map(|i| (Clash(i), i)).collect();
+        assert_eq!(m.len(), 10);
+        assert_eq!(m.get(&Clash(7)), Some(&7));
+        let m = m.insert(Clash(7), 70).remove(&Clash(3));
+        assert_eq!(m.len(), 9);
+        assert_eq!(m.get(&Clash(7)), Some(&70));
+        assert_eq!(m.get(&Clash(3)), None);
+        let m = (0..10).fold(m, |m, i| m.remove(&Clash(i)));
+        assert!(m.is_empty());
+        assert_eq!(m, PMap::new());
+    }
+
+}
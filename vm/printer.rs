@@ -0,0 +1,320 @@
+//! Turns values into text, either for people to read (`display`) or so that the reader can read
+//! it back in when that's possible (`write`).
+//!
+//! Things that don't have a syntax, like functions, print as `#<...>`.  Anything that contains
+//! itself gets datum labels, like `#0=#(1 #0#)`, so printing it always finishes.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::rc::Rc;
+
+use eval::*;
+use parser::sexp::Sexp;
+
+pub use parser::pretty::write_str;
+
+/// Like `display` in Scheme, so strings and chars come out as they are.
+pub fn display(v: &LispValue) -> String {
+    Printer::new(v, false).print_root(v)
+}
+
+/// Like `write` in Scheme, so strings are quoted and escaped and chars are `#\a`.
+pub fn write(v: &LispValue) -> String {
+    Printer::new(v, true).print_root(v)
+}
+
+/// Displays the value, or writes it with `{:#}`.
+impl fmt::Display for LispValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&if f.alternate() { write(self) } else { display(self) })
+    }
+}
+
+type Addr = *const LispValue;
+
+/// The values directly inside another one, that could lead back around to it.
+fn children(v: &LispValue) -> Vec<Rc<LispValue>> {
+    use eval::LispValue::*;
+    match v {
+        Cons(f, r) => vec![f.clone(), r.clone()],
+        Vector(items) => items.borrow().clone(),
+        HashMap(m) => m.borrow().values().cloned().collect(),
+        PersistentVector(items) => items.iter().cloned().collect(),
+        PersistentMap(m) => m.iter().map(|(_, v)| v.clone()).collect(),
+        Record(r) => r.fields.borrow().clone(),
+        _ => Vec::new()
+    }
+}
+
+/*
+ * Finds everything that's reachable from inside itself, which are the ones that need labels.
+ * Values that are only shared, and not part of a cycle, print normally.  This is a depth-first
+ * search with its own stack so long lists don't use up the real one.
+ */
+fn find_cycles(root: &LispValue) -> HashSet<Addr> {
+
+    let mut on_path = HashSet::new();
+    let mut finished = HashSet::new();
+    let mut cyclic = HashSet::new();
+
+    on_path.insert(root as Addr);
+    let mut stack: Vec<(Rc<LispValue>, bool)> = children(root).into_iter().map(|c| (c, false)).collect();
+    while let Some((v, leaving)) = stack.pop() {
+        let addr = v.as_ref() as Addr;
+        if leaving {
+            on_path.remove(&addr);
+            finished.insert(addr);
+        } else if on_path.contains(&addr) {
+            cyclic.insert(addr);
+        } else if !finished.contains(&addr) {
+            let kids = children(&v);
+            if !kids.is_empty() {
+                on_path.insert(addr);
+                stack.push((v, true));
+                stack.extend(kids.into_iter().map(|c| (c, false)));
+            }
+        }
+    }
+
+    cyclic
+
+}
+
+struct Printer {
+    readable: bool,
+    cyclic: HashSet<Addr>,
+    labels: HashMap<Addr, usize>,
+    out: String
+}
+
+impl Printer {
+
+    fn new(root: &LispValue, readable: bool) -> Printer {
+        Printer {
+            readable: readable,
+            cyclic: find_cycles(root),
+            labels: HashMap::new(),
+            out: String::new()
+        }
+    }
+
+    fn print_root(mut self, v: &LispValue) -> String {
+        self.print(v);
+        self.out
+    }
+
+    fn print_all<'a, I: Iterator<Item = &'a Rc<LispValue>>>(&mut self, items: I) {
+        for (i, v) in items.enumerate() {
+            if i > 0 {
+                self.out.push(' ');
+            }
+            self.print(v);
+        }
+    }
+
+    /// Writes `#n#` and gives back true if it's been printed already, otherwise writes `#n=` if
+    /// it's going to need the label later.
+    fn label(&mut self, v: &LispValue) -> bool {
+        let addr = v as Addr;
+        if !self.cyclic.contains(&addr) {
+            return false;
+        }
+        if let Some(n) = self.labels.get(&addr) {
+            write!(self.out, "#{}#", n).unwrap();
+            return true;
+        }
+        let n = self.labels.len();
+        self.labels.insert(addr, n);
+        write!(self.out, "#{}=", n).unwrap();
+        false
+    }
+
+    fn print(&mut self, v: &LispValue) {
+
+        use eval::LispValue::*;
+        if self.label(v) {
+            return;
+        }
+
+        let readable = self.readable;
+        match v {
+            Null => self.out.push_str("()"),
+            Integer(i) => write!(self.out, "{}", i).unwrap(),
+            ByteArray(b) => write!(self.out, "{}", Sexp::ByteArray(b.borrow().clone())).unwrap(),
+            Char(c) if readable => write!(self.out, "{}", Sexp::Char(*c)).unwrap(),
+            Char(c) => self.out.push(*c),
+            Str(s) if readable => write_str(s, &mut self.out),
+            Str(s) => self.out.push_str(s),
+            Boolean(b) => self.out.push_str(if *b { "#t" } else { "#f" }),
+            Symbol(s) => self.out.push_str(s),
+            Keyword(s) => write!(self.out, "#:{}", s).unwrap(),
+            Cons(f, r) => {
+                self.out.push('(');
+                self.print(f);
+                let mut cur = r.clone();
+                loop {
+
+                    // A labelled cell has to be printed on its own, so it goes after a dot.
+                    let next = match cur.as_ref() {
+                        Cons(f, r) if !self.cyclic.contains(&(cur.as_ref() as Addr)) => {
+                            self.out.push(' ');
+                            self.print(f);
+                            r.clone()
+                        },
+                        Null => break,
+                        t => {
+                            self.out.push_str(" . ");
+                            self.print(t);
+                            break;
+                        }
+                    };
+                    cur = next;
+
+                }
+                self.out.push(')');
+            },
+            Vector(items) => {
+                self.out.push_str("#(");
+                self.print_all(items.borrow().iter());
+                self.out.push(')');
+            },
+            HashMap(m) => {
+                let entries = m.borrow().iter().map(|(k, v)| (k.to_value(), v.clone())).collect();
+                self.print_map(entries);
+            },
+            PersistentVector(items) => {
+                self.out.push_str("#<pvector (");
+                self.print_all(items.iter());
+                self.out.push_str(")>");
+            },
+            PersistentMap(m) => {
+                self.out.push_str("#<pmap ");
+                self.print_map(m.iter().map(|(k, v)| (k.to_value(), v.clone())).collect());
+                self.out.push('>');
+            },
+            Record(r) => {
+                write!(self.out, "#<{}", r.rtype.name).unwrap();
+                for (n, v) in r.rtype.fields.iter().zip(r.fields.borrow().iter()) {
+                    write!(self.out, " {}: ", n).unwrap();
+                    self.print(v);
+                }
+                self.out.push('>');
+            },
+            Func(f) => match f.as_ref() {
+                LispFunction::Lambda(c) => {
+                    self.out.push_str("#<lambda ");
+                    print_params(&c.params, &mut self.out);
+                    self.out.push('>');
+                },
+                LispFunction::Intrinsic(i) => write!(self.out, "#<intrinsic {}>", i.name).unwrap(),
+                LispFunction::Continuation(_) => self.out.push_str("#<continuation>")
+            },
+            Condition(e) => write!(self.out, "#<condition {}: {}>", e.kind().symbol(), e).unwrap(),
+            Coroutine(c) => write!(self.out, "#<coroutine {}>", c.status()).unwrap()
+        }
+
+    }
+
+    /// Hash maps come out sorted by key, so the same map always prints the same way.
+    fn print_map(&mut self, mut entries: Vec<(Rc<LispValue>, Rc<LispValue>)>) {
+        entries.sort_by_cached_key(|e| write(&e.0));
+        self.out.push('{');
+        for (i, (k, v)) in entries.iter().enumerate() {
+            if i > 0 {
+                self.out.push(' ');
+            }
+            self.print(k);
+            self.out.push(' ');
+            self.print(v);
+        }
+        self.out.push('}');
+    }
+
+}
+
+/// Writes out a parameter list the way it would look in the `lambda`.
+fn print_params(p: &Params, out: &mut String) {
+
+    let mut parts: Vec<String> = p.required.clone();
+    let with_default = |(n, d): &(String, Option<Rc<Sexp>>)| match d {
+        Some(d) => format!("({} {})", n, d),
+        &None => n.clone()
+    };
+    if !p.optional.is_empty() {
+        parts.push("#:optional".into());
+        parts.extend(p.optional.iter().map(with_default));
+    }
+    if !p.keys.is_empty() {
+        parts.push("#:key".into());
+        parts.extend(p.keys.iter().map(with_default));
+    }
+
+    match p.rest {
+        Some(ref r) if parts.is_empty() => out.push_str(r),
+        Some(ref r) => write!(out, "({} . {})", parts.join(" "), r).unwrap(),
+        None => write!(out, "({})", parts.join(" ")).unwrap()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use parser;
+    use eval::LispValue;
+    use exec::LispProgram;
+    use super::{display, write};
+
+    fn printed(src: &str) -> (String, String) {
+        let mut prog = LispProgram::standard();
+        let toks = parser::lex(&String::from(src)).unwrap();
+        let sexp = parser::parse(&mut toks.into_iter().peekable()).unwrap();
+        let v = prog.exec(&sexp).unwrap();
+        (display(&v), write(&v))
+    }
+
+    #[test]
+    fn test_data() {
+        assert_eq!(printed("'(1 (2 \"x\") . #\\a)"), ("(1 (2 x) . a)".into(), "(1 (2 \"x\") . #\\a)".into()));
+        assert_eq!(printed("\"a\\\"b\\nc\""), ("a\"b\nc".into(), "\"a\\\"b\\nc\"".into()));
+        assert_eq!(printed("'(#t #f #:k sym ())").1, "(#t #f #:k sym ())");
+        assert_eq!(printed("#(1 #u8(2 3))").1, "#(1 #u8(2 3))");
+        assert_eq!(printed("{b 2 a \"1\"}").1, "{a \"1\" b 2}");
+        assert_eq!(printed("(pvector 1 2)").1, "#<pvector (1 2)>");
+        assert_eq!(printed("(pmap 'a 1)").1, "#<pmap {a 1}>");
+        assert_eq!(printed("#\\space").1, "#\\space");
+    }
+
+    #[test]
+    fn test_opaque() {
+        assert_eq!(printed("(lambda (a b) a)").1, "#<lambda (a b)>");
+        assert_eq!(printed("(lambda args 1)").1, "#<lambda args>");
+        assert_eq!(printed("(lambda (a #:optional (b 2) #:key c . r) 1)").1, "#<lambda (a #:optional (b 2) #:key c . r)>");
+        assert_eq!(printed("+").1, "#<intrinsic +>");
+        assert_eq!(printed("(make-coroutine (lambda (x) x))").1, "#<coroutine suspended>");
+        assert_eq!(printed("(guard (e (#t e)) (raise-error))").1, "#<condition unbound-name: unbound name raise-error>");
+        let rec = "(begin (define-record-type p (mk a) p? (a p-a)) (mk \"s\"))";
+        assert_eq!(printed(rec), ("#<p a: s>".into(), "#<p a: \"s\">".into()));
+    }
+
+    #[test]
+    fn test_cycles() {
+        assert_eq!(printed("(begin (define v #(1)) (vector-set! v 0 v) v)").1, "#0=#(#0#)");
+        assert_eq!(printed("(begin (define v #(1 2)) (vector-set! v 1 (cons \"a\" (cons v ()))) v)").1, "#0=#(1 (\"a\" #0#))");
+        assert_eq!(printed("(begin (define v #(1)) (vector-set! v 0 v) (cons v (cons v ())))").1, "(#0=#(#0#) #0#)");
+        let rec = "(begin (define-record-type n (mk) n? (next n-next set-n-next!)) (define a (mk)) (set-n-next! a a) a)";
+        assert_eq!(printed(rec).1, "#0=#<n next: #0#>");
+
+        // Shared but not cyclic, so nothing needs a label.
+        assert_eq!(printed("(begin (define x '(1)) (cons x (cons x ())))").1, "((1) (1))");
+    }
+
+    #[test]
+    fn test_fmt() {
+        let v = LispValue::Cons(Rc::new(LispValue::Str("a b".into())), Rc::new(LispValue::Symbol("c".into())));
+        assert_eq!(format!("{}", v), "(a b . c)");
+        assert_eq!(format!("{:#}", v), "(\"a b\" . c)");
+    }
+
+}
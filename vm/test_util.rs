@@ -0,0 +1,30 @@
+//! Helpers shared by the tests, mostly for running code through a whole program.
+
+use std::rc::Rc;
+
+use parser;
+use eval::{EvalError, LispValue};
+use exec::LispProgram;
+
+/// Lexes, parses and runs one expression in `prog`.
+pub fn run(prog: &mut LispProgram, src: &str) -> Result<Rc<LispValue>, EvalError> {
+    let toks = parser::lex(&String::from(src)).unwrap();
+    let sexp = parser::parse(&mut toks.into_iter().peekable()).unwrap();
+    prog.exec(&sexp)
+}
+
+pub fn int(n: i64) -> Rc<LispValue> {
+    Rc::new(LispValue::Integer(n))
+}
+
+pub fn boolean(b: bool) -> Rc<LispValue> {
+    Rc::new(LispValue::Boolean(b))
+}
+
+pub fn string(s: &str) -> Rc<LispValue> {
+    Rc::new(LispValue::Str(s.into()))
+}
+
+pub fn ch(c: char) -> Rc<LispValue> {
+    Rc::new(LispValue::Char(c))
+}